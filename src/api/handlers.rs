@@ -0,0 +1,1141 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use actix_web::{
+    delete, get, http::StatusCode, post, put, web,
+    HttpResponse, Responder,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+use uuid::Uuid;
+
+use crate::{
+    config::ConfigLoader,
+    models::{password::PasswordHash, users::User},
+    proxies::{
+        auth::AuthBackend,
+        proxy_manager::{unsupported, ProxyManager},
+        utils::{
+            bind::BindOptions, client_acl::ClientAcl,
+            conn_limit::ConnectionLimits,
+            connect::ConnectOptions, policy::Inheritance,
+            quota::QuotaLimit, rate_limit::RateLimits,
+            rules::Rule, ssrf::SsrfProtection,
+            timeout::Timeouts,
+        },
+    },
+};
+
+use super::models::{
+    AddProxyRequest, AddProxyResponse, AuthMethodRequest,
+    BandwithRequest, BandwithResponse, BlockAddressRequest,
+    DnsResponse, ErrorResponse, PolicyResponse,
+    RegisterUserRequest, Socks4Toggle, StopProxyQuery,
+    TotalBandwithResponse, UserResponse,
+};
+
+type Manager = web::Data<ProxyManager>;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_proxies)
+        .service(add_proxy)
+        .service(get_proxy)
+        .service(remove_proxy)
+        .service(stop_proxy)
+        .service(start_proxy)
+        .service(restart_proxy)
+        .service(list_sessions)
+        .service(list_auth_methods)
+        .service(add_auth_method)
+        .service(remove_auth_method)
+        .service(list_global_users)
+        .service(register_global_user)
+        .service(remove_global_user)
+        .service(list_proxy_users)
+        .service(register_proxy_user)
+        .service(remove_proxy_user)
+        .service(set_global_user_quota)
+        .service(reset_global_user_quota)
+        .service(set_proxy_user_quota)
+        .service(reset_proxy_user_quota)
+        .service(total_bandwith)
+        .service(get_bandwith)
+        .service(set_bandwith)
+        .service(get_rate_limits)
+        .service(set_rate_limits)
+        .service(get_connect_options)
+        .service(set_connect_options)
+        .service(get_bind_options)
+        .service(set_bind_options)
+        .service(get_client_acl)
+        .service(set_client_acl)
+        .service(get_socks4)
+        .service(set_socks4)
+        .service(get_connection_limits)
+        .service(set_connection_limits)
+        .service(get_timeouts)
+        .service(set_timeouts)
+        .service(list_global_blocked)
+        .service(block_global_address)
+        .service(unblock_global_address)
+        .service(list_blocked)
+        .service(block_address)
+        .service(unblock_address)
+        .service(get_global_rules)
+        .service(set_global_rules)
+        .service(get_rules)
+        .service(set_rules)
+        .service(get_ssrf)
+        .service(set_ssrf)
+        .service(get_policy)
+        .service(set_inheritance)
+        .service(get_auth_backends)
+        .service(set_auth_backends)
+        .service(get_dns)
+        .service(clear_dns_cache)
+        .service(render_metrics)
+        .service(reload_config);
+}
+
+pub(super) fn error(
+    status: StatusCode,
+    msg: impl Into<String>,
+) -> HttpResponse {
+    HttpResponse::build(status)
+        .json(ErrorResponse { error: msg.into() })
+}
+
+fn proxy_not_found(proxy_id: &str) -> HttpResponse {
+    error(
+        StatusCode::NOT_FOUND,
+        format!("Proxy not found: {}", proxy_id),
+    )
+}
+
+fn user_not_found(user_id: &str) -> HttpResponse {
+    error(
+        StatusCode::NOT_FOUND,
+        format!("User not found: {}", user_id),
+    )
+}
+
+fn users_response(
+    users: impl IntoIterator<Item = User>,
+) -> HttpResponse {
+    let mut users: Vec<UserResponse> = users
+        .into_iter()
+        .map(|u| UserResponse::from(&u))
+        .collect();
+    users.sort_by(|a, b| a.user_name.cmp(&b.user_name));
+
+    HttpResponse::Ok().json(users)
+}
+
+/// Builds a user from a register request, the response is
+/// the error to send back. Hashing runs on the blocking pool,
+/// not on the worker.
+async fn new_user(
+    body: RegisterUserRequest,
+) -> Result<User, HttpResponse> {
+    if let Some(limit) = &body.rate_limit {
+        limit.validate().map_err(|_e| {
+            error(StatusCode::BAD_REQUEST, _e)
+        })?;
+    }
+    validate_quota(&body.quota)?;
+
+    let (password, password_hash) =
+        (body.password, body.password_hash);
+    let password = web::block(move || {
+        match (password, password_hash) {
+            (Some(password), None) => {
+                PasswordHash::hash(&password)
+            }
+            (None, Some(hash)) => {
+                PasswordHash::parse(&hash)
+            }
+            _ => {
+                Err("set either password or password_hash"
+                    .to_string())
+            }
+        }
+    })
+    .await
+    .map_err(|_e| {
+        error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            _e.to_string(),
+        )
+    })?
+    .map_err(|_e| error(StatusCode::BAD_REQUEST, _e))?;
+
+    Ok(User::new(body.user_name, password)
+        .with_rate_limit(body.rate_limit)
+        .with_quota(body.quota))
+}
+
+fn validate_quota(
+    quota: &Option<QuotaLimit>,
+) -> Result<(), HttpResponse> {
+    match quota {
+        Some(quota) => quota.validate().map_err(|_e| {
+            error(StatusCode::BAD_REQUEST, _e)
+        }),
+        None => Ok(()),
+    }
+}
+
+fn parse_user_id(
+    user_id: &str,
+) -> Result<Uuid, HttpResponse> {
+    Uuid::from_str(user_id).map_err(|_| {
+        error(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid user id: {}", user_id),
+        )
+    })
+}
+
+// Proxies
+
+#[get("/proxies")]
+async fn list_proxies(manager: Manager) -> impl Responder {
+    HttpResponse::Ok().json(manager.list_proxies())
+}
+
+#[post("/proxies")]
+async fn add_proxy(
+    manager: Manager,
+    body: web::Json<AddProxyRequest>,
+) -> impl Responder {
+    let addrs = SocketAddr::new(body.host, body.port);
+
+    let id = match manager
+        .add_proxy(body.proxy_type, addrs)
+        .await
+    {
+        Ok(id) => id,
+        Err(_e) => return error(StatusCode::CONFLICT, _e),
+    };
+    if let Some(max) = body.max_bandwith {
+        manager.set_max_bandwith(&id, max).await;
+    }
+
+    HttpResponse::Created().json(AddProxyResponse { id })
+}
+
+#[get("/proxies/{proxy_id}")]
+async fn get_proxy(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.proxy_info(&proxy_id) {
+        Some(info) => HttpResponse::Ok().json(info),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[delete("/proxies/{proxy_id}")]
+async fn remove_proxy(
+    manager: Manager,
+    path: web::Path<String>,
+    query: web::Query<StopProxyQuery>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager
+        .remove_proxy(&proxy_id, query.stop_sessions())
+        .await
+    {
+        Some(()) => HttpResponse::NoContent().finish(),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[post("/proxies/{proxy_id}/stop")]
+async fn stop_proxy(
+    manager: Manager,
+    path: web::Path<String>,
+    query: web::Query<StopProxyQuery>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager
+        .stop_proxy(&proxy_id, query.stop_sessions())
+        .await
+    {
+        Some(()) => HttpResponse::NoContent().finish(),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[post("/proxies/{proxy_id}/start")]
+async fn start_proxy(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.start_proxy(&proxy_id).await {
+        Some(Ok(())) => HttpResponse::NoContent().finish(),
+        Some(Err(_e)) => error(StatusCode::CONFLICT, _e),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[post("/proxies/{proxy_id}/restart")]
+async fn restart_proxy(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.restart_proxy(&proxy_id).await {
+        Some(Ok(())) => HttpResponse::NoContent().finish(),
+        Some(Err(_e)) => error(StatusCode::CONFLICT, _e),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[get("/proxies/{proxy_id}/sessions")]
+async fn list_sessions(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.list_sessions(&proxy_id) {
+        Some(sessions) => HttpResponse::Ok().json(sessions),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// Auth methods
+
+#[get("/proxies/{proxy_id}/auth-methods")]
+async fn list_auth_methods(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.list_auth_methods(&proxy_id).await {
+        Some(methods) => {
+            let mut methods: Vec<u8> =
+                methods.into_iter().collect();
+            methods.sort();
+            HttpResponse::Ok().json(methods)
+        }
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[post("/proxies/{proxy_id}/auth-methods")]
+async fn add_auth_method(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<AuthMethodRequest>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager
+        .set_auth_method(&proxy_id, body.method)
+        .await
+    {
+        Some(()) => HttpResponse::NoContent().finish(),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[delete("/proxies/{proxy_id}/auth-methods/{method}")]
+async fn remove_auth_method(
+    manager: Manager,
+    path: web::Path<(String, u8)>,
+) -> impl Responder {
+    let (proxy_id, method) = path.into_inner();
+
+    match manager
+        .remove_auth_method(&proxy_id, method)
+        .await
+    {
+        Some(()) => HttpResponse::NoContent().finish(),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// Users
+
+#[get("/users")]
+async fn list_global_users(
+    manager: Manager,
+) -> impl Responder {
+    users_response(
+        manager.list_users(None).await.unwrap_or_default(),
+    )
+}
+
+#[post("/users")]
+async fn register_global_user(
+    manager: Manager,
+    body: web::Json<RegisterUserRequest>,
+) -> impl Responder {
+    let user = match new_user(body.into_inner()).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let resp = UserResponse::from(&user);
+
+    match manager.register_user(None, user).await {
+        Some(Err(_e)) => error(StatusCode::CONFLICT, _e),
+        _ => HttpResponse::Created().json(resp),
+    }
+}
+
+#[delete("/users/{user_id}")]
+async fn remove_global_user(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    let id = match parse_user_id(&user_id) {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+
+    match manager.remove_user(None, &id).await {
+        Some(true) => HttpResponse::NoContent().finish(),
+        _ => user_not_found(&user_id),
+    }
+}
+
+#[get("/proxies/{proxy_id}/users")]
+async fn list_proxy_users(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.list_users(Some(&proxy_id)).await {
+        Some(users) => users_response(users),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[post("/proxies/{proxy_id}/users")]
+async fn register_proxy_user(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<RegisterUserRequest>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+    let user = match new_user(body.into_inner()).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let resp = UserResponse::from(&user);
+
+    match manager.register_user(Some(&proxy_id), user).await
+    {
+        Some(Ok(())) => HttpResponse::Created().json(resp),
+        Some(Err(_e)) => error(StatusCode::CONFLICT, _e),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[delete("/proxies/{proxy_id}/users/{user_id}")]
+async fn remove_proxy_user(
+    manager: Manager,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (proxy_id, user_id) = path.into_inner();
+    let id = match parse_user_id(&user_id) {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+
+    match manager.remove_user(Some(&proxy_id), &id).await {
+        Some(true) => HttpResponse::NoContent().finish(),
+        Some(false) => user_not_found(&user_id),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// Quotas
+
+#[put("/users/{user_id}/quota")]
+async fn set_global_user_quota(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<Option<QuotaLimit>>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    let id = match parse_user_id(&user_id) {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+    if let Err(resp) = validate_quota(&body) {
+        return resp;
+    }
+
+    match manager
+        .set_user_quota(None, &id, body.into_inner())
+        .await
+    {
+        Some(true) => HttpResponse::NoContent().finish(),
+        _ => user_not_found(&user_id),
+    }
+}
+
+#[post("/users/{user_id}/quota/reset")]
+async fn reset_global_user_quota(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    let id = match parse_user_id(&user_id) {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+
+    match manager.reset_user_quota(None, &id).await {
+        Some(true) => HttpResponse::NoContent().finish(),
+        _ => user_not_found(&user_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/users/{user_id}/quota")]
+async fn set_proxy_user_quota(
+    manager: Manager,
+    path: web::Path<(String, String)>,
+    body: web::Json<Option<QuotaLimit>>,
+) -> impl Responder {
+    let (proxy_id, user_id) = path.into_inner();
+    let id = match parse_user_id(&user_id) {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+    if let Err(resp) = validate_quota(&body) {
+        return resp;
+    }
+
+    match manager
+        .set_user_quota(
+            Some(&proxy_id),
+            &id,
+            body.into_inner(),
+        )
+        .await
+    {
+        Some(true) => HttpResponse::NoContent().finish(),
+        Some(false) => user_not_found(&user_id),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[post("/proxies/{proxy_id}/users/{user_id}/quota/reset")]
+async fn reset_proxy_user_quota(
+    manager: Manager,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (proxy_id, user_id) = path.into_inner();
+    let id = match parse_user_id(&user_id) {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+
+    match manager
+        .reset_user_quota(Some(&proxy_id), &id)
+        .await
+    {
+        Some(true) => HttpResponse::NoContent().finish(),
+        Some(false) => user_not_found(&user_id),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// Bandwith
+
+#[get("/bandwith")]
+async fn total_bandwith(
+    manager: Manager,
+) -> impl Responder {
+    HttpResponse::Ok().json(TotalBandwithResponse {
+        used: manager.total_bandwith(),
+    })
+}
+
+#[get("/proxies/{proxy_id}/bandwith")]
+async fn get_bandwith(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.proxy_info(&proxy_id) {
+        Some(info) => {
+            HttpResponse::Ok().json(BandwithResponse {
+                max: info.max_bandwith,
+                used: info.bandwith,
+            })
+        }
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/bandwith")]
+async fn set_bandwith(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<BandwithRequest>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager
+        .set_max_bandwith(&proxy_id, body.max)
+        .await
+    {
+        Some(()) => HttpResponse::NoContent().finish(),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// Rate limits
+
+#[get("/proxies/{proxy_id}/rate-limit")]
+async fn get_rate_limits(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.get_rate_limits(&proxy_id) {
+        Some(limits) => HttpResponse::Ok().json(limits),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/rate-limit")]
+async fn set_rate_limits(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<RateLimits>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    if let Err(_e) = body.validate() {
+        return error(StatusCode::BAD_REQUEST, _e);
+    }
+
+    match manager
+        .set_rate_limits(&proxy_id, body.into_inner())
+        .await
+    {
+        Some(()) => HttpResponse::NoContent().finish(),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// Outbound connections
+
+#[get("/proxies/{proxy_id}/connect")]
+async fn get_connect_options(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.get_connect_options(&proxy_id) {
+        Some(options) => HttpResponse::Ok().json(options),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/connect")]
+async fn set_connect_options(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<ConnectOptions>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    if let Err(_e) = body.validate() {
+        return error(StatusCode::BAD_REQUEST, _e);
+    }
+
+    match manager
+        .set_connect_options(&proxy_id, body.into_inner())
+    {
+        Some(()) => HttpResponse::NoContent().finish(),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[get("/proxies/{proxy_id}/bind")]
+async fn get_bind_options(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.get_bind_options(&proxy_id) {
+        Some(Some(options)) => {
+            HttpResponse::Ok().json(options)
+        }
+        Some(None) => error(
+            StatusCode::BAD_REQUEST,
+            unsupported("BIND"),
+        ),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/bind")]
+async fn set_bind_options(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<BindOptions>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    if let Err(_e) = body.validate() {
+        return error(StatusCode::BAD_REQUEST, _e);
+    }
+
+    match manager
+        .set_bind_options(&proxy_id, body.into_inner())
+    {
+        Some(Ok(())) => HttpResponse::NoContent().finish(),
+        Some(Err(_e)) => error(StatusCode::BAD_REQUEST, _e),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[get("/proxies/{proxy_id}/clients")]
+async fn get_client_acl(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.get_client_acl(&proxy_id) {
+        Some(Some(acl)) => HttpResponse::Ok().json(acl),
+        Some(None) => error(
+            StatusCode::BAD_REQUEST,
+            unsupported("client access control"),
+        ),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/clients")]
+async fn set_client_acl(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<ClientAcl>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager
+        .set_client_acl(&proxy_id, body.into_inner())
+    {
+        Some(Ok(())) => HttpResponse::NoContent().finish(),
+        Some(Err(_e)) => error(StatusCode::BAD_REQUEST, _e),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[get("/proxies/{proxy_id}/socks4")]
+async fn get_socks4(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.get_socks4(&proxy_id) {
+        Some(Some(enabled)) => HttpResponse::Ok()
+            .json(Socks4Toggle { enabled }),
+        Some(None) => error(
+            StatusCode::BAD_REQUEST,
+            unsupported("SOCKS4"),
+        ),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/socks4")]
+async fn set_socks4(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<Socks4Toggle>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.set_socks4(&proxy_id, body.enabled) {
+        Some(Ok(())) => HttpResponse::NoContent().finish(),
+        Some(Err(_e)) => error(StatusCode::BAD_REQUEST, _e),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[get("/proxies/{proxy_id}/connection-limits")]
+async fn get_connection_limits(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.get_connection_limits(&proxy_id) {
+        Some(Some(limits)) => {
+            HttpResponse::Ok().json(limits)
+        }
+        Some(None) => error(
+            StatusCode::BAD_REQUEST,
+            unsupported("connection limits"),
+        ),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/connection-limits")]
+async fn set_connection_limits(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<ConnectionLimits>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    if let Err(_e) = body.validate() {
+        return error(StatusCode::BAD_REQUEST, _e);
+    }
+
+    match manager
+        .set_connection_limits(&proxy_id, body.into_inner())
+    {
+        Some(Ok(())) => HttpResponse::NoContent().finish(),
+        Some(Err(_e)) => error(StatusCode::BAD_REQUEST, _e),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[get("/proxies/{proxy_id}/timeouts")]
+async fn get_timeouts(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.get_timeouts(&proxy_id) {
+        Some(Some(timeouts)) => {
+            HttpResponse::Ok().json(timeouts)
+        }
+        Some(None) => error(
+            StatusCode::BAD_REQUEST,
+            unsupported("timeouts"),
+        ),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/timeouts")]
+async fn set_timeouts(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<Timeouts>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    if let Err(_e) = body.validate() {
+        return error(StatusCode::BAD_REQUEST, _e);
+    }
+
+    match manager.set_timeouts(&proxy_id, body.into_inner())
+    {
+        Some(Ok(())) => HttpResponse::NoContent().finish(),
+        Some(Err(_e)) => error(StatusCode::BAD_REQUEST, _e),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// Blocked addresses
+
+#[get("/blocked")]
+async fn list_global_blocked(
+    manager: Manager,
+) -> impl Responder {
+    let mut addrs: Vec<IpAddr> = manager
+        .get_blocked_address(None)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    addrs.sort();
+
+    HttpResponse::Ok().json(addrs)
+}
+
+#[post("/blocked")]
+async fn block_global_address(
+    manager: Manager,
+    body: web::Json<BlockAddressRequest>,
+) -> impl Responder {
+    manager.block_ip_address(&body.address, None).await;
+
+    HttpResponse::NoContent().finish()
+}
+
+#[delete("/blocked/{address}")]
+async fn unblock_global_address(
+    manager: Manager,
+    path: web::Path<IpAddr>,
+) -> impl Responder {
+    let address = path.into_inner();
+
+    manager.remove_blocked_address(&address, None).await;
+
+    HttpResponse::NoContent().finish()
+}
+
+#[get("/proxies/{proxy_id}/blocked")]
+async fn list_blocked(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.get_blocked_address(Some(&proxy_id)).await
+    {
+        Some(addrs) => {
+            let mut addrs: Vec<IpAddr> =
+                addrs.into_iter().collect();
+            addrs.sort();
+            HttpResponse::Ok().json(addrs)
+        }
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[post("/proxies/{proxy_id}/blocked")]
+async fn block_address(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<BlockAddressRequest>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager
+        .block_ip_address(&body.address, Some(&proxy_id))
+        .await
+    {
+        Some(()) => HttpResponse::NoContent().finish(),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[delete("/proxies/{proxy_id}/blocked/{address}")]
+async fn unblock_address(
+    manager: Manager,
+    path: web::Path<(String, IpAddr)>,
+) -> impl Responder {
+    let (proxy_id, address) = path.into_inner();
+
+    match manager
+        .remove_blocked_address(&address, Some(&proxy_id))
+        .await
+    {
+        Some(()) => HttpResponse::NoContent().finish(),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// Destination rules
+
+#[get("/rules")]
+async fn get_global_rules(
+    manager: Manager,
+) -> impl Responder {
+    let rules = manager.get_rules(None).unwrap_or_default();
+
+    HttpResponse::Ok().json(rules)
+}
+
+#[put("/rules")]
+async fn set_global_rules(
+    manager: Manager,
+    body: web::Json<Vec<Rule>>,
+) -> impl Responder {
+    match manager.set_rules(body.into_inner(), None) {
+        Some(Err(_e)) => error(StatusCode::BAD_REQUEST, _e),
+        _ => HttpResponse::NoContent().finish(),
+    }
+}
+
+#[get("/proxies/{proxy_id}/rules")]
+async fn get_rules(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.get_rules(Some(&proxy_id)) {
+        Some(rules) => HttpResponse::Ok().json(rules),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/rules")]
+async fn set_rules(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<Vec<Rule>>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager
+        .set_rules(body.into_inner(), Some(&proxy_id))
+    {
+        Some(Ok(())) => HttpResponse::NoContent().finish(),
+        Some(Err(_e)) => error(StatusCode::BAD_REQUEST, _e),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// SSRF protection
+
+#[get("/proxies/{proxy_id}/ssrf")]
+async fn get_ssrf(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.get_ssrf(&proxy_id) {
+        Some(ssrf) => HttpResponse::Ok().json(ssrf),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/ssrf")]
+async fn set_ssrf(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<SsrfProtection>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.set_ssrf(&proxy_id, body.into_inner()) {
+        Some(Ok(())) => HttpResponse::NoContent().finish(),
+        Some(Err(_e)) => error(StatusCode::BAD_REQUEST, _e),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// Policy
+
+#[get("/proxies/{proxy_id}/policy")]
+async fn get_policy(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.effective_policy(&proxy_id).await {
+        Some(policy) => HttpResponse::Ok()
+            .json(PolicyResponse::from(&policy)),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/policy/inherit")]
+async fn set_inheritance(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<Inheritance>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager
+        .set_inheritance(&proxy_id, body.into_inner())
+    {
+        Some(()) => HttpResponse::NoContent().finish(),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// Auth backends
+
+#[get("/proxies/{proxy_id}/auth")]
+async fn get_auth_backends(
+    manager: Manager,
+    path: web::Path<String>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager.get_auth_backends(&proxy_id) {
+        Some(backends) => HttpResponse::Ok().json(backends),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+#[put("/proxies/{proxy_id}/auth")]
+async fn set_auth_backends(
+    manager: Manager,
+    path: web::Path<String>,
+    body: web::Json<Vec<AuthBackend>>,
+) -> impl Responder {
+    let proxy_id = path.into_inner();
+
+    match manager
+        .set_auth_backends(&proxy_id, body.into_inner())
+    {
+        Some(Ok(())) => HttpResponse::NoContent().finish(),
+        Some(Err(_e)) => error(StatusCode::BAD_REQUEST, _e),
+        None => proxy_not_found(&proxy_id),
+    }
+}
+
+// DNS
+
+#[get("/dns")]
+async fn get_dns(manager: Manager) -> impl Responder {
+    HttpResponse::Ok().json(DnsResponse {
+        config: manager.dns_config(),
+        stats: manager.dns_stats(),
+    })
+}
+
+#[delete("/dns/cache")]
+async fn clear_dns_cache(
+    manager: Manager,
+) -> impl Responder {
+    manager.clear_dns_cache();
+
+    HttpResponse::NoContent().finish()
+}
+
+// Metrics
+
+#[get("/metrics")]
+async fn render_metrics(
+    metrics: web::Data<PrometheusHandle>,
+) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+// Config
+
+#[post("/config/reload")]
+async fn reload_config(
+    loader: web::Data<ConfigLoader>,
+) -> impl Responder {
+    match loader.reload().await {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(_e) => {
+            error(StatusCode::BAD_REQUEST, _e.to_string())
+        }
+    }
+}
@@ -0,0 +1,152 @@
+mod handlers;
+mod models;
+
+use std::{io, sync::Arc};
+
+use actix_cors::Cors;
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Server, ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::{header, StatusCode},
+    middleware::{from_fn, Next},
+    web, App, Error, HttpServer,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+use tracing::info;
+
+use crate::{
+    config::{AdminConfig, ConfigLoader},
+    models::password::constant_time_eq,
+    proxies::proxy_manager::ProxyManager,
+};
+
+/// Binds the management api, the returned server runs until it is awaited
+/// to completion or stopped through its handle. Process signals are left
+/// to the caller.
+///
+/// Browser access is limited to `admin.allowed_origins`, none are allowed
+/// by default. Requests need `Authorization: Bearer <admin.token>` when a
+/// token is set. `metrics` is rendered on `GET /metrics`.
+pub fn serve(
+    manager: Arc<ProxyManager>,
+    loader: Arc<ConfigLoader>,
+    metrics: PrometheusHandle,
+    admin: &AdminConfig,
+) -> io::Result<Server> {
+    let allowed_origins = admin.allowed_origins.clone();
+    let addrs = admin.address;
+    let token =
+        web::Data::new(AdminToken(admin.token.clone()));
+
+    manager.protect_admin(addrs);
+    let manager = web::Data::from(manager);
+    let loader = web::Data::from(loader);
+    let metrics = web::Data::new(metrics);
+
+    info!("Starting admin api on : {}", addrs);
+
+    HttpServer::new(move || {
+        let cors = allowed_origins.iter().fold(
+            Cors::default()
+                .allow_any_method()
+                .allow_any_header(),
+            |cors, origin| cors.allowed_origin(origin),
+        );
+
+        // Outermost last, preflight requests carry no token
+        App::new()
+            .wrap(from_fn(authorize))
+            .wrap(cors)
+            .app_data(token.clone())
+            .app_data(manager.clone())
+            .app_data(loader.clone())
+            .app_data(metrics.clone())
+            .app_data(
+                web::JsonConfig::default().error_handler(
+                    |err, _req| {
+                        let resp = handlers::error(
+                            StatusCode::BAD_REQUEST,
+                            err.to_string(),
+                        );
+                        InternalError::from_response(
+                            err, resp,
+                        )
+                        .into()
+                    },
+                ),
+            )
+            .app_data(
+                web::QueryConfig::default().error_handler(
+                    |err, _req| {
+                        let resp = handlers::error(
+                            StatusCode::BAD_REQUEST,
+                            err.to_string(),
+                        );
+                        InternalError::from_response(
+                            err, resp,
+                        )
+                        .into()
+                    },
+                ),
+            )
+            .app_data(
+                web::PathConfig::default().error_handler(
+                    |err, _req| {
+                        let resp = handlers::error(
+                            StatusCode::BAD_REQUEST,
+                            err.to_string(),
+                        );
+                        InternalError::from_response(
+                            err, resp,
+                        )
+                        .into()
+                    },
+                ),
+            )
+            .configure(handlers::configure)
+    })
+    .disable_signals()
+    .bind(addrs)
+    .map(|server| server.run())
+}
+
+/// Token admin requests must carry, `None` lets every
+/// request through
+struct AdminToken(Option<String>);
+
+async fn authorize(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<
+    ServiceResponse<EitherBody<impl MessageBody>>,
+    Error,
+> {
+    let expected = req
+        .app_data::<web::Data<AdminToken>>()
+        .and_then(|token| token.0.clone());
+
+    if let Some(expected) = expected {
+        let given = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        if !constant_time_eq(
+            given.as_bytes(),
+            expected.as_bytes(),
+        ) {
+            let resp = handlers::error(
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid bearer token",
+            );
+            return Ok(req
+                .into_response(resp)
+                .map_into_right_body());
+        }
+    }
+
+    next.call(req).await.map(|res| res.map_into_left_body())
+}
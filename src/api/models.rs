@@ -0,0 +1,218 @@
+use std::{net::IpAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    models::users::User,
+    proxies::{
+        dns::{DnsConfig, DnsStats},
+        proxy_manager::ProxyType,
+        utils::{
+            policy::{
+                EffectivePolicy, Inheritance, PolicyScope,
+            },
+            quota::{QuotaLimit, QuotaUsage},
+            rate_limit::RateLimit,
+            rules::Rule,
+            sessions::StopSessions,
+        },
+    },
+};
+
+#[derive(Debug, Deserialize)]
+pub struct AddProxyRequest {
+    pub proxy_type: ProxyType,
+    #[serde(default = "default_host")]
+    pub host: IpAddr,
+    pub port: u16,
+    // Total bytes the proxy may serve, unlimited when not set
+    #[serde(default)]
+    pub max_bandwith: Option<u64>,
+}
+
+fn default_host() -> IpAddr {
+    IpAddr::from([0, 0, 0, 0])
+}
+
+#[derive(Debug, Serialize)]
+pub struct AddProxyResponse {
+    pub id: String,
+}
+
+/// Query of proxy stop and delete, e.g.
+/// `?sessions=drain&drain_timeout=10`
+#[derive(Debug, Deserialize)]
+pub struct StopProxyQuery {
+    #[serde(default)]
+    pub sessions: SessionsMode,
+    // Seconds to wait for sessions when draining
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionsMode {
+    #[default]
+    Keep,
+    Drain,
+    Abort,
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+impl StopProxyQuery {
+    pub fn stop_sessions(&self) -> StopSessions {
+        match self.sessions {
+            SessionsMode::Keep => StopSessions::Keep,
+            SessionsMode::Drain => StopSessions::Drain(
+                Duration::from_secs(self.drain_timeout),
+            ),
+            SessionsMode::Abort => StopSessions::Abort,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthMethodRequest {
+    pub method: u8,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterUserRequest {
+    pub user_name: String,
+    // Either a plaintext password or an argon2/bcrypt hash
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub quota: Option<QuotaLimit>,
+}
+
+/// User as exposed by the api, never includes the password
+#[derive(Debug, Serialize)]
+pub struct UserResponse {
+    pub user_id: String,
+    pub user_name: String,
+    pub rate_limit: Option<RateLimit>,
+    pub quota: QuotaUsage,
+}
+
+impl From<&User> for UserResponse {
+    fn from(user: &User) -> Self {
+        Self {
+            user_id: user.user_id.to_string(),
+            user_name: user.user_name.clone(),
+            rate_limit: user.rate_limiter.limit(),
+            quota: user.quota.usage(),
+        }
+    }
+}
+
+/// Effective policy of a proxy, entries are tagged with the
+/// layer they come from
+#[derive(Debug, Serialize)]
+pub struct PolicyResponse {
+    pub inherit: Inheritance,
+    pub users: Vec<PolicyUserResponse>,
+    pub blocked: Vec<PolicyBlockResponse>,
+    pub rules: Vec<PolicyRuleResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PolicyUserResponse {
+    pub scope: PolicyScope,
+    #[serde(flatten)]
+    pub user: UserResponse,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PolicyBlockResponse {
+    pub scope: PolicyScope,
+    pub address: IpAddr,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PolicyRuleResponse {
+    pub scope: PolicyScope,
+    #[serde(flatten)]
+    pub rule: Rule,
+}
+
+impl From<&EffectivePolicy> for PolicyResponse {
+    fn from(policy: &EffectivePolicy) -> Self {
+        Self {
+            inherit: policy.inherit,
+            users: policy
+                .users
+                .iter()
+                .map(|(scope, user)| PolicyUserResponse {
+                    scope: *scope,
+                    user: UserResponse::from(user),
+                })
+                .collect(),
+            blocked: policy
+                .blocked
+                .iter()
+                .map(|(scope, address)| {
+                    PolicyBlockResponse {
+                        scope: *scope,
+                        address: *address,
+                    }
+                })
+                .collect(),
+            rules: policy
+                .rules
+                .iter()
+                .map(|(scope, rule)| PolicyRuleResponse {
+                    scope: *scope,
+                    rule: rule.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BandwithRequest {
+    pub max: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BandwithResponse {
+    pub max: u64,
+    pub used: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotalBandwithResponse {
+    pub used: u64,
+}
+
+/// Whether a socks5 proxy also serves SOCKS4 clients
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Socks4Toggle {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlockAddressRequest {
+    pub address: IpAddr,
+}
+
+/// Resolver settings and counters
+#[derive(Debug, Serialize)]
+pub struct DnsResponse {
+    pub config: DnsConfig,
+    pub stats: DnsStats,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
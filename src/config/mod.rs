@@ -0,0 +1,532 @@
+mod models;
+mod reload;
+
+pub use models::{AdminConfig, Config};
+pub use reload::ConfigLoader;
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use toml::{Table, Value};
+
+use models::UserConfig;
+
+use tracing::warn;
+
+use crate::{
+    models::password::PasswordHash,
+    proxies::{
+        proxy_manager::ProxyType,
+        utils::{
+            client_acl::ClientAclSet,
+            rate_limit::RateLimit, rules::Rule,
+        },
+    },
+};
+
+// Prefix of env variables overriding config keys, path
+// segments are separated with `__`, for example
+// `PROXIER__PROXIES__MAIN__PORT=1081`
+pub const ENV_PREFIX: &str = "PROXIER__";
+
+// RFC 1929 limits both fields to 255 bytes
+const MAX_CREDENTIAL_LEN: usize = 255;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(PathBuf, io::Error),
+    ParseError(String),
+    // Offending key and the reason
+    InvalidKey(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            ConfigError::IoError(path, err) => {
+                write!(
+                    f,
+                    "Can not read config {}: {}",
+                    path.display(),
+                    err
+                )
+            }
+            ConfigError::ParseError(msg) => {
+                write!(f, "Config Parse Error: {}", msg)
+            }
+            ConfigError::InvalidKey(key, msg) => {
+                write!(
+                    f,
+                    "Invalid config `{}`: {}",
+                    key, msg
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid(
+    key: impl Into<String>,
+    msg: impl Into<String>,
+) -> ConfigError {
+    ConfigError::InvalidKey(key.into(), msg.into())
+}
+
+impl Config {
+    /// Reads a config file and applies env overrides on top
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let raw =
+            fs::read_to_string(path).map_err(|e| {
+                ConfigError::IoError(path.to_path_buf(), e)
+            })?;
+
+        Self::parse(&raw, std::env::vars())
+    }
+
+    pub fn parse(
+        raw: &str,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut value: Value = toml::from_str(raw)
+            .map_err(|e| {
+                ConfigError::ParseError(e.to_string())
+            })?;
+
+        apply_env_overrides(&mut value, vars)?;
+
+        let config: Config =
+            serde_path_to_error::deserialize(value)
+                .map_err(|e| {
+                    invalid(
+                        e.path().to_string(),
+                        e.inner().to_string(),
+                    )
+                })?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.dns
+            .validate()
+            .map_err(|_e| invalid("dns", _e))?;
+
+        match &self.admin.token {
+            Some(token) if token.is_empty() => {
+                return Err(invalid(
+                    "admin.token",
+                    "token must not be empty",
+                ));
+            }
+            None if !self
+                .admin
+                .address
+                .ip()
+                .is_loopback() =>
+            {
+                return Err(invalid(
+                    "admin.token",
+                    "a token is required when the admin api \
+                     listens beyond loopback",
+                ));
+            }
+            _ => {}
+        }
+
+        validate_users("users", &self.users)?;
+        validate_rules("rules", &self.rules)?;
+
+        let mut addresses = HashMap::new();
+        let mut names = HashMap::new();
+
+        for (idx, proxy) in self.proxies.iter().enumerate()
+        {
+            let key = format!("proxies[{}]", idx);
+
+            if proxy.port == 0 {
+                return Err(invalid(
+                    format!("{}.port", key),
+                    "port 0 is not allowed",
+                ));
+            }
+
+            if proxy.addrs() == self.admin.address {
+                return Err(invalid(
+                    format!("{}.port", key),
+                    "address is used by the admin api",
+                ));
+            }
+
+            if let Some(other) =
+                addresses.insert(proxy.addrs(), idx)
+            {
+                return Err(invalid(
+                    format!("{}.port", key),
+                    format!(
+                        "address {} is also used by proxies[{}]",
+                        proxy.addrs(),
+                        other
+                    ),
+                ));
+            }
+
+            if let Some(name) = &proxy.name {
+                if name.is_empty() || name.contains("__") {
+                    return Err(invalid(
+                        format!("{}.name", key),
+                        "must be non empty and must not contain `__`",
+                    ));
+                }
+
+                if let Some(other) =
+                    names.insert(name.to_lowercase(), idx)
+                {
+                    return Err(invalid(
+                        format!("{}.name", key),
+                        format!(
+                            "name `{}` is also used by proxies[{}]",
+                            name, other
+                        ),
+                    ));
+                }
+            }
+
+            if proxy.auth_methods.is_empty() {
+                return Err(invalid(
+                    format!("{}.auth_methods", key),
+                    "at least one auth method is required",
+                ));
+            }
+
+            validate_rate_limit(
+                &format!("{}.rate_limit", key),
+                &proxy.rate_limit,
+            )?;
+            validate_rate_limit(
+                &format!("{}.connection_rate_limit", key),
+                &proxy.connection_rate_limit,
+            )?;
+
+            proxy.connect.validate().map_err(|_e| {
+                invalid(format!("{}.connect", key), _e)
+            })?;
+
+            if proxy.socks4
+                && proxy.proxy_type != ProxyType::Socks5
+            {
+                return Err(invalid(
+                    format!("{}.socks4", key),
+                    "only socks5 proxies serve SOCKS4",
+                ));
+            }
+
+            if let Some(clients) = &proxy.clients {
+                let key = format!("{}.clients", key);
+                if proxy.proxy_type != ProxyType::Socks5 {
+                    return Err(invalid(
+                        key,
+                        "only socks5 proxies check clients",
+                    ));
+                }
+                ClientAclSet::compile(clients.clone())
+                    .map_err(|_e| invalid(key, _e))?;
+            }
+
+            if let Some(bind) = &proxy.bind {
+                let key = format!("{}.bind", key);
+                if proxy.proxy_type != ProxyType::Socks5 {
+                    return Err(invalid(
+                        key,
+                        "only socks5 proxies serve BIND",
+                    ));
+                }
+                bind.validate()
+                    .map_err(|_e| invalid(key, _e))?;
+            }
+
+            if let Some(limits) = &proxy.connection_limits {
+                let key =
+                    format!("{}.connection_limits", key);
+                if proxy.proxy_type != ProxyType::Socks5 {
+                    return Err(invalid(
+                        key,
+                        "only socks5 proxies have connection limits",
+                    ));
+                }
+                limits
+                    .validate()
+                    .map_err(|_e| invalid(key, _e))?;
+            }
+
+            if let Some(timeouts) = &proxy.timeouts {
+                let key = format!("{}.timeouts", key);
+                timeouts
+                    .validate()
+                    .map_err(|_e| invalid(key, _e))?;
+            }
+
+            validate_users(
+                &format!("{}.users", key),
+                &proxy.users,
+            )?;
+            validate_rules(
+                &format!("{}.rules", key),
+                &proxy.rules,
+            )?;
+            proxy.ssrf.validate().map_err(|_e| {
+                invalid(format!("{}.ssrf", key), _e)
+            })?;
+
+            for (i, backend) in
+                proxy.auth.iter().enumerate()
+            {
+                backend.validate().map_err(|_e| {
+                    invalid(
+                        format!("{}.auth[{}]", key, i),
+                        _e,
+                    )
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_rules(
+    key: &str,
+    rules: &[Rule],
+) -> Result<(), ConfigError> {
+    for (idx, rule) in rules.iter().enumerate() {
+        rule.validate().map_err(|_e| {
+            invalid(format!("{}[{}]", key, idx), _e)
+        })?;
+    }
+
+    Ok(())
+}
+
+fn validate_users(
+    key: &str,
+    users: &[UserConfig],
+) -> Result<(), ConfigError> {
+    let mut seen = HashMap::new();
+
+    for (idx, user) in users.iter().enumerate() {
+        let key = format!("{}[{}]", key, idx);
+
+        if user.user_name.is_empty()
+            || user.user_name.len() > MAX_CREDENTIAL_LEN
+        {
+            return Err(invalid(
+                format!("{}.user_name", key),
+                "must be between 1 and 255 bytes",
+            ));
+        }
+
+        match (&user.password, &user.password_hash) {
+            (Some(password), None) => {
+                if password.is_empty()
+                    || password.len() > MAX_CREDENTIAL_LEN
+                {
+                    return Err(invalid(
+                        format!("{}.password", key),
+                        "must be between 1 and 255 bytes",
+                    ));
+                }
+                warn!(
+                    "`{}.password` is plaintext, replace it with a `password_hash` from `proxier hash-password`",
+                    key
+                );
+            }
+            (None, Some(hash)) => {
+                PasswordHash::parse(hash).map_err(
+                    |_e| {
+                        invalid(
+                            format!(
+                                "{}.password_hash",
+                                key
+                            ),
+                            _e,
+                        )
+                    },
+                )?;
+            }
+            _ => {
+                return Err(invalid(
+                    format!("{}.password", key),
+                    "set either password or password_hash",
+                ));
+            }
+        }
+
+        validate_rate_limit(
+            &format!("{}.rate_limit", key),
+            &user.rate_limit,
+        )?;
+
+        if let Some(quota) = &user.quota {
+            quota.validate().map_err(|_e| {
+                invalid(format!("{}.quota", key), _e)
+            })?;
+        }
+
+        if let Some(other) =
+            seen.insert(user.user_name.as_str(), idx)
+        {
+            return Err(invalid(
+                format!("{}.user_name", key),
+                format!(
+                    "user `{}` is already defined at index {}",
+                    user.user_name, other
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_rate_limit(
+    key: &str,
+    limit: &Option<RateLimit>,
+) -> Result<(), ConfigError> {
+    match limit {
+        Some(limit) => {
+            limit.validate().map_err(|_e| invalid(key, _e))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Sets config keys from `PROXIER__` env variables.
+///
+/// Tables are walked by key, arrays such as `proxies` by the element `name`
+/// or by index. Values are read as TOML when possible so numbers, booleans
+/// and arrays keep their type, anything else is taken as a string.
+fn apply_env_overrides(
+    root: &mut Value,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), ConfigError> {
+    let mut overrides: Vec<(String, String)> = vars
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    overrides.sort();
+
+    for (name, raw) in overrides {
+        let path: Vec<String> = name[ENV_PREFIX.len()..]
+            .split("__")
+            .map(|s| s.to_lowercase())
+            .collect();
+
+        if path.iter().any(|s| s.is_empty()) {
+            return Err(invalid(name, "empty key segment"));
+        }
+
+        let (last, parents) = path
+            .split_last()
+            .expect("split yields a segment");
+
+        let mut current = &mut *root;
+        for segment in parents {
+            current = child_mut(current, segment)
+                .ok_or_else(|| {
+                    invalid(
+                        &name,
+                        format!(
+                            "no config entry `{}`",
+                            segment
+                        ),
+                    )
+                })?;
+        }
+
+        let value = parse_override(&raw);
+        match current {
+            Value::Table(table) => {
+                table.insert(last.clone(), value);
+            }
+            Value::Array(items) => {
+                let idx: usize =
+                    last.parse().map_err(|_| {
+                        invalid(
+                            &name,
+                            format!(
+                                "`{}` is not an index",
+                                last
+                            ),
+                        )
+                    })?;
+                let item = items.get_mut(idx).ok_or_else(
+                    || {
+                        invalid(
+                            &name,
+                            format!(
+                                "index {} out of range",
+                                idx
+                            ),
+                        )
+                    },
+                )?;
+                *item = value;
+            }
+            _ => {
+                return Err(invalid(
+                    name,
+                    "parent key is not a table or array",
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Child of a table by key, created when missing, or an
+/// array element by `name` or index
+fn child_mut<'a>(
+    value: &'a mut Value,
+    segment: &str,
+) -> Option<&'a mut Value> {
+    match value {
+        Value::Table(table) => Some(
+            table
+                .entry(segment.to_string())
+                .or_insert_with(|| {
+                    Value::Table(Table::new())
+                }),
+        ),
+        Value::Array(items) => {
+            let by_name = items.iter().position(|item| {
+                item.get("name")
+                    .and_then(Value::as_str)
+                    .is_some_and(|n| {
+                        n.eq_ignore_ascii_case(segment)
+                    })
+            });
+
+            let idx =
+                by_name.or_else(|| segment.parse().ok())?;
+            items.get_mut(idx)
+        }
+        _ => None,
+    }
+}
+
+fn parse_override(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
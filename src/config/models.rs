@@ -0,0 +1,244 @@
+use std::net::{IpAddr, SocketAddr};
+
+use serde::Deserialize;
+
+use crate::{
+    models::password::PasswordHash,
+    proxies::{
+        auth::{AuthBackend, AuthMethod},
+        dns::DnsConfig,
+        proxy_manager::ProxyType,
+        utils::{
+            bind::BindOptions,
+            client_acl::ClientAcl,
+            conn_limit::ConnectionLimits,
+            connect::ConnectOptions,
+            policy::Inheritance,
+            quota::QuotaLimit,
+            rate_limit::{RateLimit, RateLimits},
+            rules::Rule,
+            ssrf::SsrfProtection,
+            timeout::Timeouts,
+        },
+    },
+};
+
+/// Root of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub admin: AdminConfig,
+
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+
+    #[serde(default)]
+    pub dns: DnsConfig,
+
+    // Manager wide users and blocks, inherited by every
+    // proxy
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+
+    #[serde(default)]
+    pub blocked: Vec<IpAddr>,
+
+    // Destination rules evaluated after the ones of a proxy
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+
+    #[serde(default)]
+    pub proxies: Vec<ProxyConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    #[serde(default = "default_admin_address")]
+    pub address: SocketAddr,
+
+    // Browser origins allowed to call the api
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+
+    // Bearer token every request must carry, required unless
+    // the api only listens on loopback
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            address: default_admin_address(),
+            allowed_origins: Vec::new(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShutdownConfig {
+    // Seconds established sessions get to finish on
+    // SIGINT/SIGTERM before they are closed
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout: default_drain_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub user_name: String,
+
+    // Plaintext, only hashed once loaded. Kept for existing
+    // configs, `password_hash` should be used instead.
+    #[serde(default)]
+    pub password: Option<String>,
+
+    // Argon2 or bcrypt hash, see `proxier hash-password`
+    #[serde(default)]
+    pub password_hash: Option<String>,
+
+    // Shared by every connection of the user
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+
+    // Bytes per period, usage is counted in both directions
+    #[serde(default)]
+    pub quota: Option<QuotaLimit>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    // Used to address the proxy from env overrides
+    #[serde(default)]
+    pub name: Option<String>,
+
+    pub proxy_type: ProxyType,
+
+    #[serde(default = "default_host")]
+    pub host: IpAddr,
+    pub port: u16,
+
+    pub auth_methods: Vec<AuthMethod>,
+
+    // Total bytes the proxy may serve, unlimited when not
+    // set
+    #[serde(default)]
+    pub max_bandwith: Option<u64>,
+
+    // Shared by every connection of the proxy
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    // Applied to each connection on its own
+    #[serde(default)]
+    pub connection_rate_limit: Option<RateLimit>,
+
+    // Address families used for targets and the delay
+    // between racing connection attempts
+    #[serde(default)]
+    pub connect: ConnectOptions,
+
+    // Handshake, connect, idle and lifetime limits of
+    // socks5 proxies, defaults when not set
+    #[serde(default)]
+    pub timeouts: Option<Timeouts>,
+
+    // Concurrent connections of socks5 proxies, unlimited
+    // when not set
+    #[serde(default)]
+    pub connection_limits: Option<ConnectionLimits>,
+
+    // Where BIND requests of socks5 proxies listen
+    #[serde(default)]
+    pub bind: Option<BindOptions>,
+
+    // Serve SOCKS4 and SOCKS4a clients on a socks5 proxy
+    #[serde(default)]
+    pub socks4: bool,
+
+    // Client addresses a socks5 proxy serves and the auth
+    // methods they may use, every client when not set
+    #[serde(default)]
+    pub clients: Option<ClientAcl>,
+
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+
+    #[serde(default)]
+    pub blocked: Vec<IpAddr>,
+
+    // Destination rules, the first matching one decides
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+
+    // Loopback, private, link-local, multicast and
+    // unspecified destinations are refused unless disabled
+    // or listed as exceptions
+    #[serde(default)]
+    pub ssrf: SsrfProtection,
+
+    // Global users and blocks the proxy applies, its own
+    // users shadow global ones with the same name
+    #[serde(default)]
+    pub inherit: Inheritance,
+
+    // Backends asked in order for username/password
+    // credentials
+    #[serde(default = "AuthBackend::defaults")]
+    pub auth: Vec<AuthBackend>,
+}
+
+impl UserConfig {
+    /// Hash of the configured password, hashing a plaintext
+    /// one with a new salt
+    pub fn hashed_password(
+        &self,
+    ) -> Result<PasswordHash, String> {
+        match (&self.password, &self.password_hash) {
+            (_, Some(hash)) => PasswordHash::parse(hash),
+            (Some(password), None) => {
+                PasswordHash::hash(password)
+            }
+            (None, None) => {
+                Err("password is not set".to_string())
+            }
+        }
+    }
+}
+
+impl ProxyConfig {
+    pub fn addrs(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            proxy: self.rate_limit,
+            connection: self.connection_rate_limit,
+        }
+    }
+}
+
+fn default_admin_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 9090))
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+fn default_host() -> IpAddr {
+    IpAddr::from([0, 0, 0, 0])
+}
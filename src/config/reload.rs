@@ -0,0 +1,824 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::{
+    models::{password::PasswordHash, users::User},
+    proxies::{
+        proxy_manager::ProxyManager,
+        utils::{
+            connect::ConnectOptions, policy::Inheritance,
+            sessions::StopSessions, ssrf::SsrfProtection,
+        },
+    },
+};
+
+use super::{
+    invalid,
+    models::{ProxyConfig, UserConfig},
+    AdminConfig, Config, ConfigError,
+};
+
+/// Changes made by a reload, proxies by listening address.
+/// A proxy whose type changed is both removed and added.
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadSummary {
+    pub added: Vec<SocketAddr>,
+    pub removed: Vec<SocketAddr>,
+    pub updated: Vec<SocketAddr>,
+    // Proxies that could not be started, retried on the
+    // next reload
+    pub failed: Vec<String>,
+}
+
+// Config the manager currently runs, passwords are hashed
+struct Applied {
+    config: Config,
+    // Proxies started from the config
+    proxies: HashMap<SocketAddr, String>,
+}
+
+/// Keeps the proxy manager in sync with the config file.
+///
+/// Only what the config declares is touched, proxies, users and blocks added
+/// through the admin api survive a reload.
+pub struct ConfigLoader {
+    path: PathBuf,
+    manager: Arc<ProxyManager>,
+    applied: Mutex<Applied>,
+}
+
+impl ConfigLoader {
+    /// Loads the config and starts every proxy in it
+    pub async fn start(
+        path: PathBuf,
+        manager: Arc<ProxyManager>,
+    ) -> Result<Self, ConfigError> {
+        let mut config = hash_passwords(
+            &Config::default(),
+            Config::load(&path)?,
+        )
+        .await?;
+        // Errors are logged, what failed is retried on reload
+        let mut failed = Vec::new();
+
+        manager.configure_dns(&config.dns);
+
+        config.users = sync_users(
+            &manager,
+            None,
+            &[],
+            &config.users,
+            &mut failed,
+        )
+        .await
+        .1;
+        sync_blocked(&manager, None, &[], &config.blocked)
+            .await;
+        apply_section(
+            "rules",
+            &Vec::new(),
+            &config.rules.clone(),
+            &mut config.rules,
+            &mut failed,
+            |rules| manager.set_rules(rules, None),
+        );
+
+        let mut proxies = HashMap::new();
+        for (idx, proxy) in
+            config.proxies.iter_mut().enumerate()
+        {
+            let (proxy_id, applied) =
+                add_proxy(&manager, proxy, &mut failed)
+                    .await
+                    .map_err(|_e| {
+                        invalid(
+                            format!("proxies[{}]", idx),
+                            _e,
+                        )
+                    })?;
+            proxies.insert(proxy.addrs(), proxy_id);
+            *proxy = applied;
+        }
+
+        info!("Loaded config from {}", path.display());
+
+        Ok(Self {
+            path,
+            manager,
+            applied: Mutex::new(Applied {
+                config,
+                proxies,
+            }),
+        })
+    }
+
+    pub async fn admin(&self) -> AdminConfig {
+        self.applied.lock().await.config.admin.clone()
+    }
+
+    pub async fn drain_timeout(&self) -> Duration {
+        let applied = self.applied.lock().await;
+
+        Duration::from_secs(
+            applied.config.shutdown.drain_timeout,
+        )
+    }
+
+    /// Re-reads the config file and applies the difference to
+    /// the running proxies.
+    ///
+    /// Removed proxies stop accepting connections, sessions they already
+    /// accepted keep running until they close. When the file is invalid
+    /// nothing is changed.
+    pub async fn reload(
+        &self,
+    ) -> Result<ReloadSummary, ConfigError> {
+        let config = Config::load(&self.path)?;
+
+        let mut applied = self.applied.lock().await;
+        let Applied {
+            config: old_config,
+            proxies,
+        } = &mut *applied;
+        let mut config =
+            hash_passwords(old_config, config).await?;
+        let mut summary = ReloadSummary::default();
+
+        if old_config.admin != config.admin {
+            warn!("Admin api changes need a restart");
+        }
+
+        if old_config.dns != config.dns {
+            self.manager.configure_dns(&config.dns);
+            info!("DNS resolver reconfigured");
+        }
+
+        config.users = sync_users(
+            &self.manager,
+            None,
+            &old_config.users,
+            &config.users,
+            &mut summary.failed,
+        )
+        .await
+        .1;
+        sync_blocked(
+            &self.manager,
+            None,
+            &old_config.blocked,
+            &config.blocked,
+        )
+        .await;
+        apply_section(
+            "rules",
+            &old_config.rules,
+            &config.rules.clone(),
+            &mut config.rules,
+            &mut summary.failed,
+            |rules| self.manager.set_rules(rules, None),
+        );
+
+        let new_proxies: HashMap<SocketAddr, &ProxyConfig> =
+            config
+                .proxies
+                .iter()
+                .map(|p| (p.addrs(), p))
+                .collect();
+
+        // Stop removed proxies first so their ports are free
+        // for new ones
+        for old_proxy in &old_config.proxies {
+            let addrs = old_proxy.addrs();
+            let kept =
+                new_proxies.get(&addrs).is_some_and(|p| {
+                    p.proxy_type == old_proxy.proxy_type
+                });
+            if kept {
+                continue;
+            }
+
+            if let Some(proxy_id) = proxies.remove(&addrs) {
+                self.manager
+                    .remove_proxy(
+                        &proxy_id,
+                        StopSessions::Keep,
+                    )
+                    .await;
+                summary.removed.push(addrs);
+            }
+        }
+
+        let old_proxies: HashMap<SocketAddr, &ProxyConfig> =
+            old_config
+                .proxies
+                .iter()
+                .map(|p| (p.addrs(), p))
+                .collect();
+
+        // What runs, failed sections keep their old value
+        let mut applied_proxies =
+            Vec::with_capacity(config.proxies.len());
+
+        for proxy in &config.proxies {
+            let addrs = proxy.addrs();
+
+            // Proxies removed through the admin api are
+            // started again
+            let running = proxies
+                .get(&addrs)
+                .filter(|id| {
+                    self.manager.get_proxy(id).is_some()
+                })
+                .cloned();
+
+            match (running, old_proxies.get(&addrs)) {
+                (Some(proxy_id), Some(old_proxy)) => {
+                    let (changed, applied) = update_proxy(
+                        &self.manager,
+                        &proxy_id,
+                        old_proxy,
+                        proxy,
+                        &mut summary.failed,
+                    )
+                    .await;
+                    if changed {
+                        summary.updated.push(addrs);
+                    }
+                    applied_proxies.push(applied);
+                }
+                _ => match add_proxy(
+                    &self.manager,
+                    proxy,
+                    &mut summary.failed,
+                )
+                .await
+                {
+                    Ok((proxy_id, applied)) => {
+                        proxies.insert(addrs, proxy_id);
+                        summary.added.push(addrs);
+                        applied_proxies.push(applied);
+                    }
+                    Err(_e) => {
+                        proxies.remove(&addrs);
+                        error!(
+                            "Can not start proxy on {}: {}",
+                            addrs, _e
+                        );
+                        summary.failed.push(format!(
+                            "{}: {}",
+                            addrs, _e
+                        ));
+                        // Started again on the next reload
+                        applied_proxies.push(proxy.clone());
+                    }
+                },
+            }
+        }
+
+        config.proxies = applied_proxies;
+        *old_config = config;
+
+        info!(
+            "Reloaded config from {}: {} added, {} removed, {} updated, {} failed",
+            self.path.display(),
+            summary.added.len(),
+            summary.removed.len(),
+            summary.updated.len(),
+            summary.failed.len()
+        );
+
+        Ok(summary)
+    }
+}
+
+/// Starts a proxy and loads its auth backends, users, auth
+/// methods, limits and block list. Returns the id and the
+/// config that was applied, sections that failed are pushed
+/// to `failed`.
+async fn add_proxy(
+    manager: &ProxyManager,
+    proxy: &ProxyConfig,
+    failed: &mut Vec<String>,
+) -> Result<(String, ProxyConfig), String> {
+    let proxy_id = manager
+        .add_proxy(proxy.proxy_type, proxy.addrs())
+        .await?;
+
+    // A backend that can not be opened fails the proxy
+    if let Some(Err(_e)) = manager
+        .set_auth_backends(&proxy_id, proxy.auth.clone())
+    {
+        manager
+            .remove_proxy(&proxy_id, StopSessions::Abort)
+            .await;
+        return Err(format!("auth: {}", _e));
+    }
+
+    // Everything but the limit is loaded as a diff from an
+    // empty config
+    let empty = ProxyConfig {
+        auth_methods: Vec::new(),
+        rate_limit: None,
+        connection_rate_limit: None,
+        connect: ConnectOptions::default(),
+        timeouts: None,
+        connection_limits: None,
+        bind: None,
+        socks4: false,
+        clients: None,
+        users: Vec::new(),
+        blocked: Vec::new(),
+        rules: Vec::new(),
+        ssrf: SsrfProtection::default(),
+        inherit: Inheritance::default(),
+        ..proxy.clone()
+    };
+    let (_, applied) = update_proxy(
+        manager, &proxy_id, &empty, proxy, failed,
+    )
+    .await;
+
+    if let Some(max) = proxy.max_bandwith {
+        manager.set_max_bandwith(&proxy_id, max).await;
+    }
+
+    info!(
+        "Configured {:?} proxy {} on {}",
+        proxy.proxy_type,
+        proxy.name.as_deref().unwrap_or(&proxy_id),
+        proxy.addrs()
+    );
+
+    Ok((proxy_id, applied))
+}
+
+/// Applies the changes between two versions of a proxy
+/// config in place. Returns whether anything changed and the
+/// config that was applied, sections that failed keep their
+/// old value and are pushed to `failed`.
+async fn update_proxy(
+    manager: &ProxyManager,
+    proxy_id: &String,
+    old: &ProxyConfig,
+    new: &ProxyConfig,
+    failed: &mut Vec<String>,
+) -> (bool, ProxyConfig) {
+    let mut changed = false;
+    let mut applied = new.clone();
+    let mut errors = Vec::new();
+
+    let old_methods: HashSet<u8> = old
+        .auth_methods
+        .iter()
+        .map(|m| m.to_byte())
+        .collect();
+    let new_methods: HashSet<u8> = new
+        .auth_methods
+        .iter()
+        .map(|m| m.to_byte())
+        .collect();
+
+    for method in old_methods.difference(&new_methods) {
+        manager.remove_auth_method(proxy_id, *method).await;
+        changed = true;
+    }
+    for method in new_methods.difference(&old_methods) {
+        manager.set_auth_method(proxy_id, *method).await;
+        changed = true;
+    }
+
+    let (users_changed, users) = sync_users(
+        manager,
+        Some(proxy_id),
+        &old.users,
+        &new.users,
+        &mut errors,
+    )
+    .await;
+    changed |= users_changed;
+    applied.users = users;
+
+    if old.max_bandwith != new.max_bandwith {
+        manager
+            .set_max_bandwith(
+                proxy_id,
+                new.max_bandwith.unwrap_or(u64::MAX),
+            )
+            .await;
+        changed = true;
+    }
+
+    if old.rate_limits() != new.rate_limits() {
+        manager
+            .set_rate_limits(proxy_id, new.rate_limits())
+            .await;
+        changed = true;
+    }
+
+    if old.connect != new.connect {
+        manager.set_connect_options(proxy_id, new.connect);
+        changed = true;
+    }
+
+    changed |= apply_section(
+        "bind",
+        &old.bind,
+        &new.bind,
+        &mut applied.bind,
+        &mut errors,
+        |bind| {
+            manager.set_bind_options(
+                proxy_id,
+                bind.unwrap_or_default(),
+            )
+        },
+    );
+    changed |= apply_section(
+        "clients",
+        &old.clients,
+        &new.clients,
+        &mut applied.clients,
+        &mut errors,
+        |clients| {
+            manager.set_client_acl(
+                proxy_id,
+                clients.unwrap_or_default(),
+            )
+        },
+    );
+    changed |= apply_section(
+        "socks4",
+        &old.socks4,
+        &new.socks4,
+        &mut applied.socks4,
+        &mut errors,
+        |socks4| manager.set_socks4(proxy_id, socks4),
+    );
+    changed |= apply_section(
+        "connection_limits",
+        &old.connection_limits,
+        &new.connection_limits,
+        &mut applied.connection_limits,
+        &mut errors,
+        |limits| {
+            manager.set_connection_limits(
+                proxy_id,
+                limits.unwrap_or_default(),
+            )
+        },
+    );
+    changed |= apply_section(
+        "timeouts",
+        &old.timeouts,
+        &new.timeouts,
+        &mut applied.timeouts,
+        &mut errors,
+        |timeouts| {
+            manager.set_timeouts(
+                proxy_id,
+                timeouts.unwrap_or_default(),
+            )
+        },
+    );
+
+    changed |= sync_blocked(
+        manager,
+        Some(proxy_id),
+        &old.blocked,
+        &new.blocked,
+    )
+    .await;
+    changed |= apply_section(
+        "rules",
+        &old.rules,
+        &new.rules,
+        &mut applied.rules,
+        &mut errors,
+        |rules| manager.set_rules(rules, Some(proxy_id)),
+    );
+    changed |= apply_section(
+        "ssrf",
+        &old.ssrf,
+        &new.ssrf,
+        &mut applied.ssrf,
+        &mut errors,
+        |ssrf| manager.set_ssrf(proxy_id, ssrf),
+    );
+
+    if old.inherit != new.inherit {
+        manager.set_inheritance(proxy_id, new.inherit);
+        changed = true;
+    }
+
+    changed |= apply_section(
+        "auth",
+        &old.auth,
+        &new.auth,
+        &mut applied.auth,
+        &mut errors,
+        |auth| manager.set_auth_backends(proxy_id, auth),
+    );
+
+    failed.extend(
+        errors
+            .into_iter()
+            .map(|_e| format!("{}: {}", new.addrs(), _e)),
+    );
+
+    (changed, applied)
+}
+
+/// Applies a section that changed. When it fails the error
+/// is pushed to `failed` and `applied` keeps the old value,
+/// so the next reload tries again.
+fn apply_section<T: PartialEq + Clone>(
+    section: &str,
+    old: &T,
+    new: &T,
+    applied: &mut T,
+    failed: &mut Vec<String>,
+    set: impl FnOnce(T) -> Option<Result<(), String>>,
+) -> bool {
+    if old == new {
+        return false;
+    }
+
+    match set(new.clone()) {
+        Some(Err(_e)) => {
+            error!("Can not set {}: {}", section, _e);
+            failed.push(format!("{}: {}", section, _e));
+            *applied = old.clone();
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Applies block list changes, either globally or for a
+/// specific proxy
+async fn sync_blocked(
+    manager: &ProxyManager,
+    proxy_id: Option<&String>,
+    old: &[IpAddr],
+    new: &[IpAddr],
+) -> bool {
+    let mut changed = false;
+
+    for addrs in old.iter().filter(|a| !new.contains(a)) {
+        manager
+            .remove_blocked_address(addrs, proxy_id)
+            .await;
+        changed = true;
+    }
+    for addrs in new.iter().filter(|a| !old.contains(a)) {
+        manager.block_ip_address(addrs, proxy_id).await;
+        changed = true;
+    }
+
+    changed
+}
+
+/// Applies user changes, either globally or for a specific
+/// proxy. Users are matched by name and updated in place, a
+/// changed password or limit keeps the user and its usage.
+/// Returns whether anything changed and the users applied,
+/// a user that could not be loaded is retried on reload.
+async fn sync_users(
+    manager: &ProxyManager,
+    proxy_id: Option<&String>,
+    old: &[UserConfig],
+    new: &[UserConfig],
+    failed: &mut Vec<String>,
+) -> (bool, Vec<UserConfig>) {
+    let mut changed = false;
+    let mut applied = new.to_vec();
+    // The old config stays applied while the live user is
+    // untouched, otherwise none is
+    let mut fail = |user: &UserConfig, _e: String, kept| {
+        error!(
+            "Can not load user {}: {}",
+            user.user_name, _e
+        );
+        failed.push(format!(
+            "users.{}: {}",
+            user.user_name, _e
+        ));
+        applied.retain(|a| a.user_name != user.user_name);
+        if kept {
+            applied.extend(
+                old.iter()
+                    .find(|o| o.user_name == user.user_name)
+                    .cloned(),
+            );
+        }
+    };
+    let live = manager
+        .list_users(proxy_id)
+        .await
+        .unwrap_or_default();
+
+    for user in new.iter().filter(|u| !old.contains(u)) {
+        let password = match user.hashed_password() {
+            Ok(password) => password,
+            Err(_e) => {
+                fail(user, _e, true);
+                continue;
+            }
+        };
+
+        let replacement = match live
+            .iter()
+            .find(|l| l.user_name == user.user_name)
+        {
+            Some(live_user) => {
+                live_user
+                    .rate_limiter
+                    .set_limit(user.rate_limit);
+                live_user.quota.set_limit(user.quota);
+
+                if live_user.password.as_ref()
+                    == Some(&password)
+                {
+                    changed = true;
+                    continue;
+                }
+
+                manager
+                    .remove_user(
+                        proxy_id,
+                        &live_user.user_id,
+                    )
+                    .await;
+                User {
+                    password: Some(password),
+                    ..live_user.clone()
+                }
+            }
+            None => User::new(&user.user_name, password)
+                .with_rate_limit(user.rate_limit)
+                .with_quota(user.quota),
+        };
+
+        if let Some(Err(_e)) = manager
+            .register_user(proxy_id, replacement)
+            .await
+        {
+            fail(user, _e, false);
+            continue;
+        }
+        changed = true;
+    }
+
+    for user in old.iter().filter(|o| {
+        !new.iter().any(|n| n.user_name == o.user_name)
+    }) {
+        for live_user in live
+            .iter()
+            .filter(|l| l.user_name == user.user_name)
+        {
+            manager
+                .remove_user(proxy_id, &live_user.user_id)
+                .await;
+        }
+        changed = true;
+    }
+
+    (changed, applied)
+}
+
+/// Replaces plaintext passwords with hashes so none are kept
+/// once loaded. A password the applied hash of the user
+/// still verifies keeps that hash. Argon2 is slow, this runs
+/// on the blocking pool.
+async fn hash_passwords(
+    applied: &Config,
+    config: Config,
+) -> Result<Config, ConfigError> {
+    let applied = applied.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut config = config;
+
+        hash_users(&applied.users, &mut config.users);
+        for proxy in &mut config.proxies {
+            let old = applied
+                .proxies
+                .iter()
+                .find(|p| p.addrs() == proxy.addrs())
+                .map(|p| p.users.as_slice())
+                .unwrap_or_default();
+            hash_users(old, &mut proxy.users);
+        }
+
+        config
+    })
+    .await
+    .map_err(|_e| invalid("users", _e.to_string()))
+}
+
+fn hash_users(
+    applied: &[UserConfig],
+    users: &mut [UserConfig],
+) {
+    for user in users {
+        let Some(password) = user.password.take() else {
+            continue;
+        };
+
+        let kept = applied
+            .iter()
+            .filter(|a| a.user_name == user.user_name)
+            .filter_map(|a| a.password_hash.as_ref())
+            .find(|hash| {
+                PasswordHash::parse(hash).is_ok_and(
+                    |hash| hash.verify(&password),
+                )
+            });
+
+        user.password_hash = match kept {
+            Some(hash) => Some(hash.clone()),
+            None => match PasswordHash::hash(&password) {
+                Ok(hash) => Some(hash.as_str().to_string()),
+                Err(_e) => {
+                    error!(
+                        "Can not hash password of {}: {}",
+                        user.user_name, _e
+                    );
+                    None
+                }
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(
+        password: Option<&str>,
+        password_hash: Option<&str>,
+    ) -> UserConfig {
+        UserConfig {
+            user_name: "samet".to_string(),
+            password: password.map(str::to_string),
+            password_hash: password_hash
+                .map(str::to_string),
+            rate_limit: None,
+            quota: None,
+        }
+    }
+
+    fn verifies(user: &UserConfig, password: &str) -> bool {
+        PasswordHash::parse(
+            user.password_hash.as_ref().unwrap(),
+        )
+        .unwrap()
+        .verify(password)
+    }
+
+    #[test]
+    fn plaintext_is_hashed() {
+        let mut users = vec![user(Some("secret"), None)];
+        hash_users(&[], &mut users);
+
+        assert_eq!(users[0].password, None);
+        assert!(verifies(&users[0], "secret"));
+    }
+
+    #[test]
+    fn applied_hash_is_kept() {
+        let mut applied = vec![user(Some("secret"), None)];
+        hash_users(&[], &mut applied);
+
+        let mut same = vec![user(Some("secret"), None)];
+        hash_users(&applied, &mut same);
+        assert_eq!(same, applied);
+
+        let mut changed = vec![user(Some("other"), None)];
+        hash_users(&applied, &mut changed);
+        assert_ne!(changed, applied);
+        assert!(verifies(&changed[0], "other"));
+
+        // Hashes are left alone
+        let hash = PasswordHash::hash("secret").unwrap();
+        let mut hashed = vec![user(
+            None,
+            Some(hash.as_str()),
+        )];
+        hash_users(&applied, &mut hashed);
+        assert_eq!(
+            hashed[0].password_hash.as_deref(),
+            Some(hash.as_str())
+        );
+    }
+}
@@ -1,19 +1,55 @@
+mod api;
+mod config;
 mod models;
 mod proxies;
 
-use std::time::{self, Duration};
+use std::{
+    env,
+    io::{self, Write},
+    path::PathBuf,
+    process,
+    sync::Arc,
+    time::Duration,
+};
 
+use config::ConfigLoader;
 use dotenv::dotenv;
-use metrics_exporter_prometheus::PrometheusBuilder;
-use models::users::User;
-use proxies::proxy_manager::{ProxyManager, ProxyType};
-use tracing::info;
-use tracing_subscriber;
+use metrics_exporter_prometheus::{
+    PrometheusBuilder, PrometheusHandle,
+};
+use models::password::PasswordHash;
+use proxies::proxy_manager::ProxyManager;
+use tracing::{error, info, warn};
+
+// Used when neither an argument nor `PROXIER_CONFIG` is set
+const DEFAULT_CONFIG_PATH: &str = "proxier.toml";
+
+// Prints the hash of a password read from stdin instead of
+// starting, for `password_hash` config keys
+const HASH_PASSWORD_COMMAND: &str = "hash-password";
+
+// Histograms are drained into their summaries this often
+const METRICS_UPKEEP_INTERVAL: Duration =
+    Duration::from_secs(5);
+
+// Exit codes
+const EXIT_OK: i32 = 0;
+const EXIT_CONFIG: i32 = 1;
+const EXIT_ADMIN_API: i32 = 2;
+// Drain deadline passed or a second signal came before every
+// session ended
+const EXIT_SESSIONS_ABORTED: i32 = 3;
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
+    if env::args().nth(1).as_deref()
+        == Some(HASH_PASSWORD_COMMAND)
+    {
+        process::exit(hash_password());
+    }
+
     tracing_subscriber::fmt()
         .with_line_number(true)
         .with_level(true)
@@ -22,34 +58,192 @@ async fn main() {
 
     info!("Application Starting");
 
-    let mut proxy_manager = ProxyManager::new();
-
-    let proxy_id = proxy_manager
-        .add_proxy(ProxyType::Socks5, 1080)
-        .await
-        .unwrap();
-
-
-    proxy_manager.list_auth_methods(&proxy_id).await;
-
-    // Set access as non passoword
-    proxy_manager.set_auth_method(&proxy_id, 0).await;
-
-    // Also with password
-    proxy_manager.set_auth_method(&proxy_id, 2).await;
-
-    let user = User::new("samet", "password");
-
-    // register to user
-    proxy_manager
-        .register_user(Some(&proxy_id), user)
-        .await;
-
+    let metrics = match install_metrics() {
+        Ok(metrics) => metrics,
+        Err(_e) => {
+            error!("Metrics recorder failed: {}", _e);
+            process::exit(EXIT_ADMIN_API);
+        }
+    };
+
+    let config_path: PathBuf = env::args()
+        .nth(1)
+        .or_else(|| env::var("PROXIER_CONFIG").ok())
+        .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string())
+        .into();
+
+    let proxy_manager = Arc::new(ProxyManager::new());
+
+    let loader = match ConfigLoader::start(
+        config_path,
+        Arc::clone(&proxy_manager),
+    )
+    .await
+    {
+        Ok(loader) => Arc::new(loader),
+        Err(_e) => {
+            error!("{}", _e);
+            process::exit(EXIT_CONFIG);
+        }
+    };
+
+    #[cfg(unix)]
+    reload_on_hangup(Arc::clone(&loader));
+
+    let admin = loader.admin().await;
+
+    let server = match api::serve(
+        Arc::clone(&proxy_manager),
+        Arc::clone(&loader),
+        metrics.clone(),
+        &admin,
+    ) {
+        Ok(server) => server,
+        Err(_e) => {
+            error!("Admin api failed: {}", _e);
+            process::exit(EXIT_ADMIN_API);
+        }
+    };
+    let server_handle = server.handle();
+
+    let mut status = tokio::select! {
+        res = server => {
+            // Only ends on its own when it failed
+            error!("Admin api stopped: {:?}", res);
+            EXIT_ADMIN_API
+        }
+        signal = shutdown_signal() => {
+            info!("Received {}, shutting down", signal);
+            EXIT_OK
+        }
+    };
+
+    server_handle.stop(true).await;
+
+    let drain_timeout = loader.drain_timeout().await;
+
+    tokio::select! {
+        aborted = proxy_manager.shutdown(drain_timeout) => {
+            if aborted > 0 {
+                warn!("Closed {} sessions at the drain deadline", aborted);
+                status = status.max(EXIT_SESSIONS_ABORTED);
+            }
+        }
+        signal = shutdown_signal() => {
+            warn!("Received {} again, closing sessions", signal);
+            status = status.max(EXIT_SESSIONS_ABORTED);
+        }
+    }
+
+    info!(
+        "Stopped, served {} bytes in total",
+        proxy_manager.total_bandwith()
+    );
+    metrics.run_upkeep();
+    info!("Final metrics:\n{}", metrics.render());
+    let _ = io::stdout().flush();
+
+    process::exit(status);
+}
 
+/// Records metrics from here on, they are served by the
+/// admin api
+fn install_metrics() -> Result<PrometheusHandle, String> {
+    let handle = PrometheusBuilder::new()
+        .install_recorder()
+        .map_err(|_e| _e.to_string())?;
+
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(METRICS_UPKEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+
+    Ok(handle)
+}
 
-    let one_mb = 1024*1024;
+/// Hashes the first line of stdin and prints it
+fn hash_password() -> i32 {
+    let mut password = String::new();
+    if let Err(_e) = io::stdin().read_line(&mut password) {
+        eprintln!("Can not read password: {}", _e);
+        return EXIT_CONFIG;
+    }
+
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eprintln!("Password is empty");
+        return EXIT_CONFIG;
+    }
+
+    match PasswordHash::hash(password) {
+        Ok(hash) => {
+            println!("{}", hash.as_str());
+            EXIT_OK
+        }
+        Err(_e) => {
+            eprintln!("{}", _e);
+            EXIT_CONFIG
+        }
+    }
+}
 
-    proxy_manager.set_max_bandwith(&proxy_id, one_mb).await;
+/// Resolves with the name of the first SIGINT or SIGTERM
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate =
+            match signal(SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(_e) => {
+                    error!(
+                        "Can not listen for SIGTERM: {}",
+                        _e
+                    );
+                    let _ = tokio::signal::ctrl_c().await;
+                    return "SIGINT";
+                }
+            };
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
 
-    loop {}
+/// Reloads the config each time the process gets SIGHUP
+#[cfg(unix)]
+fn reload_on_hangup(loader: Arc<ConfigLoader>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(_e) => {
+            error!("Can not listen for SIGHUP: {}", _e);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading config");
+
+            if let Err(_e) = loader.reload().await {
+                error!("Config reload failed: {}", _e);
+            }
+        }
+    });
 }
@@ -0,0 +1,267 @@
+use std::{fmt, sync::OnceLock};
+
+use argon2::{
+    password_hash::{
+        rand_core::OsRng, PasswordHasher, PasswordVerifier,
+        SaltString,
+    },
+    Argon2,
+};
+use blake2::{Blake2b512, Digest};
+
+// Prefixes of bcrypt hashes as written by htpasswd and most
+// libraries
+const BCRYPT_PREFIXES: [&str; 4] =
+    ["$2a$", "$2b$", "$2x$", "$2y$"];
+
+/// Salted password hash, Argon2id for passwords hashed here,
+/// bcrypt hashes can be imported. Plaintext is never kept.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PasswordHash(String);
+
+impl PasswordHash {
+    /// Hashes a plaintext password with Argon2id and a random
+    /// salt
+    pub fn hash(password: &str) -> Result<Self, String> {
+        let salt = SaltString::generate(&mut OsRng);
+
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| Self(hash.to_string()))
+            .map_err(|_e| {
+                format!("can not hash password: {}", _e)
+            })
+    }
+
+    /// Imports an existing Argon2 (PHC string) or bcrypt hash
+    pub fn parse(hash: &str) -> Result<Self, String> {
+        if BCRYPT_PREFIXES
+            .iter()
+            .any(|p| hash.starts_with(p))
+        {
+            hash.parse::<bcrypt::HashParts>().map_err(
+                |_e| format!("invalid bcrypt hash: {}", _e),
+            )?;
+        } else if hash.starts_with("$argon2") {
+            argon2::PasswordHash::new(hash).map_err(
+                |_e| format!("invalid argon2 hash: {}", _e),
+            )?;
+        } else {
+            return Err("must be an argon2 or bcrypt hash"
+                .to_string());
+        }
+
+        Ok(Self(hash.to_string()))
+    }
+
+    /// Constant time check of a plaintext password. Slow on
+    /// purpose, async code should use `User::authenticate`.
+    pub fn verify(&self, password: &str) -> bool {
+        if self.0.starts_with("$argon2") {
+            argon2::PasswordHash::new(&self.0).is_ok_and(
+                |hash| {
+                    Argon2::default()
+                        .verify_password(
+                            password.as_bytes(),
+                            &hash,
+                        )
+                        .is_ok()
+                },
+            )
+        } else {
+            bcrypt::verify(password, &self.0)
+                .unwrap_or(false)
+        }
+    }
+
+    /// `verify` on the blocking pool
+    pub async fn verify_async(
+        &self,
+        password: String,
+    ) -> bool {
+        let hash = self.clone();
+
+        tokio::task::spawn_blocking(move || {
+            hash.verify(&password)
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// Hash checked for unknown users, so a login takes as
+    /// long whether the user exists or not
+    pub fn dummy() -> &'static Self {
+        static DUMMY: OnceLock<PasswordHash> =
+            OnceLock::new();
+
+        DUMMY.get_or_init(|| {
+            Self::hash("proxier-dummy-password").expect(
+                "argon2 hashing with default params",
+            )
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Unsalted digest of a username and password, recognizes
+/// credentials seen before without keeping them
+#[derive(Clone)]
+pub struct CredentialsDigest([u8; 64]);
+
+impl CredentialsDigest {
+    pub fn new(username: &str, password: &str) -> Self {
+        let digest = Blake2b512::new()
+            .chain_update(
+                (username.len() as u64).to_be_bytes(),
+            )
+            .chain_update(username)
+            .chain_update(password)
+            .finalize();
+
+        Self(digest.into())
+    }
+
+    pub fn matches(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for CredentialsDigest {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str("CredentialsDigest(..)")
+    }
+}
+
+/// Compares in constant time for inputs of the same length
+pub fn constant_time_eq(
+    given: &[u8],
+    expected: &[u8],
+) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+impl fmt::Debug for PasswordHash {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str("PasswordHash(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_round_trip() {
+        let hash = PasswordHash::hash("secret").unwrap();
+
+        assert!(hash.as_str().starts_with("$argon2id$"));
+        assert!(hash.verify("secret"));
+        assert!(!hash.verify("wrong"));
+        assert!(!hash.verify(""));
+
+        // Stored hashes are imported as they are
+        let parsed =
+            PasswordHash::parse(hash.as_str()).unwrap();
+        assert_eq!(parsed, hash);
+        assert!(parsed.verify("secret"));
+
+        // Salted, the same password hashes differently
+        assert_ne!(
+            PasswordHash::hash("secret").unwrap(),
+            hash
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_async_matches_verify() {
+        let hash = PasswordHash::hash("secret").unwrap();
+
+        assert!(
+            hash.verify_async("secret".to_string()).await
+        );
+        assert!(
+            !hash.verify_async("wrong".to_string()).await
+        );
+    }
+
+    #[test]
+    fn malformed_hashes_rejected() {
+        for hash in [
+            "",
+            "secret",
+            "$argon2id$",
+            "$argon2id$v=19$m=19456,t=2,p=1$not-base64!",
+            "$2b$",
+            "$2b$04$tooshort",
+            "$1$saltsalt$md5cryptisnotsupported",
+        ] {
+            assert!(
+                PasswordHash::parse(hash).is_err(),
+                "{}",
+                hash
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_bcrypt_verifies() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        // htpasswd writes the `$2y$` variant
+        let htpasswd = hash.replacen("$2b$", "$2y$", 1);
+
+        for hash in [hash, htpasswd] {
+            let parsed =
+                PasswordHash::parse(&hash).unwrap();
+            assert!(parsed.verify("secret"), "{}", hash);
+            assert!(!parsed.verify("wrong"), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn credentials_digest_matches_same_credentials() {
+        let digest =
+            CredentialsDigest::new("user", "secret");
+
+        assert!(digest.matches(&CredentialsDigest::new(
+            "user", "secret"
+        )));
+        for (username, password) in [
+            ("user", "wrong"),
+            ("other", "secret"),
+            // Moving bytes between the fields changes it
+            ("users", "ecret"),
+            ("use", "rsecret"),
+        ] {
+            assert!(
+                !digest.matches(&CredentialsDigest::new(
+                    username, password
+                )),
+                "{}:{}",
+                username,
+                password
+            );
+        }
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"tokens"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -1,78 +1,114 @@
-use std::{
-    collections::HashSet,
-    hash::{Hash, Hasher},
-    sync::Arc,
-};
+use std::{collections::HashMap, sync::Arc};
 
 use uuid::Uuid;
 
+use super::password::PasswordHash;
+use crate::proxies::utils::{
+    quota::{Quota, QuotaLimit},
+    rate_limit::{RateLimit, TokenBucket},
+};
+
 pub type UserId = Uuid;
 
+/// Users keyed by name, names are unique in each scope
+pub type Users = HashMap<String, User>;
+
 #[derive(Clone, Debug)]
 pub struct User {
     pub user_id: UserId,
     pub user_name: String,
-    pub password: String,
+    // None for users checked by an external auth backend
+    pub password: Option<PasswordHash>,
+
+    // Shared by every connection of the user
+    pub rate_limiter: Arc<TokenBucket>,
+    pub quota: Arc<Quota>,
 }
 
-// Store User total used bandwith
-// Limit bandwith
 // User based access controll ( auth methods)
 
 impl User {
     pub fn new(
         user_name: impl Into<String>,
-        password: impl Into<String>,
+        password: PasswordHash,
     ) -> Self {
         Self {
             user_id: Uuid::new_v4(),
             user_name: user_name.into(),
-            password: password.into(),
+            password: Some(password),
+            rate_limiter: TokenBucket::new(None),
+            quota: Arc::new(Quota::new(None)),
+        }
+    }
+
+    /// User whose password is checked by an external auth
+    /// backend, only limits and usage are kept here
+    pub fn external(user_name: impl Into<String>) -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            user_name: user_name.into(),
+            password: None,
+            rate_limiter: TokenBucket::new(None),
+            quota: Arc::new(Quota::new(None)),
         }
     }
 
+    pub fn with_rate_limit(
+        self,
+        limit: Option<RateLimit>,
+    ) -> Self {
+        self.rate_limiter.set_limit(limit);
+        self
+    }
+
+    pub fn with_quota(
+        self,
+        limit: Option<QuotaLimit>,
+    ) -> Self {
+        self.quota.set_limit(limit);
+        self
+    }
+
     pub fn find_user_by_name(
-        users: &HashSet<User>,
+        users: &Users,
         user_name: String,
     ) -> Option<User> {
-        users
-            .iter()
-            .find(|u| u.user_name == user_name)
-            .cloned()
+        users.get(&user_name).cloned()
     }
 
     pub fn check_user_avaliable(
-        users: &HashSet<User>,
+        users: &Users,
         user_name: String,
     ) -> bool {
         Self::find_user_by_name(users, user_name).is_some()
     }
 
-    pub fn check_user_pass(
-        users: &HashSet<User>,
-        user_name: String,
+    /// Checks the password of a user found with
+    /// `find_user_by_name` on the blocking pool. Unknown
+    /// users, and users without a password here, are checked
+    /// against a dummy hash so they take as long as a wrong
+    /// password.
+    pub async fn authenticate(
+        user: Option<User>,
         password: String,
-    ) -> bool {
-        users
-            .iter()
-            .find(|u| {
-                u.user_name == user_name
-                    && u.password == password
-            })
-            .is_some()
-    }
-}
-
-impl PartialEq for User {
-    fn eq(&self, other: &Self) -> bool {
-        self.user_id == other.user_id
-    }
-}
-
-impl Eq for User {}
-
-impl Hash for User {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.user_id.hash(state);
+    ) -> Option<User> {
+        tokio::task::spawn_blocking(move || {
+            match user
+                .as_ref()
+                .and_then(|u| u.password.as_ref())
+            {
+                Some(hash) if hash.verify(&password) => {
+                    user
+                }
+                Some(_) => None,
+                None => {
+                    PasswordHash::dummy().verify(&password);
+                    None
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten()
     }
 }
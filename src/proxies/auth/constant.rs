@@ -0,0 +1,5 @@
+pub const DEFAULT_SQLITE_QUERY: &str =
+    "SELECT password_hash FROM users WHERE user_name = ?1";
+
+// Seconds a webhook may take to answer
+pub const DEFAULT_WEBHOOK_TIMEOUT: u64 = 5;
@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use tokio::fs;
+use tracing::warn;
+
+use crate::{
+    models::password::PasswordHash,
+    proxies::utils::policy::ProxyPolicy,
+};
+
+use super::{AuthOutcome, Authenticator};
+
+/// `user:hash` file, read again whenever it changes on disk.
+/// Lines with hashes other than bcrypt or argon2 (`htpasswd
+/// -m`, `-s`) are skipped.
+#[derive(Debug)]
+pub struct HtpasswdAuthenticator {
+    path: PathBuf,
+    policy: Arc<ProxyPolicy>,
+    // Modification time of the file the entries come from
+    entries: Mutex<
+        Option<(SystemTime, HashMap<String, PasswordHash>)>,
+    >,
+}
+
+impl HtpasswdAuthenticator {
+    pub fn open(
+        path: &Path,
+        policy: Arc<ProxyPolicy>,
+    ) -> Result<Self, String> {
+        if !path.is_file() {
+            return Err(format!(
+                "htpasswd file {} not found",
+                path.display()
+            ));
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            policy,
+            entries: Mutex::new(None),
+        })
+    }
+
+    async fn lookup(
+        &self,
+        user_name: &str,
+    ) -> Result<Option<PasswordHash>, String> {
+        let modified = fs::metadata(&self.path)
+            .await
+            .and_then(|m| m.modified())
+            .map_err(|_e| {
+                format!("{}: {}", self.path.display(), _e)
+            })?;
+
+        let stale = self
+            .entries
+            .lock()
+            .as_ref()
+            .is_none_or(|(at, _)| *at != modified);
+        if stale {
+            let content = fs::read_to_string(&self.path)
+                .await
+                .map_err(|_e| {
+                    format!(
+                        "{}: {}",
+                        self.path.display(),
+                        _e
+                    )
+                })?;
+            *self.entries.lock() =
+                Some((modified, self.parse(&content)));
+        }
+
+        Ok(self.entries.lock().as_ref().and_then(
+            |(_, entries)| entries.get(user_name).cloned(),
+        ))
+    }
+
+    fn parse(
+        &self,
+        content: &str,
+    ) -> HashMap<String, PasswordHash> {
+        let mut entries = HashMap::new();
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((user_name, hash)) =
+                line.split_once(':')
+            else {
+                warn!(
+                    "{}:{} is not `user:hash`, skipped",
+                    self.path.display(),
+                    idx + 1
+                );
+                continue;
+            };
+
+            match PasswordHash::parse(hash) {
+                Ok(hash) => {
+                    entries.insert(
+                        user_name.to_string(),
+                        hash,
+                    );
+                }
+                Err(_e) => warn!(
+                    "{}:{} skipped: {}",
+                    self.path.display(),
+                    idx + 1,
+                    _e
+                ),
+            }
+        }
+
+        entries
+    }
+}
+
+#[async_trait]
+impl Authenticator for HtpasswdAuthenticator {
+    async fn authenticate(
+        &self,
+        user_name: &str,
+        password: &str,
+    ) -> Result<AuthOutcome, String> {
+        let Some(hash) = self.lookup(user_name).await?
+        else {
+            return Ok(AuthOutcome::Unknown);
+        };
+
+        Ok(
+            if hash.verify_async(password.to_string()).await
+            {
+                AuthOutcome::Accepted(
+                    self.policy.external_user(user_name),
+                )
+            } else {
+                AuthOutcome::Rejected
+            },
+        )
+    }
+}
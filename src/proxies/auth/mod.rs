@@ -0,0 +1,198 @@
+mod constant;
+mod htpasswd;
+mod models;
+mod sqlite;
+mod webhook;
+
+pub use models::{AuthBackend, AuthMethod, AuthOutcome};
+
+use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use tracing::error;
+
+use crate::models::users::User;
+
+use super::utils::policy::ProxyPolicy;
+
+use htpasswd::HtpasswdAuthenticator;
+use sqlite::SqliteAuthenticator;
+use webhook::WebhookAuthenticator;
+
+/// Checks username/password credentials for a proxy
+#[async_trait]
+pub trait Authenticator: Send + Sync + Debug {
+    /// Errors mean the backend could not answer, for example
+    /// an unreachable webhook
+    async fn authenticate(
+        &self,
+        user_name: &str,
+        password: &str,
+    ) -> Result<AuthOutcome, String>;
+}
+
+/// Users of the proxy and the global users it inherits
+#[derive(Debug)]
+pub struct MemoryAuthenticator {
+    policy: Arc<ProxyPolicy>,
+}
+
+#[async_trait]
+impl Authenticator for MemoryAuthenticator {
+    async fn authenticate(
+        &self,
+        user_name: &str,
+        password: &str,
+    ) -> Result<AuthOutcome, String> {
+        let found = self.policy.find_user(user_name).await;
+        let known = found.is_some();
+
+        Ok(
+            match User::authenticate(
+                found,
+                password.to_string(),
+            )
+            .await
+            {
+                Some(user) => AuthOutcome::Accepted(user),
+                None if known => AuthOutcome::Rejected,
+                None => AuthOutcome::Unknown,
+            },
+        )
+    }
+}
+
+/// Backends asked in order. A backend that does not know the
+/// user or fails passes on to the next one, the first one
+/// that accepts or rejects the credentials decides.
+#[derive(Debug)]
+pub struct AuthChain {
+    backends: Vec<(AuthBackend, Box<dyn Authenticator>)>,
+}
+
+impl AuthChain {
+    /// Chain of the memory backend only
+    pub fn memory(policy: &Arc<ProxyPolicy>) -> Arc<Self> {
+        Arc::new(Self {
+            backends: vec![(
+                AuthBackend::Memory,
+                Box::new(MemoryAuthenticator {
+                    policy: Arc::clone(policy),
+                }),
+            )],
+        })
+    }
+
+    /// Opens every backend, `proxy` is the listening address
+    /// sent to webhooks
+    pub fn build(
+        backends: Vec<AuthBackend>,
+        policy: &Arc<ProxyPolicy>,
+        proxy: SocketAddr,
+    ) -> Result<Arc<Self>, String> {
+        let mut chain = Vec::with_capacity(backends.len());
+
+        for backend in backends {
+            backend.validate()?;
+
+            let authenticator: Box<dyn Authenticator> =
+                match &backend {
+                    AuthBackend::Memory => {
+                        Box::new(MemoryAuthenticator {
+                            policy: Arc::clone(policy),
+                        })
+                    }
+                    AuthBackend::Htpasswd { path } => {
+                        Box::new(
+                            HtpasswdAuthenticator::open(
+                                path,
+                                Arc::clone(policy),
+                            )?,
+                        )
+                    }
+                    AuthBackend::Sqlite { path, query } => {
+                        Box::new(SqliteAuthenticator::open(
+                            path,
+                            query,
+                            Arc::clone(policy),
+                        )?)
+                    }
+                    AuthBackend::Webhook {
+                        url,
+                        timeout,
+                    } => {
+                        Box::new(WebhookAuthenticator::new(
+                            url,
+                            *timeout,
+                            proxy,
+                            Arc::clone(policy),
+                        )?)
+                    }
+                };
+
+            chain.push((backend, authenticator));
+        }
+
+        Ok(Arc::new(Self { backends: chain }))
+    }
+
+    pub fn backends(&self) -> Vec<AuthBackend> {
+        self.backends
+            .iter()
+            .map(|(backend, _)| backend.clone())
+            .collect()
+    }
+
+    /// The user when the credentials are accepted
+    pub async fn login(
+        &self,
+        user_name: &str,
+        password: &str,
+    ) -> Option<User> {
+        match self.authenticate(user_name, password).await {
+            Ok(AuthOutcome::Accepted(user)) => Some(user),
+            Ok(_) => None,
+            Err(_e) => {
+                error!(
+                    "Can not check credentials of {}: {}",
+                    user_name, _e
+                );
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for AuthChain {
+    async fn authenticate(
+        &self,
+        user_name: &str,
+        password: &str,
+    ) -> Result<AuthOutcome, String> {
+        let mut failed = None;
+
+        for (backend, authenticator) in &self.backends {
+            match authenticator
+                .authenticate(user_name, password)
+                .await
+            {
+                Ok(AuthOutcome::Unknown) => {}
+                Ok(outcome) => return Ok(outcome),
+                Err(_e) => {
+                    error!(
+                        "Auth backend {:?} failed: {}",
+                        backend, _e
+                    );
+                    failed = Some(_e);
+                }
+            }
+        }
+
+        // Unknown to every backend that answered
+        match failed {
+            Some(_e) => Err(_e),
+            None => Ok(AuthOutcome::Unknown),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::users::User;
+
+use super::constant::{
+    DEFAULT_SQLITE_QUERY, DEFAULT_WEBHOOK_TIMEOUT,
+};
+
+/// Result of checking credentials with one backend
+#[derive(Debug, Clone)]
+pub enum AuthOutcome {
+    Accepted(User),
+    // The backend knows the user, the password is wrong
+    Rejected,
+    // The backend does not know the user, the next one in
+    // the chain is asked
+    Unknown,
+}
+
+/// Auth methods by name, mapped to the method codes
+/// proxies use
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    NoAuth,
+    UsernamePassword,
+}
+
+impl AuthMethod {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            AuthMethod::NoAuth => 0x00,
+            AuthMethod::UsernamePassword => 0x02,
+        }
+    }
+}
+
+/// Backend of an auth chain as configured
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthBackend {
+    // Users of the proxy and the global users it inherits
+    Memory,
+    // `user:hash` lines with bcrypt or argon2 hashes
+    Htpasswd {
+        path: PathBuf,
+    },
+    Sqlite {
+        path: PathBuf,
+        // Takes the user name as `?1`, returns the hash
+        #[serde(default = "default_sqlite_query")]
+        query: String,
+    },
+    // Credentials are posted as JSON, see the readme for
+    // the status codes
+    Webhook {
+        url: String,
+        // Seconds
+        #[serde(default = "default_webhook_timeout")]
+        timeout: u64,
+    },
+}
+
+fn default_sqlite_query() -> String {
+    DEFAULT_SQLITE_QUERY.to_string()
+}
+
+fn default_webhook_timeout() -> u64 {
+    DEFAULT_WEBHOOK_TIMEOUT
+}
+
+impl AuthBackend {
+    /// Default chain, only the proxy's own and global users
+    pub fn defaults() -> Vec<AuthBackend> {
+        vec![AuthBackend::Memory]
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            AuthBackend::Memory => {}
+            AuthBackend::Htpasswd { path } => {
+                if path.as_os_str().is_empty() {
+                    return Err("path must not be empty"
+                        .to_string());
+                }
+            }
+            AuthBackend::Sqlite { path, query } => {
+                if path.as_os_str().is_empty() {
+                    return Err("path must not be empty"
+                        .to_string());
+                }
+                if !query.contains("?1") {
+                    return Err(
+                        "query must take the user name as ?1"
+                            .to_string(),
+                    );
+                }
+            }
+            AuthBackend::Webhook { url, timeout } => {
+                let url = reqwest::Url::parse(url)
+                    .map_err(|_e| format!("url: {}", _e))?;
+                if !matches!(url.scheme(), "http" | "https")
+                {
+                    return Err(
+                        "url must be http or https"
+                            .to_string(),
+                    );
+                }
+                if *timeout == 0 {
+                    return Err(
+                        "timeout must be greater than 0"
+                            .to_string(),
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
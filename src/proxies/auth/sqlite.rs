@@ -0,0 +1,96 @@
+use std::{path::Path, sync::Arc};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+
+use crate::{
+    models::password::PasswordHash,
+    proxies::utils::policy::ProxyPolicy,
+};
+
+use super::{AuthOutcome, Authenticator};
+
+/// Hashes looked up in a SQLite database opened read only,
+/// the query gets the user name as `?1`
+#[derive(Debug)]
+pub struct SqliteAuthenticator {
+    conn: Arc<Mutex<Connection>>,
+    query: String,
+    policy: Arc<ProxyPolicy>,
+}
+
+impl SqliteAuthenticator {
+    pub fn open(
+        path: &Path,
+        query: &str,
+        policy: Arc<ProxyPolicy>,
+    ) -> Result<Self, String> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(|_e| {
+            format!("{}: {}", path.display(), _e)
+        })?;
+
+        // Fails early on a query that does not fit the schema
+        conn.prepare_cached(query)
+            .map_err(|_e| format!("query: {}", _e))?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            query: query.to_string(),
+            policy,
+        })
+    }
+}
+
+#[async_trait]
+impl Authenticator for SqliteAuthenticator {
+    async fn authenticate(
+        &self,
+        user_name: &str,
+        password: &str,
+    ) -> Result<AuthOutcome, String> {
+        let conn = Arc::clone(&self.conn);
+        let query = self.query.clone();
+        let name = user_name.to_string();
+        let password = password.to_string();
+
+        // Both the query and the hash are blocking
+        let verified = tokio::task::spawn_blocking(
+            move || -> Result<Option<bool>, String> {
+                let hash: Option<String> = conn
+                    .lock()
+                    .prepare_cached(&query)
+                    .and_then(|mut stmt| {
+                        stmt.query_row([&name], |row| {
+                            row.get(0)
+                        })
+                        .optional()
+                    })
+                    .map_err(|_e| _e.to_string())?;
+
+                match hash {
+                    Some(hash) => Ok(Some(
+                        PasswordHash::parse(&hash)?
+                            .verify(&password),
+                    )),
+                    None => Ok(None),
+                }
+            },
+        )
+        .await
+        .map_err(|_e| _e.to_string())??;
+
+        Ok(match verified {
+            Some(true) => AuthOutcome::Accepted(
+                self.policy.external_user(user_name),
+            ),
+            Some(false) => AuthOutcome::Rejected,
+            None => AuthOutcome::Unknown,
+        })
+    }
+}
@@ -0,0 +1,483 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::proxies::utils::{
+    policy::ProxyPolicy, quota::QuotaLimit,
+    rate_limit::RateLimit,
+};
+
+use super::{AuthOutcome, Authenticator};
+
+#[derive(Debug, Serialize)]
+struct WebhookRequest<'a> {
+    user_name: &'a str,
+    password: &'a str,
+    // Listening address of the proxy asking
+    proxy: SocketAddr,
+}
+
+/// Optional body of an accepting answer, limits replace the
+/// ones the user had
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebhookReply {
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    quota: Option<QuotaLimit>,
+}
+
+/// Credentials are posted as JSON. `2xx` accepts, `401` and
+/// `403` reject, `404` passes to the next backend, anything
+/// else is an error.
+#[derive(Debug)]
+pub struct WebhookAuthenticator {
+    client: Client,
+    url: String,
+    proxy: SocketAddr,
+    policy: Arc<ProxyPolicy>,
+}
+
+impl WebhookAuthenticator {
+    pub fn new(
+        url: &str,
+        timeout: u64,
+        proxy: SocketAddr,
+        policy: Arc<ProxyPolicy>,
+    ) -> Result<Self, String> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()
+            .map_err(|_e| _e.to_string())?;
+
+        Ok(Self {
+            client,
+            url: url.to_string(),
+            proxy,
+            policy,
+        })
+    }
+}
+
+#[async_trait]
+impl Authenticator for WebhookAuthenticator {
+    async fn authenticate(
+        &self,
+        user_name: &str,
+        password: &str,
+    ) -> Result<AuthOutcome, String> {
+        let resp = self
+            .client
+            .post(&self.url)
+            .json(&WebhookRequest {
+                user_name,
+                password,
+                proxy: self.proxy,
+            })
+            .send()
+            .await
+            .map_err(|_e| _e.to_string())?;
+
+        match resp.status() {
+            status if status.is_success() => {}
+            StatusCode::UNAUTHORIZED
+            | StatusCode::FORBIDDEN => {
+                return Ok(AuthOutcome::Rejected)
+            }
+            StatusCode::NOT_FOUND => {
+                return Ok(AuthOutcome::Unknown)
+            }
+            status => {
+                return Err(format!(
+                    "webhook answered {}",
+                    status
+                ))
+            }
+        }
+
+        let body = resp
+            .bytes()
+            .await
+            .map_err(|_e| _e.to_string())?;
+        let reply: WebhookReply = if body.is_empty() {
+            WebhookReply::default()
+        } else {
+            serde_json::from_slice(&body).map_err(|_e| {
+                format!("webhook reply: {}", _e)
+            })?
+        };
+        if let Some(limit) = &reply.rate_limit {
+            limit.validate().map_err(|_e| {
+                format!("rate_limit: {}", _e)
+            })?;
+        }
+        if let Some(quota) = &reply.quota {
+            quota
+                .validate()
+                .map_err(|_e| format!("quota: {}", _e))?;
+        }
+
+        let user = self.policy.external_user(user_name);
+        // Setting a limit refills the bucket, only do it when
+        // it changed
+        if user.rate_limiter.limit() != reply.rate_limit {
+            user.rate_limiter.set_limit(reply.rate_limit);
+        }
+        user.quota.set_limit(reply.quota);
+
+        Ok(AuthOutcome::Accepted(user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::RwLock,
+    };
+
+    use super::*;
+    use crate::{
+        models::{
+            password::PasswordHash,
+            users::{User, Users},
+        },
+        proxies::{
+            auth::{AuthBackend, AuthChain},
+            utils::policy::GlobalPolicy,
+        },
+    };
+
+    type Posted = Arc<Mutex<Vec<serde_json::Value>>>;
+
+    /// Answer of the stub endpoint for a user name, the
+    /// status, the body and how long it waits
+    fn answer(user_name: &str) -> (u16, &'static str, u64) {
+        match user_name {
+            "allowed" => (200, "", 0),
+            "limited" => {
+                (200, r#"{"rate_limit":{"rate":1000}}"#, 0)
+            }
+            "garbled" => (200, "{", 0),
+            "unauthorized" => (401, "", 0),
+            "forbidden" => (403, "", 0),
+            "failing" => (500, "", 0),
+            "slow" => (200, "", 3),
+            _ => (404, "", 0),
+        }
+    }
+
+    /// HTTP endpoint answering every request by `answer`,
+    /// the posted bodies are kept
+    async fn stub() -> (String, Posted) {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/auth",
+            listener.local_addr().unwrap()
+        );
+        let posted = Posted::default();
+
+        let seen = Arc::clone(&posted);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) =
+                    listener.accept().await.unwrap();
+                tokio::spawn(serve(
+                    stream,
+                    Arc::clone(&seen),
+                ));
+            }
+        });
+
+        (url, posted)
+    }
+
+    async fn serve(mut stream: TcpStream, posted: Posted) {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        let body = loop {
+            let n = stream.read(&mut chunk).await.unwrap();
+            if n == 0 {
+                return;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+
+            let text = String::from_utf8_lossy(&buf);
+            let Some(end) = text.find("\r\n\r\n") else {
+                continue;
+            };
+            let length = text[..end]
+                .lines()
+                .find_map(|line| {
+                    let (name, value) =
+                        line.split_once(':')?;
+                    name.eq_ignore_ascii_case(
+                        "content-length",
+                    )
+                    .then(|| value.trim().parse().ok())?
+                })
+                .unwrap_or(0);
+            if buf.len() >= end + 4 + length {
+                break buf[end + 4..end + 4 + length]
+                    .to_vec();
+            }
+        };
+
+        let request: serde_json::Value =
+            serde_json::from_slice(&body).unwrap();
+        let (status, reply, delay) =
+            answer(request["user_name"].as_str().unwrap());
+        posted.lock().unwrap().push(request);
+
+        tokio::time::sleep(Duration::from_secs(delay))
+            .await;
+        let resp = format!(
+            "HTTP/1.1 {} Stub\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status,
+            reply.len(),
+            reply
+        );
+        let _ = stream.write_all(resp.as_bytes()).await;
+    }
+
+    fn proxy() -> SocketAddr {
+        "127.0.0.1:1080".parse().unwrap()
+    }
+
+    fn policy(users: &[(&str, &str)]) -> Arc<ProxyPolicy> {
+        let users = users
+            .iter()
+            .map(|(name, password)| {
+                let user = User::new(
+                    *name,
+                    PasswordHash::hash(password).unwrap(),
+                );
+                (name.to_string(), user)
+            })
+            .collect::<Users>();
+
+        ProxyPolicy::new(
+            Arc::new(GlobalPolicy::default()),
+            Arc::new(RwLock::new(users)),
+            Arc::default(),
+        )
+    }
+
+    async fn webhook(
+        url: &str,
+    ) -> (WebhookAuthenticator, Arc<ProxyPolicy>) {
+        let policy = policy(&[]);
+        let webhook = WebhookAuthenticator::new(
+            url,
+            1,
+            proxy(),
+            Arc::clone(&policy),
+        )
+        .unwrap();
+
+        (webhook, policy)
+    }
+
+    #[tokio::test]
+    async fn accepts_on_success() {
+        let (url, posted) = stub().await;
+        let (webhook, policy) = webhook(&url).await;
+
+        let outcome =
+            webhook.authenticate("allowed", "pw").await;
+        let Ok(AuthOutcome::Accepted(user)) = outcome
+        else {
+            panic!("{:?}", outcome);
+        };
+        assert_eq!(user.user_name, "allowed");
+        assert!(user.password.is_none());
+        assert_eq!(user.rate_limiter.limit(), None);
+        // The same user on every login
+        assert_eq!(
+            policy.external_user("allowed").user_id,
+            user.user_id
+        );
+
+        let posted = posted.lock().unwrap();
+        assert_eq!(
+            posted[0],
+            serde_json::json!({
+                "user_name": "allowed",
+                "password": "pw",
+                "proxy": "127.0.0.1:1080",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn reply_sets_limits() {
+        let (url, _) = stub().await;
+        let (webhook, _) = webhook(&url).await;
+
+        let outcome =
+            webhook.authenticate("limited", "pw").await;
+        let Ok(AuthOutcome::Accepted(user)) = outcome
+        else {
+            panic!("{:?}", outcome);
+        };
+        assert_eq!(
+            user.rate_limiter.limit(),
+            Some(RateLimit {
+                rate: 1000,
+                burst: None
+            })
+        );
+
+        let err = webhook
+            .authenticate("garbled", "pw")
+            .await
+            .unwrap_err();
+        assert!(
+            err.starts_with("webhook reply: "),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_and_passes_on() {
+        let (url, _) = stub().await;
+        let (webhook, _) = webhook(&url).await;
+
+        for user_name in ["unauthorized", "forbidden"] {
+            assert!(matches!(
+                webhook.authenticate(user_name, "pw").await,
+                Ok(AuthOutcome::Rejected)
+            ));
+        }
+        assert!(matches!(
+            webhook.authenticate("nobody", "pw").await,
+            Ok(AuthOutcome::Unknown)
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_errors_fail() {
+        let (url, _) = stub().await;
+        let (webhook, _) = webhook(&url).await;
+
+        assert_eq!(
+            webhook
+                .authenticate("failing", "pw")
+                .await
+                .unwrap_err(),
+            "webhook answered 500 Internal Server Error"
+        );
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let (url, _) = stub().await;
+        let (webhook, _) = webhook(&url).await;
+
+        let started = std::time::Instant::now();
+        assert!(webhook
+            .authenticate("slow", "pw")
+            .await
+            .is_err());
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn unreachable_fails() {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/auth",
+            listener.local_addr().unwrap()
+        );
+        drop(listener);
+        let (webhook, _) = webhook(&url).await;
+
+        assert!(webhook
+            .authenticate("allowed", "pw")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn chain_falls_through() {
+        let (url, posted) = stub().await;
+        let chain = AuthChain::build(
+            vec![
+                AuthBackend::Memory,
+                AuthBackend::Webhook { url, timeout: 1 },
+            ],
+            &policy(&[("alice", "secret")]),
+            proxy(),
+        )
+        .unwrap();
+
+        // Known to memory, the webhook is not asked
+        let user = chain.login("alice", "secret").await;
+        assert!(user.unwrap().password.is_some());
+        assert!(chain
+            .login("alice", "wrong")
+            .await
+            .is_none());
+        assert!(posted.lock().unwrap().is_empty());
+
+        // Unknown to memory, the webhook decides
+        let user = chain.login("allowed", "pw").await;
+        assert!(user.unwrap().password.is_none());
+        assert!(chain
+            .login("forbidden", "pw")
+            .await
+            .is_none());
+        assert!(matches!(
+            chain.authenticate("nobody", "pw").await,
+            Ok(AuthOutcome::Unknown)
+        ));
+        assert!(chain
+            .login("failing", "pw")
+            .await
+            .is_none());
+        assert_eq!(posted.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn chain_skips_failed_backend() {
+        let (url, posted) = stub().await;
+        let chain = AuthChain::build(
+            vec![
+                AuthBackend::Webhook { url, timeout: 1 },
+                AuthBackend::Memory,
+            ],
+            &policy(&[("failing", "secret")]),
+            proxy(),
+        )
+        .unwrap();
+
+        // The webhook fails, memory still decides
+        assert!(chain
+            .login("failing", "secret")
+            .await
+            .is_some());
+        assert!(matches!(
+            chain.authenticate("failing", "wrong").await,
+            Ok(AuthOutcome::Rejected)
+        ));
+        assert_eq!(posted.lock().unwrap().len(), 2);
+
+        // Unknown to the rest, the failure is reported
+        let mut chain = Arc::try_unwrap(chain).unwrap();
+        chain.backends.pop();
+        assert!(chain
+            .authenticate("failing", "secret")
+            .await
+            .is_err());
+    }
+}
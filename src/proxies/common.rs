@@ -7,6 +7,10 @@ pub enum ProxyError {
     ParseError(String),
     ConnectionError(String),
     TimeoutError(String),
+    // Peer closed before a message was complete
+    TruncatedError(String),
+    // A field is longer than allowed
+    OversizedError(String),
 }
 
 impl fmt::Display for ProxyError {
@@ -27,6 +31,12 @@ impl fmt::Display for ProxyError {
             ProxyError::TimeoutError(msg) => {
                 write!(f, "Timeout Error: {}", msg)
             }
+            ProxyError::TruncatedError(msg) => {
+                write!(f, "Truncated Message: {}", msg)
+            }
+            ProxyError::OversizedError(msg) => {
+                write!(f, "Oversized Message: {}", msg)
+            }
         }
     }
 }
@@ -42,4 +52,10 @@ impl std::error::Error for ProxyError {
     }
 }
 
+impl From<io::Error> for ProxyError {
+    fn from(err: io::Error) -> Self {
+        ProxyError::IoError(err)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, ProxyError>;
@@ -0,0 +1,12 @@
+// Seconds a single query may take
+pub const DEFAULT_DNS_TIMEOUT: u64 = 5;
+
+// Names kept in the cache
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+// Bounds of the TTL answers are cached for, in seconds
+pub const DEFAULT_MIN_TTL: u64 = 0;
+pub const DEFAULT_MAX_TTL: u64 = 3600;
+
+// Seconds a name that does not exist is remembered at most
+pub const DEFAULT_NEGATIVE_TTL: u64 = 30;
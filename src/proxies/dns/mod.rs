@@ -0,0 +1,577 @@
+mod constant;
+mod models;
+
+pub use models::{DnsConfig, DnsStats};
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use hickory_resolver::{
+    config::{
+        LookupIpStrategy, NameServerConfigGroup,
+        ResolverConfig, ResolverOpts,
+    },
+    error::ResolveErrorKind,
+    system_conf, TokioAsyncResolver,
+};
+use metrics::{counter, histogram};
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+
+#[derive(Debug)]
+struct CacheEntry {
+    expires: Instant,
+    // `None` when the name does not exist
+    addrs: Option<Vec<IpAddr>>,
+}
+
+/// Resolves domain targets of every proxy without blocking
+/// the runtime. Answers are cached for their TTL and names
+/// that do not exist for the negative TTL, failures are not
+/// cached.
+#[derive(Debug)]
+pub struct Resolver {
+    // Replaced as a whole when the config changes
+    state: Mutex<(DnsConfig, Arc<TokioAsyncResolver>)>,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    lookups: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl Resolver {
+    pub fn new(config: &DnsConfig) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new((
+                config.clone(),
+                Arc::new(build(config)),
+            )),
+            cache: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            negative_hits: AtomicU64::new(0),
+            lookups: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        })
+    }
+
+    pub fn config(&self) -> DnsConfig {
+        self.state.lock().0.clone()
+    }
+
+    /// Switch to a new config, the cache is emptied
+    pub fn configure(&self, config: &DnsConfig) {
+        *self.state.lock() =
+            (config.clone(), Arc::new(build(config)));
+        self.clear();
+    }
+
+    pub fn clear(&self) {
+        self.cache.lock().clear();
+    }
+
+    pub fn stats(&self) -> DnsStats {
+        DnsStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            negative_hits: self
+                .negative_hits
+                .load(Ordering::Relaxed),
+            lookups: self.lookups.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            cached: self.cache.lock().len(),
+        }
+    }
+
+    /// Every address of a host with the given port
+    pub async fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, String> {
+        Ok(self
+            .lookup(host)
+            .await?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Every address of a host, IPv4 ones first. Literal
+    /// addresses are returned as they are.
+    pub async fn lookup(
+        &self,
+        host: &str,
+    ) -> Result<Vec<IpAddr>, String> {
+        let literal = host
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+
+        let name = host.to_ascii_lowercase();
+
+        if let Some(addrs) = self.cached(&name) {
+            return addrs.ok_or_else(|| {
+                format!("{} does not exist", host)
+            });
+        }
+
+        let (config, resolver) = self.state.lock().clone();
+
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        counter!("proxier_dns_lookups_total").increment(1);
+        let started = Instant::now();
+
+        let result = resolver.lookup_ip(host).await;
+
+        histogram!("proxier_dns_lookup_seconds")
+            .record(started.elapsed().as_secs_f64());
+
+        match result {
+            Ok(lookup) => {
+                let mut addrs: Vec<IpAddr> =
+                    lookup.iter().collect();
+                addrs.sort_by_key(|ip| ip.is_ipv6());
+
+                let ttl = lookup
+                    .valid_until()
+                    .saturating_duration_since(
+                        Instant::now(),
+                    )
+                    .clamp(
+                        Duration::from_secs(config.min_ttl),
+                        Duration::from_secs(config.max_ttl),
+                    );
+                debug!(
+                    "Resolved {} to {:?} for {:?}",
+                    host, addrs, ttl
+                );
+                self.store(
+                    name,
+                    Some(addrs.clone()),
+                    ttl,
+                    &config,
+                );
+
+                Ok(addrs)
+            }
+            Err(_e) => match _e.kind() {
+                ResolveErrorKind::NoRecordsFound {
+                    negative_ttl,
+                    ..
+                } => {
+                    // The SOA minimum when the server sent one
+                    let ttl = negative_ttl.map_or(
+                        config.negative_ttl,
+                        |ttl| {
+                            u64::from(ttl)
+                                .min(config.negative_ttl)
+                        },
+                    );
+                    self.store(
+                        name,
+                        None,
+                        Duration::from_secs(ttl),
+                        &config,
+                    );
+
+                    Err(format!("{} does not exist", host))
+                }
+                _ => {
+                    self.failures
+                        .fetch_add(1, Ordering::Relaxed);
+                    counter!("proxier_dns_failures_total")
+                        .increment(1);
+
+                    Err(format!(
+                        "Can not resolve {}: {}",
+                        host, _e
+                    ))
+                }
+            },
+        }
+    }
+
+    /// Unexpired answer, `Some(None)` when the name is known
+    /// not to exist
+    fn cached(
+        &self,
+        name: &str,
+    ) -> Option<Option<Vec<IpAddr>>> {
+        let cache = self.cache.lock();
+        let entry = cache
+            .get(name)
+            .filter(|e| e.expires > Instant::now())?;
+
+        if entry.addrs.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            counter!("proxier_dns_cache_hits_total")
+                .increment(1);
+        } else {
+            self.negative_hits
+                .fetch_add(1, Ordering::Relaxed);
+            counter!(
+                "proxier_dns_cache_negative_hits_total"
+            )
+            .increment(1);
+        }
+
+        Some(entry.addrs.clone())
+    }
+
+    fn store(
+        &self,
+        name: String,
+        addrs: Option<Vec<IpAddr>>,
+        ttl: Duration,
+        config: &DnsConfig,
+    ) {
+        if config.cache_size == 0 || ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock();
+
+        if cache.len() >= config.cache_size
+            && !cache.contains_key(&name)
+        {
+            cache.retain(|_, e| e.expires > now);
+        }
+        // Still full, drop the entry closest to expiring
+        if cache.len() >= config.cache_size
+            && !cache.contains_key(&name)
+        {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, e)| e.expires)
+                .map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+
+        cache.insert(
+            name,
+            CacheEntry {
+                expires: now + ttl,
+                addrs,
+            },
+        );
+    }
+}
+
+/// Resolver without its own cache, answers are cached by
+/// `Resolver` so the TTL bounds apply
+fn build(config: &DnsConfig) -> TokioAsyncResolver {
+    let (resolver_config, mut opts) = if config
+        .servers
+        .is_empty()
+    {
+        system_conf::read_system_conf().unwrap_or_else(
+                |_e| {
+                    warn!(
+                        "Can not read system DNS config, using defaults: {}",
+                        _e
+                    );
+                    (
+                        ResolverConfig::default(),
+                        ResolverOpts::default(),
+                    )
+                },
+            )
+    } else {
+        // Servers may listen on different ports
+        let mut group = NameServerConfigGroup::new();
+        for server in &config.servers {
+            group.merge(
+                NameServerConfigGroup::from_ips_clear(
+                    &[server.ip()],
+                    server.port(),
+                    true,
+                ),
+            );
+        }
+        (
+            ResolverConfig::from_parts(None, vec![], group),
+            ResolverOpts::default(),
+        )
+    };
+
+    opts.timeout = Duration::from_secs(config.timeout);
+    opts.cache_size = 0;
+    opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+
+    TokioAsyncResolver::tokio(resolver_config, opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    const A: u16 = 1;
+    const SOA: u16 = 6;
+    const NXDOMAIN: u16 = 3;
+
+    /// DNS server answering `<name>.test` with 192.0.2.1
+    /// for `ttl` seconds, `missing.test` does not exist.
+    /// Returns its address and the number of questions it
+    /// got.
+    async fn stub(
+        ttl: u32,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket =
+            UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addrs = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+
+        let counted = Arc::clone(&queries);
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let Ok((len, from)) =
+                    socket.recv_from(&mut buf).await
+                else {
+                    return;
+                };
+                counted.fetch_add(1, Ordering::Relaxed);
+                let reply = answer(&buf[..len], ttl);
+                let _ = socket.send_to(&reply, from).await;
+            }
+        });
+
+        (addrs, queries)
+    }
+
+    fn answer(query: &[u8], ttl: u32) -> Vec<u8> {
+        // Question name ends at the root label
+        let mut end = 12;
+        let mut labels = Vec::new();
+        while query[end] != 0 {
+            let len = query[end] as usize;
+            labels.push(
+                String::from_utf8_lossy(
+                    &query[end + 1..end + 1 + len],
+                )
+                .to_lowercase(),
+            );
+            end += 1 + len;
+        }
+        let qtype = u16::from_be_bytes([
+            query[end + 1],
+            query[end + 2],
+        ]);
+        let question = &query[12..end + 5];
+        let missing =
+            labels.first().is_some_and(|l| l == "missing");
+
+        let mut records = Vec::new();
+        let (rcode, answers) = if missing {
+            (NXDOMAIN, 0)
+        } else if qtype == A {
+            records.extend_from_slice(&[0xc0, 0x0c]);
+            records.extend_from_slice(&A.to_be_bytes());
+            records.extend_from_slice(&1u16.to_be_bytes());
+            records.extend_from_slice(&ttl.to_be_bytes());
+            records.extend_from_slice(&4u16.to_be_bytes());
+            records.extend_from_slice(&[192, 0, 2, 1]);
+            (0, 1)
+        } else {
+            (0, 0)
+        };
+
+        // SOA with a minimum of `ttl` for negative answers
+        let authority = u16::from(answers == 0);
+        if answers == 0 {
+            let mut soa = vec![
+                2, b'n', b's', 0, 2, b'h', b'm', 0,
+            ];
+            for value in [1, 60, 60, 60, ttl] {
+                soa.extend_from_slice(&value.to_be_bytes());
+            }
+            records.extend_from_slice(&[0xc0, 0x0c]);
+            records.extend_from_slice(&SOA.to_be_bytes());
+            records.extend_from_slice(&1u16.to_be_bytes());
+            records.extend_from_slice(&ttl.to_be_bytes());
+            records.extend_from_slice(
+                &(soa.len() as u16).to_be_bytes(),
+            );
+            records.extend_from_slice(&soa);
+        }
+
+        let mut reply = query[..2].to_vec();
+        reply.extend_from_slice(
+            &(0x8180 | rcode).to_be_bytes(),
+        );
+        for count in [1, answers, authority, 0] {
+            reply.extend_from_slice(&count.to_be_bytes());
+        }
+        reply.extend_from_slice(question);
+        reply.extend_from_slice(&records);
+        reply
+    }
+
+    fn resolver(server: SocketAddr) -> Arc<Resolver> {
+        Resolver::new(&DnsConfig {
+            servers: vec![server],
+            timeout: 2,
+            ..DnsConfig::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn caches_answers_for_their_ttl() {
+        let (server, queries) = stub(1).await;
+        let resolver = resolver(server);
+
+        let addrs = resolver
+            .resolve("Cached.test", 80)
+            .await
+            .unwrap();
+        assert_eq!(
+            addrs,
+            vec!["192.0.2.1:80".parse().unwrap()]
+        );
+        let asked = queries.load(Ordering::Relaxed);
+        assert!(asked > 0);
+
+        // Names are cached lowercase
+        let addrs =
+            resolver.lookup("cached.TEST").await.unwrap();
+        assert_eq!(
+            addrs,
+            vec![IpAddr::from([192, 0, 2, 1])]
+        );
+        assert_eq!(queries.load(Ordering::Relaxed), asked);
+
+        let stats = resolver.stats();
+        assert_eq!(
+            (stats.hits, stats.lookups, stats.cached),
+            (1, 1, 1)
+        );
+
+        tokio::time::sleep(Duration::from_millis(1100))
+            .await;
+
+        resolver.lookup("cached.test").await.unwrap();
+        assert!(queries.load(Ordering::Relaxed) > asked);
+        assert_eq!(resolver.stats().lookups, 2);
+    }
+
+    #[tokio::test]
+    async fn caches_missing_names() {
+        let (server, queries) = stub(1).await;
+        let resolver = resolver(server);
+
+        let err = resolver
+            .lookup("missing.test")
+            .await
+            .unwrap_err();
+        assert_eq!(err, "missing.test does not exist");
+        let asked = queries.load(Ordering::Relaxed);
+
+        let err = resolver
+            .lookup("missing.test")
+            .await
+            .unwrap_err();
+        assert_eq!(err, "missing.test does not exist");
+        assert_eq!(queries.load(Ordering::Relaxed), asked);
+
+        let stats = resolver.stats();
+        assert_eq!(
+            (
+                stats.negative_hits,
+                stats.lookups,
+                stats.failures
+            ),
+            (1, 1, 0)
+        );
+
+        // Remembered for the SOA minimum
+        tokio::time::sleep(Duration::from_millis(1100))
+            .await;
+
+        resolver.lookup("missing.test").await.unwrap_err();
+        assert!(queries.load(Ordering::Relaxed) > asked);
+    }
+
+    #[tokio::test]
+    async fn min_ttl_and_disabled_cache() {
+        let (server, queries) = stub(0).await;
+
+        let resolver = Resolver::new(&DnsConfig {
+            servers: vec![server],
+            min_ttl: 60,
+            ..DnsConfig::default()
+        });
+        resolver.lookup("short.test").await.unwrap();
+        resolver.lookup("short.test").await.unwrap();
+        assert_eq!(resolver.stats().hits, 1);
+
+        let resolver = Resolver::new(&DnsConfig {
+            servers: vec![server],
+            cache_size: 0,
+            min_ttl: 60,
+            ..DnsConfig::default()
+        });
+        let asked = queries.load(Ordering::Relaxed);
+        resolver.lookup("short.test").await.unwrap();
+        resolver.lookup("short.test").await.unwrap();
+        assert_eq!(resolver.stats().hits, 0);
+        assert_eq!(resolver.stats().lookups, 2);
+        assert!(queries.load(Ordering::Relaxed) > asked);
+    }
+
+    #[tokio::test]
+    async fn failures_are_not_cached() {
+        // Nothing listens there
+        let socket =
+            UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        drop(socket);
+
+        let resolver = Resolver::new(&DnsConfig {
+            servers: vec![server],
+            timeout: 1,
+            ..DnsConfig::default()
+        });
+
+        assert!(resolver
+            .lookup("down.test")
+            .await
+            .is_err());
+        assert!(resolver
+            .lookup("down.test")
+            .await
+            .is_err());
+
+        let stats = resolver.stats();
+        assert_eq!((stats.failures, stats.cached), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn literals_are_not_looked_up() {
+        let resolver = Resolver::new(&DnsConfig::default());
+
+        let addrs =
+            resolver.resolve("[::1]", 443).await.unwrap();
+        assert_eq!(
+            addrs,
+            vec!["[::1]:443".parse().unwrap()]
+        );
+        assert_eq!(resolver.stats().lookups, 0);
+    }
+}
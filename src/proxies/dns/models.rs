@@ -0,0 +1,112 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use super::constant::{
+    DEFAULT_CACHE_SIZE, DEFAULT_DNS_TIMEOUT,
+    DEFAULT_MAX_TTL, DEFAULT_MIN_TTL, DEFAULT_NEGATIVE_TTL,
+};
+
+/// Resolver used by every proxy for domain targets
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct DnsConfig {
+    // Upstream servers, the system ones when empty
+    #[serde(default)]
+    pub servers: Vec<SocketAddr>,
+
+    // Seconds a single query may take
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+
+    // Names kept in the cache, 0 disables it
+    #[serde(default = "default_cache_size")]
+    pub cache_size: usize,
+
+    // Record TTLs are clamped to these, in seconds
+    #[serde(default = "default_min_ttl")]
+    pub min_ttl: u64,
+    #[serde(default = "default_max_ttl")]
+    pub max_ttl: u64,
+
+    // Seconds a name that does not exist is remembered at
+    // most, 0 disables negative caching
+    #[serde(default = "default_negative_ttl")]
+    pub negative_ttl: u64,
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_DNS_TIMEOUT
+}
+
+fn default_cache_size() -> usize {
+    DEFAULT_CACHE_SIZE
+}
+
+fn default_min_ttl() -> u64 {
+    DEFAULT_MIN_TTL
+}
+
+fn default_max_ttl() -> u64 {
+    DEFAULT_MAX_TTL
+}
+
+fn default_negative_ttl() -> u64 {
+    DEFAULT_NEGATIVE_TTL
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            timeout: default_timeout(),
+            cache_size: default_cache_size(),
+            min_ttl: default_min_ttl(),
+            max_ttl: default_max_ttl(),
+            negative_ttl: default_negative_ttl(),
+        }
+    }
+}
+
+impl DnsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timeout == 0 {
+            return Err("timeout must be greater than 0"
+                .to_string());
+        }
+        if self.min_ttl > self.max_ttl {
+            return Err(
+                "min_ttl must not be greater than max_ttl"
+                    .to_string(),
+            );
+        }
+        if let Some(server) =
+            self.servers.iter().find(|s| s.port() == 0)
+        {
+            return Err(format!(
+                "server {} has no port",
+                server
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Resolver counters since start
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct DnsStats {
+    // Answered from the cache
+    pub hits: u64,
+    // Answered from the cache with "does not exist"
+    pub negative_hits: u64,
+    // Sent to the upstream servers
+    pub lookups: u64,
+    // Lookups that failed for another reason than a
+    // missing name, never cached
+    pub failures: u64,
+    // Names in the cache, expired ones included
+    pub cached: usize,
+}
@@ -0,0 +1,26 @@
+// Auth method codes, same values as SOCKS5 so both proxy
+// types are configured alike through `ProxyEx`
+pub const NO_AUTH: u8 = 0x00;
+pub const USERNAME_PASSWORD: u8 = 0x02;
+
+pub const PROXY_REALM: &str = "proxier";
+
+// Upper bound for a request or response head
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+// Upper bounds for one chunk of a chunked body and for the
+// extensions on its size line
+pub const MAX_CHUNK_SIZE: u64 = 1 << 30;
+pub const MAX_CHUNK_EXT_SIZE: usize = 1024;
+
+// Headers that are meaningful only for a single connection
+pub const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
@@ -1,60 +1,1151 @@
-use std::{collections::HashSet, io};
+mod constant;
+mod models;
+
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use async_trait::async_trait;
-use tokio::sync::RwLock;
+use constant::{
+    MAX_CHUNK_EXT_SIZE, MAX_CHUNK_SIZE, MAX_HEAD_SIZE,
+    NO_AUTH, PROXY_REALM, USERNAME_PASSWORD,
+};
+use models::{
+    BodyLength, HttpRequest, HttpResponse, Target,
+};
+use parking_lot::Mutex;
+use tokio::{
+    io::{
+        self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt,
+        AsyncWrite, AsyncWriteExt, BufReader,
+    },
+    net::{TcpListener, TcpStream},
+    sync::RwLock,
+    task::JoinHandle,
+    time,
+};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::models::{
+    password::CredentialsDigest,
+    users::{User, Users},
+};
+
+use super::{
+    auth::{AuthBackend, AuthChain},
+    common::{ProxyError, Result as ProxyResult},
+    dns::Resolver,
+    proxy_manager::{ProxyEx, ProxyType},
+    utils::{
+        connect::{connect_happy_eyeballs, ConnectOptions},
+        io::{accept_with_backoff, copy_limited},
+        policy::{GlobalPolicy, ProxyPolicy},
+        rate_limit::{
+            Limiters, RateLimit, RateLimits, TokenBucket,
+        },
+        rules::Destination,
+        sessions::{
+            SessionInfo, SessionStats, Sessions,
+            StopSessions,
+        },
+        timeout::{
+            copy_bidirectional_timeout, timed_out, Timeouts,
+        },
+    },
+};
+
+/// Upstream connection kept alive between requests of the
+/// same client, keyed by its authority
+type Upstream = (String, BufReader<TcpStream>);
+
+/// Credentials accepted on a connection and the chain that
+/// accepted them
+type Verified = (Arc<AuthChain>, CredentialsDigest, User);
+
+#[derive(Clone, Debug)]
+pub struct HttpProxy {
+    // Listening address
+    addrs: SocketAddr,
+
+    avaliable_auth_methods: Arc<RwLock<HashSet<u8>>>,
+    avaliable_users: Arc<RwLock<Users>>,
 
-use super::proxy_manager::ProxyEx;
+    blocked_ippaddr: Arc<RwLock<HashSet<IpAddr>>>,
+    // Own users and blocks layered over the global ones
+    policy: Arc<ProxyPolicy>,
+    // Shared with every proxy of the manager
+    resolver: Arc<Resolver>,
+    // Replaced as a whole when the backends change
+    authenticator: Arc<Mutex<Arc<AuthChain>>>,
 
-#[derive(Debug)]
-pub struct HttpProxy {}
+    max_bandwith: Arc<AtomicU64>,
+    bandwith: Arc<AtomicU64>,
+
+    // Shared by every connection
+    rate_limiter: Arc<TokenBucket>,
+    // Each connection gets its own bucket
+    connection_rate_limit: Arc<Mutex<Option<RateLimit>>>,
+
+    // Address families and racing of outbound connections
+    connect_options: Arc<Mutex<ConnectOptions>>,
+    timeouts: Arc<Mutex<Timeouts>>,
+
+    // Accept loop, aborting it closes the listener
+    accept_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    sessions: Arc<Sessions>,
+}
 
-/*
 #[async_trait]
 impl ProxyEx for HttpProxy {
     async fn start(&self) -> Result<(), String> {
-        todo!()
+        if self.is_running() {
+            return Err(
+                "Proxy already running!".to_string()
+            );
+        }
+
+        let addrs = self.addrs;
+
+        let listener = TcpListener::bind(addrs)
+            .await
+            .map_err(|_e| _e.to_string())?;
+
+        info!("Starting http proxy on : {}", addrs);
+
+        let proxy = Arc::new(self.clone());
+        let sessions = Arc::clone(&self.sessions);
+
+        let accept_task = tokio::spawn(async move {
+            loop {
+                let (socket, addr) =
+                    accept_with_backoff(&listener).await;
+
+                let proxy_clone = Arc::clone(&proxy);
+
+                sessions.spawn(addr, |stats| async move {
+                    if let Err(_e) = handle_conn(
+                        proxy_clone,
+                        socket,
+                        stats,
+                    )
+                    .await
+                    {
+                        error!(
+                            "Http connection {} failed: {}",
+                            addr, _e
+                        );
+                    }
+                });
+            }
+        });
+
+        *self.accept_task.lock() = Some(accept_task);
+
+        Ok(())
+    }
+
+    async fn stop(&self, sessions: StopSessions) {
+        let accept_task = self.accept_task.lock().take();
+
+        if let Some(accept_task) = accept_task {
+            accept_task.abort();
+            // Wait until the listener is dropped and the
+            // port is free again
+            let _ = accept_task.await;
+            info!("Stopped proxy on : {}", self.addrs);
+        }
+
+        self.sessions.close(sessions).await;
+    }
+
+    fn is_running(&self) -> bool {
+        self.accept_task.lock().is_some()
+    }
+
+    fn active_sessions(&self) -> usize {
+        self.sessions.active()
+    }
+
+    fn sessions(&self) -> Vec<SessionInfo> {
+        self.sessions.list()
+    }
+
+    fn policy(&self) -> Arc<ProxyPolicy> {
+        Arc::clone(&self.policy)
+    }
+
+    fn auth_backends(&self) -> Vec<AuthBackend> {
+        self.authenticator.lock().backends()
+    }
+
+    fn set_auth_backends(
+        &self,
+        backends: Vec<AuthBackend>,
+    ) -> Result<(), String> {
+        let chain = AuthChain::build(
+            backends,
+            &self.policy,
+            self.addrs,
+        )?;
+        *self.authenticator.lock() = chain;
+        Ok(())
+    }
+
+    fn proxy_type(&self) -> ProxyType {
+        ProxyType::Http
     }
 
     async fn avaliable_auth_methods(&self) -> HashSet<u8> {
-        todo!()
+        self.avaliable_auth_methods.read().await.clone()
     }
 
     async fn set_avaliable_auth_method(
         &self,
         methods: Vec<u8>,
     ) {
-        todo!()
+        let mut set =
+            self.avaliable_auth_methods.write().await;
+
+        methods.iter().for_each(|method| {
+            set.insert(*method);
+        });
     }
 
     async fn remove_avaliable_auth_method(
         &self,
         methods: Vec<u8>,
     ) {
-        todo!()
+        let mut set =
+            self.avaliable_auth_methods.write().await;
+
+        methods.iter().for_each(|method| {
+            set.remove(method);
+        });
     }
 
-    async fn avaliable_users(
-        &self,
-    ) -> std::collections::HashSet<crate::models::users::User>
-    {
-        todo!()
+    async fn avaliable_users(&self) -> Users {
+        let res = self.avaliable_users.read().await;
+        res.clone()
     }
 
-    async fn set_user(
-        &self,
-        user: crate::models::users::User,
-    ) {
-        todo!()
+    async fn set_user(&self, user: User) -> bool {
+        let mut users = self.avaliable_users.write().await;
+        if users.contains_key(&user.user_name) {
+            return false;
+        }
+
+        users.insert(user.user_name.clone(), user);
+        true
     }
 
     async fn remove_user(&self, user_id: &str) -> bool {
-        todo!()
+        let Ok(user_id) = Uuid::from_str(user_id) else {
+            return false;
+        };
+
+        let mut users = self.avaliable_users.write().await;
+        let before = users.len();
+        users.retain(|_, user| user.user_id != user_id);
+
+        users.len() != before
+    }
+
+    async fn set_max_bandwith(&self, max: u64) {
+        self.max_bandwith.store(max, Ordering::Relaxed);
+    }
+
+    fn max_bandwith(&self) -> u64 {
+        self.max_bandwith.load(Ordering::Relaxed)
+    }
+
+    fn current_bandwith(&self) -> u64 {
+        self.bandwith.load(Ordering::Relaxed)
+    }
+
+    async fn set_rate_limits(&self, limits: RateLimits) {
+        self.rate_limiter.set_limit(limits.proxy);
+        *self.connection_rate_limit.lock() =
+            limits.connection;
+    }
+
+    fn connect_options(&self) -> ConnectOptions {
+        *self.connect_options.lock()
+    }
+
+    fn set_connect_options(&self, options: ConnectOptions) {
+        *self.connect_options.lock() = options;
+    }
+
+    fn timeouts(&self) -> Option<Timeouts> {
+        Some(*self.timeouts.lock())
+    }
+
+    fn set_timeouts(
+        &self,
+        timeouts: Timeouts,
+    ) -> Result<(), String> {
+        *self.timeouts.lock() = timeouts;
+        Ok(())
+    }
+
+    fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            proxy: self.rate_limiter.limit(),
+            connection: *self.connection_rate_limit.lock(),
+        }
+    }
+
+    async fn block_ip_address(&self, addrs: &IpAddr) {
+        let mut binding =
+            self.blocked_ippaddr.write().await;
+        binding.insert(*addrs);
+    }
+
+    async fn get_blocked_address(&self) -> HashSet<IpAddr> {
+        let binding = self.blocked_ippaddr.read().await;
+        binding.clone()
+    }
+
+    async fn remove_blocked_address(&self, addrs: &IpAddr) {
+        let mut binding =
+            self.blocked_ippaddr.write().await;
+        binding.remove(addrs);
     }
 }
 
-*/
 impl HttpProxy {
-    pub fn new() -> Self {
-        HttpProxy {}
+    pub fn new(
+        addrs: SocketAddr,
+        global: Arc<GlobalPolicy>,
+        resolver: Arc<Resolver>,
+    ) -> Self {
+        let avaliable_users =
+            Arc::new(RwLock::new(Users::new()));
+        let blocked_ippaddr =
+            Arc::new(RwLock::new(HashSet::new()));
+
+        let policy = ProxyPolicy::new(
+            global,
+            Arc::clone(&avaliable_users),
+            Arc::clone(&blocked_ippaddr),
+        );
+
+        Self {
+            addrs,
+            avaliable_auth_methods: Arc::new(RwLock::new(
+                HashSet::new(),
+            )),
+            authenticator: Arc::new(Mutex::new(
+                AuthChain::memory(&policy),
+            )),
+            policy,
+            resolver,
+            avaliable_users,
+            blocked_ippaddr,
+
+            max_bandwith: Arc::new(AtomicU64::new(0)),
+            bandwith: Arc::new(AtomicU64::new(0)),
+
+            rate_limiter: TokenBucket::new(None),
+            connection_rate_limit: Arc::new(Mutex::new(
+                None,
+            )),
+            connect_options: Arc::new(Mutex::new(
+                ConnectOptions::default(),
+            )),
+            timeouts: Arc::new(Mutex::new(
+                Timeouts::default(),
+            )),
+
+            accept_task: Arc::new(Mutex::new(None)),
+            sessions: Sessions::new(),
+        }
+    }
+
+    /// Bucket for the connection rate limit, one per client
+    /// connection
+    fn connection_limiter(&self) -> Arc<TokenBucket> {
+        TokenBucket::new(*self.connection_rate_limit.lock())
+    }
+
+    /// Limits and counters relayed bytes of a connection
+    /// pass
+    fn limiters(
+        &self,
+        connection: &Arc<TokenBucket>,
+        user: Option<&User>,
+        stats: &SessionStats,
+    ) -> Limiters {
+        let mut limiters = Limiters::default();
+
+        limiters.push_cap(
+            Arc::clone(&self.bandwith),
+            Arc::clone(&self.max_bandwith),
+        );
+        limiters.push_counter(stats.bytes());
+
+        limiters.push(Arc::clone(&self.rate_limiter));
+        limiters.push(Arc::clone(connection));
+        if let Some(user) = user {
+            limiters.push(Arc::clone(&user.rate_limiter));
+            limiters.push_quota(Arc::clone(&user.quota));
+        }
+
+        limiters
+    }
+
+    /// Checking has banthwith access more
+    fn has_bandwith(&self) -> bool {
+        self.max_bandwith.load(Ordering::Relaxed)
+            > self.bandwith.load(Ordering::Relaxed)
+    }
+
+    /// Same precedence as the SOCKS5 method selection,
+    /// offered credentials are checked first and NoAuth is
+    /// the fallback when the proxy allows it.
+    ///
+    /// `verified` holds the credentials last accepted on the
+    /// connection, repeating them skips the backends until
+    /// they are changed.
+    ///
+    /// `None` when refused, otherwise the authenticated user
+    /// if any
+    async fn authorize(
+        &self,
+        req: &HttpRequest,
+        verified: &mut Option<Verified>,
+    ) -> Option<Option<User>> {
+        let methods = self.avaliable_auth_methods().await;
+
+        if methods.contains(&USERNAME_PASSWORD) {
+            if let Some((username, password)) =
+                req.basic_credentials()
+            {
+                let chain =
+                    Arc::clone(&self.authenticator.lock());
+                let digest = CredentialsDigest::new(
+                    &username, &password,
+                );
+
+                if let Some((by, seen, user)) = verified {
+                    if Arc::ptr_eq(by, &chain)
+                        && seen.matches(&digest)
+                    {
+                        return Some(Some(user.clone()));
+                    }
+                }
+
+                if let Some(user) =
+                    chain.login(&username, &password).await
+                {
+                    *verified =
+                        Some((chain, digest, user.clone()));
+                    return Some(Some(user));
+                }
+
+                error!(
+                    "USERNAME: {} - Not valid",
+                    username
+                );
+                return None;
+            }
+        }
+
+        methods.contains(&NO_AUTH).then_some(None)
+    }
+
+    /// Resolves a target and applies the block list, rules
+    /// and bandwidth limit, errors are the response to send.
+    /// Blocked addresses are left out of the result.
+    async fn check_target(
+        &self,
+        target: &Target,
+    ) -> Result<Vec<SocketAddr>, HttpResponse> {
+        let host = target.domain();
+        if let Some(host) = host {
+            let dst = Destination::host(host, target.port);
+            if !self.policy.allows(&dst).await {
+                error!("Denied host: {}", host);
+                return Err(HttpResponse::new(
+                    403,
+                    "Forbidden",
+                ));
+            }
+        }
+
+        let addrs = match self
+            .resolver
+            .resolve(&target.host, target.port)
+            .await
+        {
+            Ok(addrs) => addrs,
+            Err(_e) => {
+                error!("{}", _e);
+                return Err(HttpResponse::new(
+                    502,
+                    "Bad Gateway",
+                ));
+            }
+        };
+
+        let mut allowed = Vec::with_capacity(addrs.len());
+        for addrs in addrs {
+            let dst = Destination::resolved(host, &addrs);
+            if self.policy.allows(&dst).await {
+                allowed.push(addrs);
+            }
+        }
+        if allowed.is_empty() {
+            return Err(HttpResponse::new(
+                403,
+                "Forbidden",
+            ));
+        }
+
+        if !self.has_bandwith() {
+            error!("Proxy has not have limit of bandwith");
+            return Err(HttpResponse::new(
+                503,
+                "Service Unavailable",
+            ));
+        }
+
+        Ok(allowed)
+    }
+
+    /// Connects to checked addresses of a target
+    async fn connect(
+        &self,
+        addrs: &[SocketAddr],
+    ) -> io::Result<TcpStream> {
+        let timeout = self.timeouts.lock().connect();
+        time::timeout(
+            timeout,
+            connect_happy_eyeballs(
+                addrs,
+                &self.connect_options(),
+            ),
+        )
+        .await
+        .unwrap_or_else(|_| Err(timed_out("connect")))
+    }
+}
+
+/// Serves one client connection.
+///
+/// `CONNECT` requests switch the connection into a raw tunnel, any other
+/// request must carry an absolute `http://` URI and is forwarded to its
+/// origin. Plain requests are served in a loop so the client connection and
+/// the upstream connection are both kept alive between requests.
+async fn handle_conn(
+    proxy: Arc<HttpProxy>,
+    socket: TcpStream,
+    stats: Arc<SessionStats>,
+) -> ProxyResult<()> {
+    let mut client = BufReader::new(socket);
+    let mut upstream: Option<Upstream> = None;
+    let connection_limiter = proxy.connection_limiter();
+    let mut verified = None;
+    let mut kept_alive = false;
+
+    loop {
+        let timeouts = *proxy.timeouts.lock();
+
+        // The next request on a kept alive connection has
+        // to start within the idle timeout
+        if let (true, Some(idle)) =
+            (kept_alive, timeouts.idle())
+        {
+            time::timeout(idle, client.fill_buf())
+                .await
+                .map_err(|_| timed_out("idle"))??;
+        }
+        kept_alive = true;
+
+        let Some(head) = time::timeout(
+            timeouts.handshake(),
+            read_head(&mut client),
+        )
+        .await
+        .map_err(|_| timed_out("handshake"))??
+        else {
+            return Ok(());
+        };
+
+        let req = match HttpRequest::from_bytes(&head) {
+            Ok(req) => req,
+            Err(_e) => {
+                send_error(
+                    &mut client,
+                    HttpResponse::new(400, "Bad Request"),
+                )
+                .await;
+                return Err(ProxyError::ParseError(_e));
+            }
+        };
+
+        let Some(user) =
+            proxy.authorize(&req, &mut verified).await
+        else {
+            let mut resp = HttpResponse::new(
+                407,
+                "Proxy Authentication Required",
+            );
+            resp.headers.insert(
+                "Proxy-Authenticate",
+                format!("Basic realm=\"{}\"", PROXY_REALM),
+            );
+            send_error(&mut client, resp).await;
+            return Ok(());
+        };
+
+        if let Some(user) = &user {
+            stats.set_user(&user.user_name);
+        }
+
+        let limiters = proxy.limiters(
+            &connection_limiter,
+            user.as_ref(),
+            &stats,
+        );
+
+        if limiters.quota_exceeded() {
+            send_error(
+                &mut client,
+                HttpResponse::new(429, "Too Many Requests"),
+            )
+            .await;
+            return Ok(());
+        }
+
+        if req.is_connect() {
+            return handle_connect(
+                &proxy, client, req, &limiters, &timeouts,
+            )
+            .await;
+        }
+
+        let keep_alive = forward_request(
+            &proxy,
+            &mut client,
+            &mut upstream,
+            req,
+            &limiters,
+        )
+        .await?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Opens a tunnel for `CONNECT host:port`, used for HTTPS
+async fn handle_connect(
+    proxy: &HttpProxy,
+    mut client: BufReader<TcpStream>,
+    req: HttpRequest,
+    limiters: &Limiters,
+    timeouts: &Timeouts,
+) -> ProxyResult<()> {
+    let target = match Target::from_authority(&req.uri, 443)
+    {
+        Ok(target) => target,
+        Err(_e) => {
+            send_error(
+                &mut client,
+                HttpResponse::new(400, "Bad Request"),
+            )
+            .await;
+            return Err(ProxyError::ParseError(_e));
+        }
+    };
+
+    let addrs = match proxy.check_target(&target).await {
+        Ok(addrs) => addrs,
+        Err(resp) => {
+            send_error(&mut client, resp).await;
+            return Ok(());
+        }
+    };
+
+    let Ok(remote_socket) = proxy.connect(&addrs).await
+    else {
+        send_error(
+            &mut client,
+            HttpResponse::new(502, "Bad Gateway"),
+        )
+        .await;
+        return Ok(());
+    };
+
+    let resp =
+        HttpResponse::new(200, "Connection Established");
+    client.write_all(&resp.to_bytes()).await?;
+
+    // Bytes the client pipelined after the request head are
+    // still in the buffer and are relayed first
+    if let Err(_e) = copy_bidirectional_timeout(
+        client,
+        remote_socket,
+        limiters,
+        timeouts,
+    )
+    .await
+    {
+        info!("Relay closed: {}", _e);
+    }
+
+    Ok(())
+}
+
+/// Forwards one absolute-URI request and its response.
+///
+/// Returns whether the client connection can serve another request.
+async fn forward_request(
+    proxy: &HttpProxy,
+    client: &mut BufReader<TcpStream>,
+    upstream: &mut Option<Upstream>,
+    mut req: HttpRequest,
+    limiters: &Limiters,
+) -> ProxyResult<bool> {
+    let (target, path) = match req.absolute_target() {
+        Ok(parsed) => parsed,
+        Err(_e) => {
+            send_error(
+                client,
+                HttpResponse::new(400, "Bad Request"),
+            )
+            .await;
+            return Err(ProxyError::ParseError(_e));
+        }
+    };
+
+    let req_body = match req.body_length() {
+        Ok(length) => length,
+        Err(_e) => {
+            send_error(
+                client,
+                HttpResponse::new(400, "Bad Request"),
+            )
+            .await;
+            return Err(ProxyError::ParseError(_e));
+        }
+    };
+
+    let authority = target.authority();
+
+    // Every request is checked, the policy may have changed
+    // since the upstream connection was opened
+    let addrs = match proxy.check_target(&target).await {
+        Ok(addrs) => addrs,
+        Err(resp) => {
+            send_error(client, resp).await;
+            return Ok(false);
+        }
+    };
+
+    // Reuse the upstream connection only for the same origin
+    // at an address that is still allowed
+    let mut remote = match upstream.take() {
+        Some((key, remote))
+            if key == authority
+                && remote
+                    .get_ref()
+                    .peer_addr()
+                    .is_ok_and(|peer| {
+                        addrs.contains(&peer)
+                    }) =>
+        {
+            remote
+        }
+        _ => match proxy.connect(&addrs).await {
+            Ok(socket) => BufReader::new(socket),
+            Err(_e) => {
+                send_error(
+                    client,
+                    HttpResponse::new(502, "Bad Gateway"),
+                )
+                .await;
+                return Ok(false);
+            }
+        },
+    };
+
+    let client_keep_alive = req.keep_alive();
+    let head_request =
+        req.method.eq_ignore_ascii_case("HEAD");
+
+    req.uri = path;
+    req.headers.strip_hop_by_hop();
+    req.headers.strip_ignored_length();
+    req.headers.insert(
+        "Host",
+        match target.port {
+            80 => target.host.clone(),
+            _ => authority.clone(),
+        },
+    );
+
+    let req_head = req.to_bytes();
+    limiters.consume(req_head.len() as u64).await;
+    remote.get_mut().write_all(&req_head).await?;
+    if let Err(_e) = copy_body(
+        client,
+        remote.get_mut(),
+        &req_body,
+        limiters,
+    )
+    .await
+    {
+        if let ProxyError::ParseError(_) = _e {
+            send_error(
+                client,
+                HttpResponse::new(400, "Bad Request"),
+            )
+            .await;
+        }
+        return Err(_e);
+    }
+
+    // Skip interim responses such as `100 Continue`
+    let mut resp = loop {
+        let Some(head) = read_head(&mut remote).await?
+        else {
+            send_error(
+                client,
+                HttpResponse::new(502, "Bad Gateway"),
+            )
+            .await;
+            return Ok(false);
+        };
+
+        let resp = match HttpResponse::from_bytes(&head) {
+            Ok(resp) => resp,
+            Err(_e) => {
+                send_error(
+                    client,
+                    HttpResponse::new(502, "Bad Gateway"),
+                )
+                .await;
+                return Err(ProxyError::ParseError(_e));
+            }
+        };
+
+        if !resp.is_informational() {
+            break resp;
+        }
+
+        limiters.consume(head.len() as u64).await;
+        client.get_mut().write_all(&head).await?;
+    };
+
+    let resp_body = match resp.body_length(head_request) {
+        Ok(length) => length,
+        Err(_e) => {
+            send_error(
+                client,
+                HttpResponse::new(502, "Bad Gateway"),
+            )
+            .await;
+            return Err(ProxyError::ParseError(_e));
+        }
+    };
+
+    let delimited = resp_body != BodyLength::UntilClose;
+    let upstream_keep_alive =
+        resp.keep_alive() && delimited;
+    let client_keep_alive = client_keep_alive && delimited;
+
+    // Proxies answer with their own protocol version
+    resp.version = "HTTP/1.1".to_string();
+    resp.headers.strip_hop_by_hop();
+    resp.headers.strip_ignored_length();
+    if !client_keep_alive {
+        resp.headers.insert("Connection", "close");
+    }
+
+    let resp_head = resp.to_bytes();
+    limiters.consume(resp_head.len() as u64).await;
+    client.get_mut().write_all(&resp_head).await?;
+    copy_body(
+        &mut remote,
+        client.get_mut(),
+        &resp_body,
+        limiters,
+    )
+    .await?;
+
+    if upstream_keep_alive {
+        *upstream = Some((authority, remote));
+    }
+
+    Ok(client_keep_alive)
+}
+
+/// Reads a message head up to and including the empty
+/// line, `None` when the peer closed before sending one
+async fn read_head<R>(
+    reader: &mut R,
+) -> ProxyResult<Option<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head = Vec::new();
+
+    loop {
+        let start = head.len();
+        let n = read_line(reader, &mut head).await?;
+
+        if n == 0 {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(ProxyError::ParseError(
+                "Connection closed inside message head"
+                    .to_string(),
+            ));
+        }
+
+        let line = &head[start..];
+        if line == b"\r\n" || line == b"\n" {
+            // Empty lines before the start line are ignored
+            if start == 0 {
+                head.clear();
+                continue;
+            }
+            return Ok(Some(head));
+        }
+
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(ProxyError::ParseError(
+                "Message head too large".to_string(),
+            ));
+        }
+    }
+}
+
+/// Reads a single line, bounded by `MAX_HEAD_SIZE`
+async fn read_line<R>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> io::Result<usize>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut limited =
+        (&mut *reader).take(MAX_HEAD_SIZE as u64);
+    limited.read_until(b'\n', buf).await
+}
+
+/// Size of a chunk from its size line, bounded by
+/// `MAX_CHUNK_SIZE` and `MAX_CHUNK_EXT_SIZE`
+fn chunk_size(line: &[u8]) -> ProxyResult<u64> {
+    let invalid = |reason: &str| {
+        ProxyError::ParseError(reason.to_string())
+    };
+
+    let line = std::str::from_utf8(line)
+        .map_err(|_| invalid("Invalid chunk size"))?;
+    let (size, ext) =
+        line.split_once(';').unwrap_or((line, ""));
+
+    if ext.len() > MAX_CHUNK_EXT_SIZE {
+        return Err(invalid("Chunk extension too large"));
+    }
+
+    let size = u64::from_str_radix(size.trim(), 16)
+        .map_err(|_| invalid("Invalid chunk size"))?;
+    if size > MAX_CHUNK_SIZE {
+        return Err(invalid("Chunk too large"));
+    }
+
+    Ok(size)
+}
+
+/// Relays a message body without changing its framing,
+/// returning the number of bytes written
+async fn copy_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    length: &BodyLength,
+    limiters: &Limiters,
+) -> ProxyResult<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match length {
+        BodyLength::Empty => Ok(0),
+        BodyLength::Fixed(len) => {
+            let copied = copy_limited(
+                &mut (&mut *reader).take(*len),
+                writer,
+                limiters,
+            )
+            .await?;
+            if copied < *len {
+                return Err(ProxyError::IoError(
+                    io::ErrorKind::UnexpectedEof.into(),
+                ));
+            }
+            Ok(copied)
+        }
+        BodyLength::UntilClose => {
+            Ok(copy_limited(reader, writer, limiters)
+                .await?)
+        }
+        BodyLength::Chunked => {
+            let mut copied = 0;
+
+            loop {
+                let mut line = Vec::new();
+                if read_line(reader, &mut line).await? == 0
+                {
+                    return Err(ProxyError::IoError(
+                        io::ErrorKind::UnexpectedEof.into(),
+                    ));
+                }
+
+                let size = chunk_size(&line)?;
+                // Chunk data followed by its CRLF
+                let framed = size
+                    .checked_add(2)
+                    .ok_or_else(|| {
+                        ProxyError::ParseError(
+                            "Invalid chunk size"
+                                .to_string(),
+                        )
+                    })?;
+
+                limiters.consume(line.len() as u64).await;
+                writer.write_all(&line).await?;
+                copied += line.len() as u64;
+
+                if size == 0 {
+                    break;
+                }
+
+                let chunk = copy_limited(
+                    &mut (&mut *reader).take(framed),
+                    writer,
+                    limiters,
+                )
+                .await?;
+                if chunk < framed {
+                    return Err(ProxyError::IoError(
+                        io::ErrorKind::UnexpectedEof.into(),
+                    ));
+                }
+                copied += chunk;
+            }
+
+            // Trailer section ends with an empty line
+            loop {
+                let mut line = Vec::new();
+                let n =
+                    read_line(reader, &mut line).await?;
+                limiters.consume(n as u64).await;
+                writer.write_all(&line).await?;
+                copied += n as u64;
+
+                if n == 0
+                    || line == b"\r\n"
+                    || line == b"\n"
+                {
+                    break;
+                }
+            }
+
+            Ok(copied)
+        }
+    }
+}
+
+/// Sends a bodiless error response and closes the client
+async fn send_error(
+    client: &mut BufReader<TcpStream>,
+    mut resp: HttpResponse,
+) {
+    resp.headers.insert("Content-Length", "0");
+    resp.headers.insert("Connection", "close");
+
+    if let Err(_e) =
+        client.write_all(&resp.to_bytes()).await
+    {
+        error!("Socket response writing error: {}", _e);
+        return;
+    }
+
+    if let Err(_e) = client.shutdown().await {
+        error!("Error while shutdown socket: {}", _e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn copy_chunked(
+        body: &[u8],
+    ) -> (ProxyResult<u64>, Vec<u8>) {
+        let mut reader = BufReader::new(body);
+        let mut written = Vec::new();
+        let copied = copy_body(
+            &mut reader,
+            &mut written,
+            &BodyLength::Chunked,
+            &Limiters::default(),
+        )
+        .await;
+        (copied, written)
+    }
+
+    #[tokio::test]
+    async fn copies_chunked_body() {
+        let body = b"5;name=value\r\nhello\r\n0\r\n\r\n";
+        let (copied, written) = copy_chunked(body).await;
+
+        assert_eq!(copied.unwrap(), body.len() as u64);
+        assert_eq!(written, body);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_chunk_header() {
+        for body in [
+            &b"ffffffffffffffff\r\n"[..],
+            &b"fffffffffffffffff\r\n"[..],
+            &b"40000001\r\n"[..],
+        ] {
+            let (copied, written) =
+                copy_chunked(body).await;
+
+            assert!(matches!(
+                copied,
+                Err(ProxyError::ParseError(_))
+            ));
+            // Nothing is relayed for a rejected size line
+            assert!(written.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_long_chunk_extension() {
+        let mut body = b"5;".to_vec();
+        body.extend(vec![b'x'; MAX_CHUNK_EXT_SIZE + 1]);
+        body.extend(b"\r\nhello\r\n0\r\n\r\n");
+        let (copied, _) = copy_chunked(&body).await;
+
+        assert!(matches!(
+            copied,
+            Err(ProxyError::ParseError(_))
+        ));
     }
 }
@@ -0,0 +1,645 @@
+use std::net::IpAddr;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use super::constant::HOP_BY_HOP_HEADERS;
+
+/// How the body following a message head is delimited
+#[derive(Debug, PartialEq, Eq)]
+pub enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+    UntilClose,
+}
+
+/// Header list that keeps the original order and casing
+#[derive(Debug, Clone, Default)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Checks a comma separated header for a token,
+    /// e.g. `Connection: keep-alive, Upgrade`
+    pub fn has_token(
+        &self,
+        name: &str,
+        token: &str,
+    ) -> bool {
+        self.tokens(name)
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(token))
+    }
+
+    pub fn insert(
+        &mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) {
+        let name = name.into();
+        self.remove(&name);
+        self.0.push((name, value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0
+            .retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// Removes headers that only apply to a single
+    /// connection, including the ones listed in `Connection`
+    pub fn strip_hop_by_hop(&mut self) {
+        let listed: Vec<String> = self
+            .0
+            .iter()
+            .filter(|(n, _)| {
+                n.eq_ignore_ascii_case("connection")
+            })
+            .flat_map(|(_, v)| v.split(','))
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+
+        for name in listed {
+            self.remove(&name);
+        }
+
+        for name in HOP_BY_HOP_HEADERS {
+            self.remove(name);
+        }
+    }
+
+    fn parse(lines: &[&str]) -> Result<Self, String> {
+        let mut headers = Vec::with_capacity(lines.len());
+
+        for line in lines {
+            let Some((name, value)) = line.split_once(':')
+            else {
+                return Err(format!(
+                    "Malformed header line: {}",
+                    line
+                ));
+            };
+
+            if name.is_empty() || name.ends_with(' ') {
+                return Err(format!(
+                    "Malformed header name: {}",
+                    name
+                ));
+            }
+
+            headers.push((
+                name.to_string(),
+                value.trim().to_string(),
+            ));
+        }
+
+        Ok(Self(headers))
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        for (name, value) in &self.0 {
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(b": ");
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.extend_from_slice(b"\r\n");
+        }
+        bytes.extend_from_slice(b"\r\n");
+    }
+
+    /// Values of a comma separated header over all of its
+    /// lines
+    fn tokens(&self, name: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .collect()
+    }
+
+    /// `Content-Length` next to `Transfer-Encoding` is
+    /// ignored, it must not reach the peer either
+    pub fn strip_ignored_length(&mut self) {
+        if self.get("transfer-encoding").is_some() {
+            self.remove("content-length");
+        }
+    }
+
+    /// Framing of the body, RFC 9112 section 6.3. A
+    /// `Transfer-Encoding` not ending in chunked is read
+    /// until close. Repeated `Content-Length` values have to
+    /// agree, anything else could be framed differently by
+    /// the peer.
+    fn body_length(&self) -> Result<BodyLength, String> {
+        let codings = self.tokens("transfer-encoding");
+        if let Some(last) = codings.last() {
+            if last.eq_ignore_ascii_case("chunked") {
+                return Ok(BodyLength::Chunked);
+            }
+            return Ok(BodyLength::UntilClose);
+        }
+
+        let lengths = self.tokens("content-length");
+        let Some(first) = lengths.first() else {
+            return Ok(BodyLength::UntilClose);
+        };
+
+        if lengths.iter().any(|len| len != first) {
+            return Err(format!(
+                "Conflicting Content-Length: {}",
+                lengths.join(", ")
+            ));
+        }
+
+        // `u64::from_str` takes a leading `+`
+        if !first.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!(
+                "Invalid Content-Length: {}",
+                first
+            ));
+        }
+
+        first.parse::<u64>().map(BodyLength::Fixed).map_err(
+            |_| {
+                format!("Invalid Content-Length: {}", first)
+            },
+        )
+    }
+}
+
+/// Splits a message head into its start line and header
+/// lines, the trailing empty line is optional
+fn split_head(head: &[u8]) -> Result<Vec<&str>, String> {
+    let head = std::str::from_utf8(head).map_err(|_| {
+        "Message head is not UTF-8".to_string()
+    })?;
+
+    let lines: Vec<&str> = head
+        .split("\r\n")
+        .flat_map(|l| l.split('\n'))
+        .take_while(|l| !l.is_empty())
+        .collect();
+
+    if lines.is_empty() {
+        return Err("Empty message head".to_string());
+    }
+
+    Ok(lines)
+}
+
+/// Destination of a request, either the authority of a
+/// `CONNECT` or the host part of an absolute URI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    /// Parses `host:port`, `[v6]:port` or a bare host
+    pub fn from_authority(
+        authority: &str,
+        default_port: u16,
+    ) -> Result<Self, String> {
+        let invalid =
+            || format!("Invalid authority: {}", authority);
+
+        let (host, port) = if let Some(rest) =
+            authority.strip_prefix('[')
+        {
+            let (host, rest) =
+                rest.split_once(']').ok_or_else(invalid)?;
+            let port = match rest.strip_prefix(':') {
+                Some(port) => Some(port),
+                None if rest.is_empty() => None,
+                None => return Err(invalid()),
+            };
+            (host, port)
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        let port = match port {
+            Some(port) => {
+                port.parse().map_err(|_| invalid())?
+            }
+            None => default_port,
+        };
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// Host when it is a name rather than an address
+    pub fn domain(&self) -> Option<&str> {
+        self.host
+            .parse::<IpAddr>()
+            .is_err()
+            .then_some(self.host.as_str())
+    }
+
+    /// Host and port in the form used by `Host` headers
+    /// and connection reuse
+    pub fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// request-line *( header-field CRLF ) CRLF
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub headers: Headers,
+}
+
+impl HttpRequest {
+    pub fn from_bytes(head: &[u8]) -> Result<Self, String> {
+        let lines = split_head(head)?;
+
+        let mut parts = lines[0].split(' ');
+        let (Some(method), Some(uri), Some(version), None) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return Err(format!(
+                "Malformed request line: {}",
+                lines[0]
+            ));
+        };
+
+        if !version.starts_with("HTTP/1.") {
+            return Err(format!(
+                "Unsupported version: {}",
+                version
+            ));
+        }
+
+        Ok(Self {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            version: version.to_string(),
+            headers: Headers::parse(&lines[1..])?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(
+            format!(
+                "{} {} {}\r\n",
+                self.method, self.uri, self.version
+            )
+            .as_bytes(),
+        );
+        self.headers.write_to(&mut bytes);
+        bytes
+    }
+
+    pub fn is_connect(&self) -> bool {
+        self.method.eq_ignore_ascii_case("CONNECT")
+    }
+
+    /// Whether the client connection may be reused after
+    /// this request
+    pub fn keep_alive(&self) -> bool {
+        let close =
+            self.headers.has_token("connection", "close")
+                || self
+                    .headers
+                    .has_token("proxy-connection", "close");
+
+        self.version == "HTTP/1.1" && !close
+    }
+
+    /// Requests only carry a body when they announce one,
+    /// a request body can not be read until close
+    pub fn body_length(
+        &self,
+    ) -> Result<BodyLength, String> {
+        match self.headers.body_length()? {
+            BodyLength::UntilClose
+                if self
+                    .headers
+                    .get("transfer-encoding")
+                    .is_some() =>
+            {
+                Err("Transfer-Encoding does not end in chunked"
+                    .to_string())
+            }
+            BodyLength::UntilClose => Ok(BodyLength::Empty),
+            length => Ok(length),
+        }
+    }
+
+    /// Username and password of a `Basic` scheme
+    /// `Proxy-Authorization` header
+    pub fn basic_credentials(
+        &self,
+    ) -> Option<(String, String)> {
+        let value =
+            self.headers.get("proxy-authorization")?;
+        let (scheme, encoded) = value.split_once(' ')?;
+
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+
+        let decoded =
+            STANDARD.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) =
+            decoded.split_once(':')?;
+
+        Some((username.to_string(), password.to_string()))
+    }
+
+    /// Splits an absolute URI into its target and the
+    /// origin-form path sent upstream
+    pub fn absolute_target(
+        &self,
+    ) -> Result<(Target, String), String> {
+        let rest = self
+            .uri
+            .strip_prefix("http://")
+            .or_else(|| self.uri.strip_prefix("HTTP://"))
+            .ok_or_else(|| {
+                format!(
+                    "Not an absolute http URI: {}",
+                    self.uri
+                )
+            })?;
+
+        let (authority, path) = match rest.find(['/', '?'])
+        {
+            Some(idx) if rest[idx..].starts_with('?') => {
+                (&rest[..idx], format!("/{}", &rest[idx..]))
+            }
+            Some(idx) => {
+                (&rest[..idx], rest[idx..].to_string())
+            }
+            None => (rest, "/".to_string()),
+        };
+
+        // Credentials in the URI are never forwarded
+        let authority = authority
+            .rsplit_once('@')
+            .map(|(_, host)| host)
+            .unwrap_or(authority);
+
+        Ok((Target::from_authority(authority, 80)?, path))
+    }
+}
+
+/// status-line *( header-field CRLF ) CRLF
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, reason: &str) -> Self {
+        Self {
+            version: "HTTP/1.1".to_string(),
+            status,
+            reason: reason.to_string(),
+            headers: Headers::default(),
+        }
+    }
+
+    pub fn from_bytes(head: &[u8]) -> Result<Self, String> {
+        let lines = split_head(head)?;
+
+        let mut parts = lines[0].splitn(3, ' ');
+        let (Some(version), Some(status)) =
+            (parts.next(), parts.next())
+        else {
+            return Err(format!(
+                "Malformed status line: {}",
+                lines[0]
+            ));
+        };
+
+        if !version.starts_with("HTTP/1.") {
+            return Err(format!(
+                "Unsupported version: {}",
+                version
+            ));
+        }
+
+        let status = status.parse().map_err(|_| {
+            format!("Invalid status code: {}", status)
+        })?;
+
+        Ok(Self {
+            version: version.to_string(),
+            status,
+            reason: parts.next().unwrap_or("").to_string(),
+            headers: Headers::parse(&lines[1..])?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(
+            format!(
+                "{} {} {}\r\n",
+                self.version, self.status, self.reason
+            )
+            .as_bytes(),
+        );
+        self.headers.write_to(&mut bytes);
+        bytes
+    }
+
+    /// Interim 1xx responses are followed by the final one
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.status)
+            && self.status != 101
+    }
+
+    /// Whether the upstream connection may be reused after
+    /// this response
+    pub fn keep_alive(&self) -> bool {
+        let close =
+            self.headers.has_token("connection", "close");
+
+        self.version == "HTTP/1.1" && !close
+    }
+
+    /// RFC 9112 section 6.3, `head_request` marks responses
+    /// to `HEAD` which never carry a body
+    pub fn body_length(
+        &self,
+        head_request: bool,
+    ) -> Result<BodyLength, String> {
+        if head_request
+            || (100..200).contains(&self.status)
+            || self.status == 204
+            || self.status == 304
+        {
+            return Ok(BodyLength::Empty);
+        }
+
+        self.headers.body_length()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &str) -> HttpRequest {
+        let head = format!(
+            "POST http://example.com/ HTTP/1.1\r\n{}\r\n",
+            headers
+        );
+        HttpRequest::from_bytes(head.as_bytes()).unwrap()
+    }
+
+    fn response(headers: &str) -> HttpResponse {
+        let head =
+            format!("HTTP/1.1 200 OK\r\n{}\r\n", headers);
+        HttpResponse::from_bytes(head.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn content_length() {
+        let req = request("Content-Length: 5\r\n");
+        assert_eq!(
+            req.body_length(),
+            Ok(BodyLength::Fixed(5))
+        );
+
+        let req = request("");
+        assert_eq!(
+            req.body_length(),
+            Ok(BodyLength::Empty)
+        );
+    }
+
+    #[test]
+    fn repeated_content_length_must_agree() {
+        let req = request(
+            "Content-Length: 5\r\nContent-Length: 5\r\n",
+        );
+        assert_eq!(
+            req.body_length(),
+            Ok(BodyLength::Fixed(5))
+        );
+
+        let req = request("Content-Length: 5, 5\r\n");
+        assert_eq!(
+            req.body_length(),
+            Ok(BodyLength::Fixed(5))
+        );
+
+        let req = request(
+            "Content-Length: 5\r\nContent-Length: 6\r\n",
+        );
+        assert!(req.body_length().is_err());
+
+        let req = request("Content-Length: 5, 6\r\n");
+        assert!(req.body_length().is_err());
+    }
+
+    #[test]
+    fn invalid_content_length() {
+        for len in [
+            "abc", "+5", "-1", "5 5", "0x10",
+        ] {
+            let req = request(&format!(
+                "Content-Length: {}\r\n",
+                len
+            ));
+            assert!(req.body_length().is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn chunked_wins_over_content_length() {
+        let mut req = request(
+            "Content-Length: 5\r\nTransfer-Encoding: chunked\r\n",
+        );
+        assert_eq!(
+            req.body_length(),
+            Ok(BodyLength::Chunked)
+        );
+
+        req.headers.strip_ignored_length();
+        assert_eq!(req.headers.get("content-length"), None);
+        assert_eq!(
+            req.headers.get("transfer-encoding"),
+            Some("chunked")
+        );
+    }
+
+    #[test]
+    fn chunked_must_be_last() {
+        let req =
+            request("Transfer-Encoding: gzip, chunked\r\n");
+        assert_eq!(
+            req.body_length(),
+            Ok(BodyLength::Chunked)
+        );
+
+        let req = request(
+            "Transfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n",
+        );
+        assert!(req.body_length().is_err());
+
+        let req = request(
+            "Transfer-Encoding: gzip\r\nContent-Length: 5\r\n",
+        );
+        assert!(req.body_length().is_err());
+
+        // Responses are read until close instead
+        let resp = response("Transfer-Encoding: gzip\r\n");
+        assert_eq!(
+            resp.body_length(false),
+            Ok(BodyLength::UntilClose)
+        );
+    }
+
+    #[test]
+    fn length_kept_without_transfer_encoding() {
+        let mut resp = response("Content-Length: 5\r\n");
+        resp.headers.strip_ignored_length();
+
+        assert_eq!(
+            resp.headers.get("content-length"),
+            Some("5")
+        );
+        assert_eq!(
+            resp.body_length(false),
+            Ok(BodyLength::Fixed(5))
+        );
+    }
+}
@@ -1,4 +1,6 @@
+pub mod auth;
 mod common;
+pub mod dns;
 pub mod http;
 pub mod proxy_manager;
 pub mod socks5;
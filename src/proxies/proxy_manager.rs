@@ -1,36 +1,99 @@
 use async_trait::async_trait;
 use dashmap::DashMap;
-use tokio::sync::{oneshot, Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::{runtime::Handle, time};
 use uuid::Uuid;
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::HashSet,
     fmt::Debug,
-    io,
-    net::IpAddr,
-    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use crate::models::users::{User, UserId};
+use crate::models::users::{User, UserId, Users};
 
 use super::{
-    http::HttpProxy, socks5::Socks5Proxy,
-    utils::io::is_port_in_use,
+    auth::AuthBackend,
+    dns::{DnsConfig, DnsStats, Resolver},
+    http::HttpProxy,
+    socks5::Socks5Proxy,
+    utils::{
+        bind::BindOptions,
+        client_acl::ClientAcl,
+        conn_limit::ConnectionLimits,
+        connect::ConnectOptions,
+        io::is_port_in_use,
+        policy::{
+            EffectivePolicy, GlobalPolicy, Inheritance,
+            ProxyPolicy,
+        },
+        quota::QuotaLimit,
+        rate_limit::RateLimits,
+        rules::{Rule, RuleSet},
+        sessions::{SessionInfo, StopSessions},
+        ssrf::SsrfProtection,
+        timeout::Timeouts,
+    },
 };
 
-use tracing::{error, info, warn};
+use tracing::{error, info};
 
+// How often shutdown checks for remaining sessions
+const DRAIN_POLL_INTERVAL: Duration =
+    Duration::from_millis(100);
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
 pub enum ProxyType {
     Http,
     Socks5,
 }
 
-type StoredProxy = (Arc<Box<dyn ProxyEx>>, u16);
+type StoredProxy = (Arc<Box<dyn ProxyEx>>, SocketAddr);
+
+/// Error for a setting a proxy does not have
+pub fn unsupported(feature: &str) -> String {
+    format!("This proxy does not support {}", feature)
+}
 
 #[async_trait]
 pub trait ProxyEx: Send + Sync + Debug {
     // Start proxy
     async fn start(&self) -> Result<(), String>;
+    // Stop accepting connections, established sessions are
+    // kept, drained or aborted
+    async fn stop(&self, sessions: StopSessions);
+    // Rebind the listener, established sessions keep running
+    async fn restart(&self) -> Result<(), String> {
+        self.stop(StopSessions::Keep).await;
+        self.start().await
+    }
+    fn is_running(&self) -> bool;
+    fn active_sessions(&self) -> usize;
+    fn sessions(&self) -> Vec<SessionInfo>;
+    fn policy(&self) -> Arc<ProxyPolicy>;
+
+    fn auth_backends(&self) -> Vec<AuthBackend>;
+    // Opens every backend, the old chain is kept on error
+    fn set_auth_backends(
+        &self,
+        backends: Vec<AuthBackend>,
+    ) -> Result<(), String>;
+
+    fn proxy_type(&self) -> ProxyType;
 
     async fn avaliable_auth_methods(&self) -> HashSet<u8>;
     async fn set_avaliable_auth_method(
@@ -42,13 +105,78 @@ pub trait ProxyEx: Send + Sync + Debug {
         methods: Vec<u8>,
     );
 
-    async fn avaliable_users(&self) -> HashSet<User>;
-    async fn set_user(&self, user: User);
+    async fn avaliable_users(&self) -> Users;
+    // False when the proxy has a user with the same name
+    async fn set_user(&self, user: User) -> bool;
     async fn remove_user(&self, user_id: &str) -> bool;
 
     async fn set_max_bandwith(&self, max: u64);
+    fn max_bandwith(&self) -> u64;
     fn current_bandwith(&self) -> u64;
 
+    fn connect_options(&self) -> ConnectOptions;
+    fn set_connect_options(&self, options: ConnectOptions);
+
+    // Only proxies that serve BIND requests have them
+    fn bind_options(&self) -> Option<BindOptions> {
+        None
+    }
+    fn set_bind_options(
+        &self,
+        _options: BindOptions,
+    ) -> Result<(), String> {
+        Err(unsupported("BIND"))
+    }
+
+    // Only proxies that check clients on accept have one
+    fn client_acl(&self) -> Option<ClientAcl> {
+        None
+    }
+    fn set_client_acl(
+        &self,
+        _acl: ClientAcl,
+    ) -> Result<(), String> {
+        Err(unsupported("client access control"))
+    }
+
+    // Only SOCKS5 listeners can serve SOCKS4 clients too
+    fn socks4(&self) -> Option<bool> {
+        None
+    }
+    fn set_socks4(
+        &self,
+        _enabled: bool,
+    ) -> Result<(), String> {
+        Err(unsupported("SOCKS4"))
+    }
+
+    // Only proxies that apply connection limits have them
+    fn connection_limits(
+        &self,
+    ) -> Option<ConnectionLimits> {
+        None
+    }
+    fn set_connection_limits(
+        &self,
+        _limits: ConnectionLimits,
+    ) -> Result<(), String> {
+        Err(unsupported("connection limits"))
+    }
+
+    // Only proxies that apply timeouts have them
+    fn timeouts(&self) -> Option<Timeouts> {
+        None
+    }
+    fn set_timeouts(
+        &self,
+        _timeouts: Timeouts,
+    ) -> Result<(), String> {
+        Err(unsupported("timeouts"))
+    }
+
+    async fn set_rate_limits(&self, limits: RateLimits);
+    fn rate_limits(&self) -> RateLimits;
+
     async fn block_ip_address(&self, addrs: &IpAddr);
     async fn get_blocked_address(&self) -> HashSet<IpAddr>;
     async fn remove_blocked_address(&self, addrs: &IpAddr);
@@ -56,66 +184,205 @@ pub trait ProxyEx: Send + Sync + Debug {
     // Analistic
 }
 
+/// Snapshot of a running proxy
+#[derive(Debug, Clone, Serialize)]
+pub struct ProxyInfo {
+    pub id: String,
+    pub proxy_type: ProxyType,
+    pub host: IpAddr,
+    pub port: u16,
+    pub running: bool,
+    pub sessions: usize,
+    pub max_bandwith: u64,
+    pub bandwith: u64,
+}
+
 #[derive(Debug)]
 pub struct ProxyManager {
-    users: Arc<RwLock<HashSet<User>>>,
-    // (Proxy, port)
+    // Users and blocked addresses every proxy inherits
+    policy: Arc<GlobalPolicy>,
+    // Resolves domain targets of every proxy
+    resolver: Arc<Resolver>,
+    // (Proxy, listening address)
     //avaliable_proxies: Arc<RwLock<Vec<StoredProxy>>>,
     avaliable_proxies: Arc<DashMap<String, StoredProxy>>,
 
-    // Blocked Domain or ipAddrs
-    blocked_ippaddr: Arc<RwLock<HashSet<IpAddr>>>,
-
     // Analistic
     total_bytes_served: Arc<AtomicU64>,
+
+    // Runtime proxies are started on, management calls may
+    // come from other runtimes (e.g. admin api workers)
+    runtime: Handle,
 }
 
 impl ProxyManager {
+    /// Must be called inside the Tokio runtime proxies
+    /// should run on
     pub fn new() -> Self {
         ProxyManager {
-            users: Arc::new(RwLock::new(HashSet::new())),
+            policy: Arc::new(GlobalPolicy::default()),
+            resolver: Resolver::new(&DnsConfig::default()),
             avaliable_proxies: Arc::new(DashMap::new()),
-
-            blocked_ippaddr: Arc::new(RwLock::new(
-                HashSet::new(),
-            )),
             total_bytes_served: Arc::new(AtomicU64::new(0)),
+
+            runtime: Handle::current(),
         }
     }
 
     pub async fn add_proxy(
-        &mut self,
+        &self,
         proxy_type: ProxyType,
-        port: u16,
+        addrs: SocketAddr,
     ) -> Result<String, String> {
-        if is_port_in_use(port).await {
+        if is_port_in_use(addrs).await {
             return Err("Port in use!".to_string());
         }
 
         // Create the proxy instance.
         let proxy: Box<dyn ProxyEx> = match proxy_type {
             ProxyType::Socks5 => {
-                Box::new(Socks5Proxy::new(port))
-            }
-            //ProxyType::Http => Box::new(HttpProxy::new()),
-            _ => {
-                return Err(
-                    "Proxy impl not available".to_string()
-                )
+                Box::new(Socks5Proxy::new(
+                    addrs,
+                    Arc::clone(&self.policy),
+                    Arc::clone(&self.resolver),
+                ))
             }
+            ProxyType::Http => Box::new(HttpProxy::new(
+                addrs,
+                Arc::clone(&self.policy),
+                Arc::clone(&self.resolver),
+            )),
         };
+        // New proxies serve without a byte cap until one
+        // is set
+        proxy.set_max_bandwith(u64::MAX).await;
+        let proxy = Arc::new(proxy);
 
-        // Start proxy
-        let _ = proxy.start().await;
+        // Start proxy, the accept loop it spawns belongs to
+        // the manager runtime
+        let starting = Arc::clone(&proxy);
+        self.runtime
+            .spawn(async move { starting.start().await })
+            .await
+            .map_err(|_e| _e.to_string())??;
 
         let id = Self::create_proxy_id();
-        let proxy = Arc::new(proxy);
         self.avaliable_proxies
-            .insert(id.clone(), (proxy, port));
+            .insert(id.clone(), (proxy, addrs));
+
+        info!("Added proxy ID: {} on {}", id, addrs);
 
         Ok(id)
     }
 
+    /// Stop a proxy and forget it, frees its port
+    pub async fn remove_proxy(
+        &self,
+        proxy_id: &String,
+        sessions: StopSessions,
+    ) -> Option<()> {
+        let (_, (proxy, addrs)) =
+            self.avaliable_proxies.remove(proxy_id)?;
+
+        proxy.stop(sessions).await;
+
+        info!(
+            "Removed proxy ID: {} on {}",
+            proxy_id, addrs
+        );
+
+        Some(())
+    }
+
+    /// Stops every proxy and waits for established sessions
+    /// up to the deadline, the rest are aborted. Returns how
+    /// many sessions had to be aborted.
+    pub async fn shutdown(
+        &self,
+        deadline: Duration,
+    ) -> usize {
+        let proxies: Vec<Arc<Box<dyn ProxyEx>>> = self
+            .avaliable_proxies
+            .iter()
+            .map(|entry| Arc::clone(&entry.value().0))
+            .collect();
+
+        for proxy in &proxies {
+            proxy.stop(StopSessions::Keep).await;
+        }
+
+        let active = || {
+            proxies
+                .iter()
+                .map(|proxy| proxy.active_sessions())
+                .sum::<usize>()
+        };
+
+        info!(
+            "Waiting up to {:?} for {} sessions",
+            deadline,
+            active()
+        );
+
+        let _ = time::timeout(deadline, async {
+            while active() > 0 {
+                time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+        })
+        .await;
+
+        let aborted = active();
+        for proxy in &proxies {
+            proxy.stop(StopSessions::Abort).await;
+        }
+
+        aborted
+    }
+
+    /// Stop accepting connections, the proxy can be started
+    /// again later
+    pub async fn stop_proxy(
+        &self,
+        proxy_id: &String,
+        sessions: StopSessions,
+    ) -> Option<()> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        proxy.stop(sessions).await;
+
+        Some(())
+    }
+
+    pub async fn start_proxy(
+        &self,
+        proxy_id: &String,
+    ) -> Option<Result<(), String>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(
+            self.runtime
+                .spawn(async move { proxy.start().await })
+                .await
+                .map_err(|_e| _e.to_string())
+                .and_then(|started| started),
+        )
+    }
+
+    pub async fn restart_proxy(
+        &self,
+        proxy_id: &String,
+    ) -> Option<Result<(), String>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(
+            self.runtime
+                .spawn(async move { proxy.restart().await })
+                .await
+                .map_err(|_e| _e.to_string())
+                .and_then(|started| started),
+        )
+    }
+
     pub fn get_proxy(
         &self,
         id: &String,
@@ -123,14 +390,48 @@ impl ProxyManager {
         self.avaliable_proxies.get(id).map(|e| e.clone())
     }
 
+    /// Info of a single proxy
+    pub fn proxy_info(
+        &self,
+        proxy_id: &String,
+    ) -> Option<ProxyInfo> {
+        let (proxy, addrs) = self.find_proxy(proxy_id)?;
+
+        Some(Self::to_info(proxy_id, &proxy, addrs))
+    }
+
+    /// Active sessions of a proxy, oldest first
+    pub fn list_sessions(
+        &self,
+        proxy_id: &String,
+    ) -> Option<Vec<SessionInfo>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.sessions())
+    }
+
+    /// Info of every proxy, ordered by port
+    pub fn list_proxies(&self) -> Vec<ProxyInfo> {
+        let mut proxies: Vec<ProxyInfo> = self
+            .avaliable_proxies
+            .iter()
+            .map(|entry| {
+                let (proxy, addrs) = entry.value();
+                Self::to_info(entry.key(), proxy, *addrs)
+            })
+            .collect();
+
+        proxies.sort_by_key(|p| (p.port, p.host));
+        proxies
+    }
+
     pub async fn list_auth_methods(
         &self,
         proxy_id: &String,
     ) -> Option<HashSet<u8>> {
-        let stored_proxy = self.get_proxy(proxy_id)?;
+        let (proxy, _) = self.find_proxy(proxy_id)?;
 
         // Read the proxy and fetch its available authentication methods.
-        let proxy = stored_proxy.0;
         let methods = proxy.avaliable_auth_methods().await;
 
         Some(methods)
@@ -140,20 +441,8 @@ impl ProxyManager {
         &self,
         proxy_id: &String,
         method: u8,
-    ) {
-        // Get the proxy outside of any lock scope.
-        let entry = match self.get_proxy(proxy_id) {
-            Some(entry) => entry,
-            None => {
-                error!(
-                    "Proxy not found for ID: {}",
-                    proxy_id
-                );
-                return;
-            }
-        };
-
-        let (proxy, _port) = entry;
+    ) -> Option<()> {
+        let (proxy, _port) = self.find_proxy(proxy_id)?;
 
         proxy.set_avaliable_auth_method(vec![method]).await;
 
@@ -161,6 +450,8 @@ impl ProxyManager {
             "Added auth method {} for proxy ID: {}",
             method, proxy_id
         );
+
+        Some(())
     }
 
     /// Remove an authentication method from a specific proxy
@@ -168,224 +459,634 @@ impl ProxyManager {
         &self,
         proxy_id: &String,
         method: u8,
-    ) {
-        // Get the proxy outside of any lock scope.
-        let entry = match self.get_proxy(proxy_id) {
-            Some(entry) => entry,
-            None => {
-                error!(
-                    "Proxy not found for ID: {}",
-                    proxy_id
-                );
-                return;
-            }
-        };
-
-        let (proxy, _) = entry;
+    ) -> Option<()> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
 
         proxy
             .remove_avaliable_auth_method(vec![method])
             .await;
+
+        Some(())
     }
 
     /// List users, either globally or for a specific proxy
     pub async fn list_users(
         &self,
         proxy_id: Option<&String>,
-    ) -> Option<HashSet<User>> {
-        match proxy_id {
+    ) -> Option<Vec<User>> {
+        let users = match proxy_id {
             Some(id) => {
-                let entry = match self.get_proxy(id) {
-                    Some(entry) => entry,
-                    None => {
-                        error!(
-                            "Proxy not found for ID: {}",
-                            id
-                        );
-                        return None;
-                    }
-                };
-
-                let (proxy, _port) = entry;
-
-                let methods = proxy.avaliable_users().await;
-
-                Some(methods)
-            }
-            None => {
-                let users = self.users.read().await;
+                let (proxy, _port) = self.find_proxy(id)?;
 
-                Some(users.clone())
+                proxy.avaliable_users().await
             }
-        }
+            None => self.policy.users.read().await.clone(),
+        };
+
+        Some(users.into_values().collect())
     }
 
-    /// Register a user, either globally or for a specific proxy
+    /// Register a user, either globally or for a specific
+    /// proxy. User names are unique in each of them.
     pub async fn register_user(
         &self,
         proxy_id: Option<&String>,
         user: User,
-    ) {
-        match proxy_id {
+    ) -> Option<Result<(), String>> {
+        let user_name = user.user_name.clone();
+        let added = match proxy_id {
             Some(id) => {
-                let entry = match self.get_proxy(id) {
-                    Some(entry) => entry,
-                    None => {
-                        error!(
-                            "Proxy not found for ID: {}",
-                            id
-                        );
-                        return;
-                    }
-                };
-
-                let (proxy, _) = entry;
-
-                proxy.set_user(user).await;
+                let (proxy, _) = self.find_proxy(id)?;
+
+                proxy.set_user(user).await
             }
             None => {
-                let mut users = self.users.write().await;
-                users.insert(user);
+                let mut users =
+                    self.policy.users.write().await;
+                !users.contains_key(&user.user_name)
+                    && users
+                        .insert(
+                            user.user_name.clone(),
+                            user,
+                        )
+                        .is_none()
             }
+        };
+
+        if !added {
+            return Some(Err(format!(
+                "User already exists: {}",
+                user_name
+            )));
         }
+
+        Some(Ok(()))
     }
 
-    /// Remove a user, either globally or for a specific proxy
+    /// Remove a user, either globally or for a specific proxy.
+    /// `None` when the proxy is unknown, `Some(false)` when
+    /// the user is.
     pub async fn remove_user(
         &self,
         proxy_id: Option<&String>,
-        user: User,
-    ) {
+        user_id: &UserId,
+    ) -> Option<bool> {
         match proxy_id {
             Some(id) => {
-                let entry = match self.get_proxy(id) {
-                    Some(entry) => entry,
-                    None => {
-                        error!(
-                            "Proxy not found for ID: {}",
-                            id
-                        );
-                        return;
-                    }
-                };
-
-                let (proxy, _) = entry;
-
-                proxy
-                    .remove_user(&user.user_id.to_string())
-                    .await;
+                let (proxy, _) = self.find_proxy(id)?;
+
+                Some(
+                    proxy
+                        .remove_user(&user_id.to_string())
+                        .await,
+                )
             }
             None => {
-                let mut users = self.users.write().await;
-                users.remove(&user);
+                let mut users =
+                    self.policy.users.write().await;
+                let before = users.len();
+                users.retain(|_, user| {
+                    &user.user_id != user_id
+                });
+
+                Some(users.len() != before)
             }
         }
     }
 
+    /// Set the quota of a user, either globally or for a
+    /// specific proxy. Same results as `remove_user`.
+    pub async fn set_user_quota(
+        &self,
+        proxy_id: Option<&String>,
+        user_id: &UserId,
+        limit: Option<QuotaLimit>,
+    ) -> Option<bool> {
+        let user =
+            self.find_user(proxy_id, user_id).await?;
+
+        Some(
+            user.map(|u| u.quota.set_limit(limit))
+                .is_some(),
+        )
+    }
+
+    /// Reset the quota usage of a user, either globally or
+    /// for a specific proxy. Same results as `remove_user`.
+    pub async fn reset_user_quota(
+        &self,
+        proxy_id: Option<&String>,
+        user_id: &UserId,
+    ) -> Option<bool> {
+        let user =
+            self.find_user(proxy_id, user_id).await?;
+
+        Some(user.map(|u| u.quota.reset()).is_some())
+    }
+
+    async fn find_user(
+        &self,
+        proxy_id: Option<&String>,
+        user_id: &UserId,
+    ) -> Option<Option<User>> {
+        let users = self.list_users(proxy_id).await?;
+
+        Some(
+            users
+                .into_iter()
+                .find(|u| &u.user_id == user_id),
+        )
+    }
+
     /// Seting Max bandwith for single proxy
     pub async fn set_max_bandwith(
         &self,
         proxy_id: &String,
         max: u64,
-    ) {
-        let entry = match self.get_proxy(proxy_id) {
-            Some(entry) => entry,
-            None => {
-                error!(
-                    "Proxy not found for ID: {}",
-                    proxy_id
-                );
-                return;
-            }
-        };
-
-        let (proxy, _) = entry;
+    ) -> Option<()> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
 
         proxy.set_max_bandwith(max).await;
+
+        Some(())
     }
 
+    pub async fn set_rate_limits(
+        &self,
+        proxy_id: &String,
+        limits: RateLimits,
+    ) -> Option<()> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        proxy.set_rate_limits(limits).await;
 
-    /// Get usaged bandwith of a proxy
-    pub async fn get_bandwith(        &self,
-        proxy_id: &String) -> u64{
-            let entry = match self.get_proxy(proxy_id) {
-                Some(entry) => entry,
-                None => {
-                    error!(
-                        "Proxy not found for ID: {}",
-                        proxy_id
-                    );
-                    return 0;
-                }
-            };
-    
-            let (proxy, _) = entry;
-    
-            proxy.current_bandwith()
-        }
+        Some(())
+    }
+
+    pub fn get_rate_limits(
+        &self,
+        proxy_id: &String,
+    ) -> Option<RateLimits> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.rate_limits())
+    }
+
+    pub fn get_connect_options(
+        &self,
+        proxy_id: &String,
+    ) -> Option<ConnectOptions> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.connect_options())
+    }
 
+    pub fn set_connect_options(
+        &self,
+        proxy_id: &String,
+        options: ConnectOptions,
+    ) -> Option<()> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
 
-    async fn block_ip_address(&self, addrs: &IpAddr,proxy_id: &String){
-        let entry = match self.get_proxy(proxy_id) {
-            Some(entry) => entry,
+        proxy.set_connect_options(options);
+
+        Some(())
+    }
+
+    /// `Some(None)` when the proxy does not serve BIND
+    pub fn get_bind_options(
+        &self,
+        proxy_id: &String,
+    ) -> Option<Option<BindOptions>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.bind_options())
+    }
+
+    pub fn set_bind_options(
+        &self,
+        proxy_id: &String,
+        options: BindOptions,
+    ) -> Option<Result<(), String>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.set_bind_options(options))
+    }
+
+    /// `Some(None)` when the proxy does not check clients
+    pub fn get_client_acl(
+        &self,
+        proxy_id: &String,
+    ) -> Option<Option<ClientAcl>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.client_acl())
+    }
+
+    pub fn set_client_acl(
+        &self,
+        proxy_id: &String,
+        acl: ClientAcl,
+    ) -> Option<Result<(), String>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.set_client_acl(acl))
+    }
+
+    /// `Some(None)` when the proxy can not serve SOCKS4
+    pub fn get_socks4(
+        &self,
+        proxy_id: &String,
+    ) -> Option<Option<bool>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.socks4())
+    }
+
+    pub fn set_socks4(
+        &self,
+        proxy_id: &String,
+        enabled: bool,
+    ) -> Option<Result<(), String>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.set_socks4(enabled))
+    }
+
+    /// `Some(None)` when the proxy has no connection limits
+    pub fn get_connection_limits(
+        &self,
+        proxy_id: &String,
+    ) -> Option<Option<ConnectionLimits>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.connection_limits())
+    }
+
+    pub fn set_connection_limits(
+        &self,
+        proxy_id: &String,
+        limits: ConnectionLimits,
+    ) -> Option<Result<(), String>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.set_connection_limits(limits))
+    }
+
+    /// `Some(None)` when the proxy has no timeouts
+    pub fn get_timeouts(
+        &self,
+        proxy_id: &String,
+    ) -> Option<Option<Timeouts>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.timeouts())
+    }
+
+    pub fn set_timeouts(
+        &self,
+        proxy_id: &String,
+        timeouts: Timeouts,
+    ) -> Option<Result<(), String>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.set_timeouts(timeouts))
+    }
+
+    /// Block an address, either globally or for a specific
+    /// proxy. IPv4-mapped addresses are kept as IPv4 ones.
+    pub async fn block_ip_address(
+        &self,
+        addrs: &IpAddr,
+        proxy_id: Option<&String>,
+    ) -> Option<()> {
+        let addrs = &addrs.to_canonical();
+        match proxy_id {
+            Some(id) => {
+                let (proxy, _) = self.find_proxy(id)?;
+
+                proxy.block_ip_address(addrs).await;
+            }
             None => {
-                error!(
-                    "Proxy not found for ID: {}",
-                    proxy_id
-                );
-                return;
+                let mut blocked = self
+                    .policy
+                    .blocked_ippaddr
+                    .write()
+                    .await;
+                blocked.insert(*addrs);
             }
-        };
+        }
 
-        let (proxy, _) = entry;
+        Some(())
+    }
+
+    pub async fn get_blocked_address(
+        &self,
+        proxy_id: Option<&String>,
+    ) -> Option<HashSet<IpAddr>> {
+        match proxy_id {
+            Some(id) => {
+                let (proxy, _) = self.find_proxy(id)?;
 
-        proxy.block_ip_address(addrs).await;
+                Some(proxy.get_blocked_address().await)
+            }
+            None => Some(
+                self.policy
+                    .blocked_ippaddr
+                    .read()
+                    .await
+                    .clone(),
+            ),
+        }
     }
-    async fn get_blocked_address(&self, proxy_id: &String) -> HashSet<IpAddr>{
-        let entry = match self.get_proxy(proxy_id) {
-            Some(entry) => entry,
+
+    pub async fn remove_blocked_address(
+        &self,
+        addrs: &IpAddr,
+        proxy_id: Option<&String>,
+    ) -> Option<()> {
+        let addrs = &addrs.to_canonical();
+        match proxy_id {
+            Some(id) => {
+                let (proxy, _) = self.find_proxy(id)?;
+
+                proxy.remove_blocked_address(addrs).await;
+            }
             None => {
-                error!(
-                    "Proxy not found for ID: {}",
-                    proxy_id
-                );
-                return HashSet::new();
+                let mut blocked = self
+                    .policy
+                    .blocked_ippaddr
+                    .write()
+                    .await;
+                blocked.remove(addrs);
             }
-        };
+        }
 
-        let (proxy, _) = entry;
+        Some(())
+    }
+
+    /// Destination rules, either global or of a specific
+    /// proxy
+    pub fn get_rules(
+        &self,
+        proxy_id: Option<&String>,
+    ) -> Option<Vec<Rule>> {
+        match proxy_id {
+            Some(id) => {
+                let (proxy, _) = self.find_proxy(id)?;
 
-        proxy.get_blocked_address().await
+                Some(proxy.policy().rules())
+            }
+            None => Some(self.policy.rules.lock().rules()),
+        }
     }
 
-    async fn remove_blocked_address(&self, addrs: &IpAddr, proxy_id: &String){
-        let entry = match self.get_proxy(proxy_id) {
-            Some(entry) => entry,
+    /// Replace the destination rules, either globally or of
+    /// a specific proxy
+    pub fn set_rules(
+        &self,
+        rules: Vec<Rule>,
+        proxy_id: Option<&String>,
+    ) -> Option<Result<(), String>> {
+        match proxy_id {
+            Some(id) => {
+                let (proxy, _) = self.find_proxy(id)?;
+
+                Some(proxy.policy().set_rules(rules))
+            }
             None => {
-                error!(
-                    "Proxy not found for ID: {}",
-                    proxy_id
-                );
-                return;
+                Some(RuleSet::compile(rules).map(|rules| {
+                    *self.policy.rules.lock() =
+                        Arc::new(rules);
+                }))
             }
-        };
+        }
+    }
+
+    pub fn get_ssrf(
+        &self,
+        proxy_id: &String,
+    ) -> Option<SsrfProtection> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
 
-        let (proxy, _) = entry;
+        Some(proxy.policy().ssrf())
+    }
+
+    /// Replace the SSRF protection of a proxy
+    pub fn set_ssrf(
+        &self,
+        proxy_id: &String,
+        options: SsrfProtection,
+    ) -> Option<Result<(), String>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.policy().set_ssrf(options))
+    }
+
+    /// Address of the admin api, proxies never connect to it
+    /// whatever their SSRF protection says
+    pub fn protect_admin(&self, addrs: SocketAddr) {
+        *self.policy.admin.lock() = Some(addrs);
+    }
+
+    /// Users and blocks a proxy applies, its own merged with
+    /// the inherited global ones
+    pub async fn effective_policy(
+        &self,
+        proxy_id: &String,
+    ) -> Option<EffectivePolicy> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.policy().effective().await)
+    }
+
+    /// Choose what a proxy inherits from the global policy
+    pub fn set_inheritance(
+        &self,
+        proxy_id: &String,
+        inherit: Inheritance,
+    ) -> Option<()> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
 
-        proxy.remove_blocked_address(addrs);
+        proxy.policy().set_inheritance(inherit);
 
+        Some(())
     }
-    
 
+    pub fn get_auth_backends(
+        &self,
+        proxy_id: &String,
+    ) -> Option<Vec<AuthBackend>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.auth_backends())
+    }
+
+    /// Replace the auth chain of a proxy
+    pub fn set_auth_backends(
+        &self,
+        proxy_id: &String,
+        backends: Vec<AuthBackend>,
+    ) -> Option<Result<(), String>> {
+        let (proxy, _) = self.find_proxy(proxy_id)?;
+
+        Some(proxy.set_auth_backends(backends))
+    }
+
+    pub fn dns_config(&self) -> DnsConfig {
+        self.resolver.config()
+    }
+
+    /// Switch the resolver of every proxy to a new config,
+    /// cached answers are dropped
+    pub fn configure_dns(&self, config: &DnsConfig) {
+        self.resolver.configure(config);
+    }
+
+    pub fn dns_stats(&self) -> DnsStats {
+        self.resolver.stats()
+    }
+
+    pub fn clear_dns_cache(&self) {
+        self.resolver.clear();
+    }
+
+    /// Total bytes served by every proxy
+    pub fn total_bandwith(&self) -> u64 {
+        self.update_bandwith_usage();
+        self.total_bytes_served.load(Ordering::Relaxed)
+    }
 
     fn update_bandwith_usage(&self) {
-        let total = self.avaliable_proxies.iter().fold(0, |t, p| {
-            t + p.0.current_bandwith() 
-        });
-        self.total_bytes_served.store(total, Ordering::Relaxed)
+        let total = self
+            .avaliable_proxies
+            .iter()
+            .fold(0, |t, p| t + p.0.current_bandwith());
+        self.total_bytes_served
+            .store(total, Ordering::Relaxed)
+    }
+
+    /// Same as `get_proxy` but logs unknown ids
+    fn find_proxy(
+        &self,
+        proxy_id: &String,
+    ) -> Option<StoredProxy> {
+        let entry = self.get_proxy(proxy_id);
+
+        if entry.is_none() {
+            error!("Proxy not found for ID: {}", proxy_id);
+        }
+
+        entry
+    }
+
+    fn to_info(
+        id: &str,
+        proxy: &Arc<Box<dyn ProxyEx>>,
+        addrs: SocketAddr,
+    ) -> ProxyInfo {
+        ProxyInfo {
+            id: id.to_string(),
+            proxy_type: proxy.proxy_type(),
+            host: addrs.ip(),
+            port: addrs.port(),
+            running: proxy.is_running(),
+            sessions: proxy.active_sessions(),
+            max_bandwith: proxy.max_bandwith(),
+            bandwith: proxy.current_bandwith(),
+        }
     }
 
     fn create_proxy_id() -> String {
         Uuid::new_v4().to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::password::PasswordHash;
+
+    #[tokio::test]
+    async fn blocks_mapped_addresses_as_ipv4() {
+        let manager = ProxyManager::new();
+        let proxy_id = manager
+            .add_proxy(
+                ProxyType::Socks5,
+                "127.0.0.1:0".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+        let mapped: IpAddr =
+            "::ffff:1.2.3.4".parse().unwrap();
+        let v4: IpAddr = "1.2.3.4".parse().unwrap();
+
+        for scope in [None, Some(&proxy_id)] {
+            manager.block_ip_address(&mapped, scope).await;
+            let blocked = manager
+                .get_blocked_address(scope)
+                .await
+                .unwrap();
+            assert_eq!(blocked, HashSet::from([v4]));
+
+            manager
+                .remove_blocked_address(&mapped, scope)
+                .await;
+            let blocked = manager
+                .get_blocked_address(scope)
+                .await
+                .unwrap();
+            assert!(blocked.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn users_unique_by_name() {
+        let manager = ProxyManager::new();
+        let proxy_id = manager
+            .add_proxy(
+                ProxyType::Http,
+                "127.0.0.1:0".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+        let hash = PasswordHash::hash("secret").unwrap();
+
+        for scope in [None, Some(&proxy_id)] {
+            let user = User::new("user", hash.clone());
+            let user_id = user.user_id;
+
+            assert_eq!(
+                manager.register_user(scope, user).await,
+                Some(Ok(()))
+            );
+            assert!(matches!(
+                manager
+                    .register_user(
+                        scope,
+                        User::new("user", hash.clone())
+                    )
+                    .await,
+                Some(Err(_))
+            ));
+            assert_eq!(
+                manager
+                    .list_users(scope)
+                    .await
+                    .unwrap()
+                    .len(),
+                1
+            );
+
+            assert_eq!(
+                manager.remove_user(scope, &user_id).await,
+                Some(true)
+            );
+            assert!(manager
+                .list_users(scope)
+                .await
+                .unwrap()
+                .is_empty());
+        }
+    }
+}
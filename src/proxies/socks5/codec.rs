@@ -0,0 +1,497 @@
+use std::net::Ipv4Addr;
+
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+use super::{
+    constant::{
+        MAX_DOMAIN_LEN, MAX_USER_ID_LEN, SOCKET4_VERSION,
+        SOCKET5_VERSION, USER_PASS_VERSION,
+    },
+    models::{AuthRequest, Socks4Request, UserPassRequest},
+};
+use crate::proxies::common::{
+    ProxyError, Result as ProxyResult,
+};
+
+// Handshake messages are read field by field with
+// `read_exact`, nothing past the end of a message is
+// consumed so data a client sends early stays in the socket
+// and is relayed once the connection is set up.
+
+/// Reads exactly `len` bytes, a connection closed before
+/// that is a truncated message
+async fn read_field<R>(
+    reader: &mut R,
+    len: usize,
+    field: &str,
+) -> ProxyResult<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf).await.map_err(
+        |_e| match _e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                ProxyError::TruncatedError(format!(
+                    "Connection closed while reading {}",
+                    field
+                ))
+            }
+            _ => ProxyError::IoError(_e),
+        },
+    )?;
+
+    Ok(buf)
+}
+
+/// +----+----------+----------+
+/// |VER | NMETHODS | METHODS  |
+/// +----+----------+----------+
+/// | 1  |    1     | 1 to 255 |
+/// +----+----------+----------+
+pub async fn read_auth_request<R>(
+    reader: &mut R,
+) -> ProxyResult<AuthRequest>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut frame =
+        read_field(reader, 2, "VER and NMETHODS").await?;
+
+    // Anything else is not worth waiting for
+    if frame[0] != SOCKET5_VERSION {
+        return Err(ProxyError::ParseError(format!(
+            "Unsupported version: {}",
+            frame[0]
+        )));
+    }
+    if frame[1] == 0 {
+        return Err(ProxyError::ParseError(
+            "No auth method offered".to_string(),
+        ));
+    }
+
+    let nmethods = frame[1] as usize;
+    frame.extend(
+        read_field(reader, nmethods, "METHODS").await?,
+    );
+
+    AuthRequest::from_bytes(&frame)
+        .map_err(ProxyError::ParseError)
+}
+
+/// +----+------+----------+------+----------+
+/// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+/// +----+------+----------+------+----------+
+/// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+/// +----+------+----------+------+----------+
+pub async fn read_user_pass_request<R>(
+    reader: &mut R,
+) -> ProxyResult<UserPassRequest>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut frame =
+        read_field(reader, 2, "VER and ULEN").await?;
+
+    if frame[0] != USER_PASS_VERSION {
+        return Err(ProxyError::ParseError(format!(
+            "Unsupported sub-negotiation version: {}",
+            frame[0]
+        )));
+    }
+
+    let ulen = frame[1] as usize;
+    frame.extend(
+        read_field(reader, ulen + 1, "UNAME and PLEN")
+            .await?,
+    );
+
+    let plen = frame[frame.len() - 1] as usize;
+    frame.extend(read_field(reader, plen, "PASSWD").await?);
+
+    UserPassRequest::from_bytes(&frame)
+        .map_err(ProxyError::ParseError)
+}
+
+/// Reads the bytes of a request, parsing is left to
+/// `Request::from_bytes` so the reply can tell what was
+/// wrong. An unknown ATYP ends the frame after the header
+/// since the length of the address is unknown.
+///
+/// +----+-----+-------+------+----------+----------+
+/// |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
+/// +----+-----+-------+------+----------+----------+
+/// | 1  |  1  | X'00' |  1   | Variable |    2     |
+/// +----+-----+-------+------+----------+----------+
+pub async fn read_request<R>(
+    reader: &mut R,
+) -> ProxyResult<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut frame =
+        read_field(reader, 4, "request header").await?;
+
+    if frame[0] != SOCKET5_VERSION {
+        return Err(ProxyError::ParseError(format!(
+            "Unsupported version: {}",
+            frame[0]
+        )));
+    }
+
+    let addr_len = match frame[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let len =
+                read_field(reader, 1, "domain length")
+                    .await?[0];
+            frame.push(len);
+
+            let len = len as usize;
+            if len == 0 {
+                return Err(ProxyError::ParseError(
+                    "Empty domain name".to_string(),
+                ));
+            }
+            if len > MAX_DOMAIN_LEN {
+                return Err(ProxyError::OversizedError(
+                    format!(
+                        "Domain name of {} bytes, at most {}",
+                        len, MAX_DOMAIN_LEN
+                    ),
+                ));
+            }
+            len
+        }
+        _ => return Ok(frame),
+    };
+
+    frame.extend(
+        read_field(reader, addr_len + 2, "DST.ADDR")
+            .await?,
+    );
+
+    Ok(frame)
+}
+
+/// Reads up to a NUL, which is not returned. Fields longer
+/// than `max` are rejected.
+async fn read_until_nul<R>(
+    reader: &mut R,
+    max: usize,
+    field: &str,
+) -> ProxyResult<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    loop {
+        let byte = read_field(reader, 1, field).await?[0];
+        if byte == 0 {
+            return Ok(buf);
+        }
+        if buf.len() == max {
+            return Err(ProxyError::OversizedError(
+                format!(
+                    "{} longer than {} bytes",
+                    field, max
+                ),
+            ));
+        }
+        buf.push(byte);
+    }
+}
+
+/// SOCKS4 and SOCKS4a requests, see `Socks4Request`
+pub async fn read_socks4_request<R>(
+    reader: &mut R,
+) -> ProxyResult<Socks4Request>
+where
+    R: AsyncRead + Unpin,
+{
+    let header =
+        read_field(reader, 8, "VN to DSTIP").await?;
+
+    if header[0] != SOCKET4_VERSION {
+        return Err(ProxyError::ParseError(format!(
+            "Unsupported version: {}",
+            header[0]
+        )));
+    }
+
+    let user_id =
+        read_until_nul(reader, MAX_USER_ID_LEN, "USERID")
+            .await?;
+
+    let dst_ip = Ipv4Addr::new(
+        header[4], header[5], header[6], header[7],
+    );
+    let domain = if Socks4Request::is_socks4a(&dst_ip) {
+        let domain = read_until_nul(
+            reader,
+            MAX_DOMAIN_LEN,
+            "domain name",
+        )
+        .await?;
+        if domain.is_empty() {
+            return Err(ProxyError::ParseError(
+                "Empty domain name".to_string(),
+            ));
+        }
+        Some(domain)
+    } else {
+        None
+    };
+
+    Socks4Request::from_parts(
+        &header,
+        &user_id,
+        domain.as_deref(),
+    )
+    .map_err(ProxyError::ParseError)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::io::ReadBuf;
+
+    use super::*;
+
+    /// Hands out at most one byte per read, like a peer
+    /// sending every byte in its own segment
+    struct Fragmented<'a>(&'a [u8]);
+
+    impl AsyncRead for Fragmented<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some((first, rest)) =
+                self.0.split_first()
+            {
+                buf.put_slice(&[*first]);
+                self.0 = rest;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn domain_request(domain: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x05, 0x01, 0x00, 0x03];
+        frame.push(domain.len() as u8);
+        frame.extend_from_slice(domain);
+        frame.extend_from_slice(&80u16.to_be_bytes());
+        frame
+    }
+
+    #[tokio::test]
+    async fn fragmented_greeting() {
+        let mut reader =
+            Fragmented(&[0x05, 0x02, 0x00, 0x02]);
+
+        let req =
+            read_auth_request(&mut reader).await.unwrap();
+
+        assert_eq!(req.methods, vec![0x00, 0x02]);
+        assert!(reader.0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fragmented_user_pass() {
+        let mut reader =
+            Fragmented(b"\x01\x04user\x06secret");
+
+        let req = read_user_pass_request(&mut reader)
+            .await
+            .unwrap();
+
+        assert_eq!(req.username, "user");
+        assert_eq!(req.password, "secret");
+    }
+
+    #[tokio::test]
+    async fn fragmented_request() {
+        let frame = domain_request(b"example.com");
+        let mut reader = Fragmented(&frame);
+
+        let read = read_request(&mut reader).await.unwrap();
+
+        assert_eq!(read, frame);
+    }
+
+    #[tokio::test]
+    async fn fragmented_socks4a_request() {
+        let mut reader = Fragmented(
+            b"\x04\x01\x00\x50\x00\x00\x00\x01user\0example.com\0",
+        );
+
+        let req =
+            read_socks4_request(&mut reader).await.unwrap();
+
+        assert_eq!(req.user_id, "user");
+        assert_eq!(
+            req.domain.as_deref(),
+            Some("example.com")
+        );
+        assert!(reader.0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn truncated_frames() {
+        let greeting: &[u8] = &[0x05, 0x03, 0x00, 0x02];
+        let mut reader = greeting;
+        assert!(matches!(
+            read_auth_request(&mut reader).await,
+            Err(ProxyError::TruncatedError(_))
+        ));
+
+        let user_pass: &[u8] = b"\x01\x04us";
+        let mut reader = user_pass;
+        assert!(matches!(
+            read_user_pass_request(&mut reader).await,
+            Err(ProxyError::TruncatedError(_))
+        ));
+
+        let frame = domain_request(b"example.com");
+        for len in [2, 4, 5, 10, frame.len() - 1] {
+            let mut reader = &frame[..len];
+            assert!(
+                matches!(
+                    read_request(&mut reader).await,
+                    Err(ProxyError::TruncatedError(_))
+                ),
+                "{} bytes",
+                len
+            );
+        }
+
+        let socks4: &[u8] =
+            b"\x04\x01\x00\x50\x7f\x00\x00\x01user";
+        let mut reader = socks4;
+        assert!(matches!(
+            read_socks4_request(&mut reader).await,
+            Err(ProxyError::TruncatedError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_domain() {
+        let frame = domain_request(&[b'a'; 254]);
+        let mut reader = &frame[..];
+
+        assert!(matches!(
+            read_request(&mut reader).await,
+            Err(ProxyError::OversizedError(_))
+        ));
+
+        let frame = domain_request(&[b'a'; MAX_DOMAIN_LEN]);
+        let mut reader = &frame[..];
+        assert!(read_request(&mut reader).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn empty_domain() {
+        let frame = domain_request(b"");
+        let mut reader = &frame[..];
+
+        assert!(matches!(
+            read_request(&mut reader).await,
+            Err(ProxyError::ParseError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_socks4_fields() {
+        let mut frame =
+            b"\x04\x01\x00\x50\x7f\x00\x00\x01".to_vec();
+        frame.extend_from_slice(
+            &[b'u'; MAX_USER_ID_LEN + 1],
+        );
+        frame.push(0);
+        let mut reader = &frame[..];
+        assert!(matches!(
+            read_socks4_request(&mut reader).await,
+            Err(ProxyError::OversizedError(_))
+        ));
+
+        let mut frame =
+            b"\x04\x01\x00\x50\x00\x00\x00\x01\x00"
+                .to_vec();
+        frame
+            .extend_from_slice(&[b'a'; MAX_DOMAIN_LEN + 1]);
+        frame.push(0);
+        let mut reader = &frame[..];
+        assert!(matches!(
+            read_socks4_request(&mut reader).await,
+            Err(ProxyError::OversizedError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_atyp_ends_after_header() {
+        let data: &[u8] = &[
+            0x05, 0x01, 0x00, 0x09, 1, 2, 3,
+        ];
+        let mut reader = data;
+
+        let frame =
+            read_request(&mut reader).await.unwrap();
+
+        assert_eq!(frame, vec![0x05, 0x01, 0x00, 0x09]);
+        assert_eq!(reader, &[1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn wrong_version() {
+        let data: &[u8] = &[0x04, 0x01, 0x00];
+        let mut reader = data;
+        assert!(matches!(
+            read_auth_request(&mut reader).await,
+            Err(ProxyError::ParseError(_))
+        ));
+
+        let data: &[u8] = &[0x05, 0x00];
+        let mut reader = data;
+        assert!(matches!(
+            read_auth_request(&mut reader).await,
+            Err(ProxyError::ParseError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn pipelined_payload_stays_in_stream() {
+        let mut data = vec![0x05, 0x01, 0x00];
+        data.extend(domain_request(b"example.com"));
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        let mut reader = &data[..];
+
+        read_auth_request(&mut reader).await.unwrap();
+        read_request(&mut reader).await.unwrap();
+
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n\r\n");
+
+        let mut data =
+            b"\x04\x01\x00\x50\x00\x00\x00\x01user\x00"
+                .to_vec();
+        data.extend_from_slice(b"example.com\x00payload");
+        let mut reader = &data[..];
+
+        let req =
+            read_socks4_request(&mut reader).await.unwrap();
+
+        assert_eq!(req.user_id, "user");
+        assert_eq!(
+            req.domain.as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(reader, b"payload");
+    }
+}
@@ -1 +1,20 @@
 pub const SOCKET5_VERSION: u8 = 0x5;
+pub const SOCKET4_VERSION: u8 = 0x4;
+
+// SOCKS4 reply codes, the version byte of a reply is 0
+pub const SOCKS4_REPLY_VERSION: u8 = 0x0;
+pub const SOCKS4_GRANTED: u8 = 0x5a;
+pub const SOCKS4_REJECTED: u8 = 0x5b;
+pub const SOCKS4_USER_MISMATCH: u8 = 0x5d;
+
+// Longest SOCKS4 USERID, it has no length field
+pub const MAX_USER_ID_LEN: usize = 255;
+
+// Version of the username/password sub-negotiation, RFC 1929
+pub const USER_PASS_VERSION: u8 = 0x1;
+
+// Longest host name a request may carry, RFC 1035
+pub const MAX_DOMAIN_LEN: usize = 253;
+
+// Largest payload a UDP datagram can carry
+pub const UDP_MAX_DATAGRAM: usize = 65_535;
@@ -1,92 +1,264 @@
+mod codec;
 mod constant;
 mod models;
 
 use std::{
     collections::HashSet,
+    future::Future,
     net::{IpAddr, SocketAddr},
     str::FromStr,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
 };
 
 use async_trait::async_trait;
-use constant::SOCKET5_VERSION;
-use metrics::counter;
+use codec::{
+    read_auth_request, read_request, read_socks4_request,
+    read_user_pass_request,
+};
+use constant::{
+    SOCKET4_VERSION, SOCKET5_VERSION, SOCKS4_USER_MISMATCH,
+    UDP_MAX_DATAGRAM,
+};
 use models::{
-    AuthMethods, AuthReply, AuthRequest, CommandType,
-    Reply, ReplyType, Request,
+    AddressType, AuthMethods, AuthReply, CommandType,
+    Reply, ReplyType, Request, Socks4Reply, UdpReply,
+    UdpRequest, UserPassReply, UserPassStatus,
 };
+use parking_lot::Mutex;
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncWriteExt},
     runtime::Runtime,
 };
 use tokio::{
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpStream, UdpSocket},
     sync::RwLock,
+    task::JoinHandle,
+    time::{self, Instant},
 };
 use tracing::{error, info, instrument};
 use uuid::Uuid;
 
-use crate::models::users::User;
+use crate::models::users::{User, Users};
 
 use super::{
+    auth::{AuthBackend, AuthChain},
     common::{ProxyError, Result as ProxyResult},
-    proxy_manager::ProxyEx,
+    dns::Resolver,
+    proxy_manager::{ProxyEx, ProxyType},
+    utils::{
+        bind::BindOptions,
+        client_acl::{
+            client_refused, ClientAccess, ClientAcl,
+            ClientAclSet,
+        },
+        conn_limit::{
+            limit_reached, ConnectionLimiter,
+            ConnectionLimits, ConnectionPermit, Slot,
+            WhenFull,
+        },
+        connect::{connect_happy_eyeballs, ConnectOptions},
+        io::accept_with_backoff,
+        policy::{GlobalPolicy, ProxyPolicy},
+        rate_limit::{
+            Limiters, RateLimit, RateLimits, TokenBucket,
+        },
+        rules::Destination,
+        sessions::{
+            SessionInfo, SessionStats, Sessions,
+            StopSessions,
+        },
+        timeout::{
+            copy_bidirectional_timeout, timed_out, Timeouts,
+        },
+    },
 };
 
 #[derive(Clone, Debug)]
 pub struct Socks5Proxy {
-    // Port
-    port: u16,
+    // Listening address
+    addrs: SocketAddr,
 
     avaliable_auth_methods: Arc<RwLock<HashSet<u8>>>,
-    avaliable_users: Arc<RwLock<HashSet<User>>>,
-
+    avaliable_users: Arc<RwLock<Users>>,
 
     blocked_ippaddr: Arc<RwLock<HashSet<IpAddr>>>,
-
+    // Own users and blocks layered over the global ones
+    policy: Arc<ProxyPolicy>,
+    // Shared with every proxy of the manager
+    resolver: Arc<Resolver>,
+    // Replaced as a whole when the backends change
+    authenticator: Arc<Mutex<Arc<AuthChain>>>,
 
     max_bandwith: Arc<AtomicU64>,
     bandwith: Arc<AtomicU64>,
+
+    // Shared by every connection
+    rate_limiter: Arc<TokenBucket>,
+    // Each connection gets its own bucket
+    connection_rate_limit: Arc<Mutex<Option<RateLimit>>>,
+
+    // Address families and racing of outbound connections
+    connect_options: Arc<Mutex<ConnectOptions>>,
+
+    timeouts: Arc<Mutex<Timeouts>>,
+    // Concurrent connections of the proxy, its clients and
+    // users
+    connection_limits: Arc<ConnectionLimiter>,
+    // Where BIND requests listen
+    bind_options: Arc<Mutex<BindOptions>>,
+    // SOCKS4 and SOCKS4a clients served on the same port
+    socks4: Arc<AtomicBool>,
+    // Clients served and the auth methods they may use,
+    // replaced as a whole
+    client_acl: Arc<Mutex<Arc<ClientAclSet>>>,
+
+    // Accept loop, aborting it closes the listener
+    accept_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    sessions: Arc<Sessions>,
 }
 
 #[async_trait]
 impl ProxyEx for Socks5Proxy {
     async fn start(&self) -> Result<(), String> {
-        let addrs = format!("0.0.0.0:{}", self.port);
+        if self.is_running() {
+            return Err(
+                "Proxy already running!".to_string()
+            );
+        }
 
-        let listener = TcpListener::bind(&addrs)
+        let addrs = self.addrs;
+
+        let listener = TcpListener::bind(addrs)
             .await
             .map_err(|_e| _e.to_string())?;
 
         info!("Starting sock5 proxy on : {}", addrs);
 
         let proxy = Arc::new(RwLock::new(self.clone()));
+        let sessions = Arc::clone(&self.sessions);
+        let limiter = Arc::clone(&self.connection_limits);
+        let client_acl = Arc::clone(&self.client_acl);
 
-        tokio::spawn(async move {
+        let accept_task = tokio::spawn(async move {
             loop {
-                let (socket, addr) = listener
-                    .accept()
-                    .await
-                    .map_err(|_e| _e.to_string())
-                    // TODO: Handle
-                    .unwrap();
+                // A full proxy stops accepting, clients wait
+                // in the listen backlog
+                let queued = match limiter
+                    .limits()
+                    .when_full
+                {
+                    WhenFull::Queue => Some(
+                        limiter.acquire(Slot::Proxy).await,
+                    ),
+                    _ => None,
+                };
+
+                let (socket, addr) =
+                    accept_with_backoff(&listener).await;
+
+                // Refused clients are closed before anything
+                // is read
+                let acl = Arc::clone(&client_acl.lock());
+                let ClientAccess::Allowed(auth_methods) =
+                    acl.check(&addr.ip())
+                else {
+                    client_refused(&addr);
+                    continue;
+                };
+
+                let permit = queued.or_else(|| {
+                    limiter.try_acquire(Slot::Proxy)
+                });
+                if permit.is_none() {
+                    limit_reached(&Slot::Proxy);
+                    if limiter.limits().when_full
+                        != WhenFull::Reject
+                    {
+                        continue;
+                    }
+                }
 
                 let proxy_clone = Arc::clone(&proxy);
 
-                tokio::spawn(handle_conn(
-                    proxy_clone,
-                    socket,
-                    addr,
-                ));
+                sessions.spawn(addr, |stats| async move {
+                    if let Err(_e) = handle_conn(
+                        proxy_clone,
+                        socket,
+                        addr,
+                        stats,
+                        permit,
+                        auth_methods,
+                    )
+                    .await
+                    {
+                        error!(
+                            "Socks5 connection {} failed: {}",
+                            addr, _e
+                        );
+                    }
+                });
             }
         });
 
+        *self.accept_task.lock() = Some(accept_task);
+
         Ok(())
     }
 
+    async fn stop(&self, sessions: StopSessions) {
+        let accept_task = self.accept_task.lock().take();
+
+        if let Some(accept_task) = accept_task {
+            accept_task.abort();
+            // Wait until the listener is dropped and the
+            // port is free again
+            let _ = accept_task.await;
+            info!("Stopped proxy on : {}", self.addrs);
+        }
+
+        self.sessions.close(sessions).await;
+    }
+
+    fn is_running(&self) -> bool {
+        self.accept_task.lock().is_some()
+    }
+
+    fn active_sessions(&self) -> usize {
+        self.sessions.active()
+    }
+
+    fn sessions(&self) -> Vec<SessionInfo> {
+        self.sessions.list()
+    }
+
+    fn policy(&self) -> Arc<ProxyPolicy> {
+        Arc::clone(&self.policy)
+    }
+
+    fn auth_backends(&self) -> Vec<AuthBackend> {
+        self.authenticator.lock().backends()
+    }
+
+    fn set_auth_backends(
+        &self,
+        backends: Vec<AuthBackend>,
+    ) -> Result<(), String> {
+        let chain = AuthChain::build(
+            backends,
+            &self.policy,
+            self.addrs,
+        )?;
+        *self.authenticator.lock() = chain;
+        Ok(())
+    }
+
+    fn proxy_type(&self) -> ProxyType {
+        ProxyType::Socks5
+    }
+
     async fn avaliable_auth_methods(&self) -> HashSet<u8> {
         self.avaliable_auth_methods.read().await.clone()
     }
@@ -115,14 +287,19 @@ impl ProxyEx for Socks5Proxy {
         });
     }
 
-    async fn avaliable_users(&self) -> HashSet<User> {
+    async fn avaliable_users(&self) -> Users {
         let res = self.avaliable_users.read().await;
         res.clone()
     }
 
-    async fn set_user(&self, user: User) {
+    async fn set_user(&self, user: User) -> bool {
         let mut users = self.avaliable_users.write().await;
-        users.insert(user);
+        if users.contains_key(&user.user_name) {
+            return false;
+        }
+
+        users.insert(user.user_name.clone(), user);
+        true
     }
 
     async fn remove_user(&self, user_id: &str) -> bool {
@@ -131,65 +308,183 @@ impl ProxyEx for Socks5Proxy {
             Err(_) => return false,
         };
 
-        let user = {
-            let users = self.avaliable_users.read().await;
-            users
-                .iter()
-                .find(|user| user.user_id == user_id)
-                .cloned()
-        };
-
-        if user.is_none() {
-            return false;
-        }
-
         let mut users = self.avaliable_users.write().await;
-        users.remove(&user.unwrap());
+        let before = users.len();
+        users.retain(|_, user| user.user_id != user_id);
 
-        true
+        users.len() != before
     }
 
     async fn set_max_bandwith(&self, max: u64) {
         self.max_bandwith.store(max, Ordering::Relaxed);
     }
 
-    fn current_bandwith(&self) -> u64{
+    fn max_bandwith(&self) -> u64 {
+        self.max_bandwith.load(Ordering::Relaxed)
+    }
+
+    fn current_bandwith(&self) -> u64 {
         self.bandwith().load(Ordering::Relaxed)
     }
 
-    async fn block_ip_address(&self, addrs: &IpAddr){
-        let mut binding = self.blocked_ippaddr.write().await;
+    async fn set_rate_limits(&self, limits: RateLimits) {
+        self.rate_limiter.set_limit(limits.proxy);
+        *self.connection_rate_limit.lock() =
+            limits.connection;
+    }
+
+    fn connect_options(&self) -> ConnectOptions {
+        *self.connect_options.lock()
+    }
+
+    fn set_connect_options(&self, options: ConnectOptions) {
+        *self.connect_options.lock() = options;
+    }
+
+    fn bind_options(&self) -> Option<BindOptions> {
+        Some(*self.bind_options.lock())
+    }
+
+    fn set_bind_options(
+        &self,
+        options: BindOptions,
+    ) -> Result<(), String> {
+        *self.bind_options.lock() = options;
+        Ok(())
+    }
+
+    fn client_acl(&self) -> Option<ClientAcl> {
+        Some(self.client_acl.lock().acl())
+    }
+
+    fn set_client_acl(
+        &self,
+        acl: ClientAcl,
+    ) -> Result<(), String> {
+        *self.client_acl.lock() =
+            Arc::new(ClientAclSet::compile(acl)?);
+        Ok(())
+    }
+
+    fn socks4(&self) -> Option<bool> {
+        Some(self.socks4.load(Ordering::Relaxed))
+    }
+
+    fn set_socks4(
+        &self,
+        enabled: bool,
+    ) -> Result<(), String> {
+        self.socks4.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn connection_limits(
+        &self,
+    ) -> Option<ConnectionLimits> {
+        Some(self.connection_limits.limits())
+    }
+
+    fn set_connection_limits(
+        &self,
+        limits: ConnectionLimits,
+    ) -> Result<(), String> {
+        self.connection_limits.set_limits(limits);
+        Ok(())
+    }
+
+    fn timeouts(&self) -> Option<Timeouts> {
+        Some(*self.timeouts.lock())
+    }
+
+    fn set_timeouts(
+        &self,
+        timeouts: Timeouts,
+    ) -> Result<(), String> {
+        *self.timeouts.lock() = timeouts;
+        Ok(())
+    }
+
+    fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            proxy: self.rate_limiter.limit(),
+            connection: *self.connection_rate_limit.lock(),
+        }
+    }
+
+    async fn block_ip_address(&self, addrs: &IpAddr) {
+        let mut binding =
+            self.blocked_ippaddr.write().await;
         binding.insert(addrs.clone());
     }
 
-    async fn get_blocked_address(&self) -> HashSet<IpAddr>{
+    async fn get_blocked_address(&self) -> HashSet<IpAddr> {
         let binding = self.blocked_ippaddr.read().await;
         binding.clone()
     }
 
-    async fn remove_blocked_address(&self, addrs: &IpAddr){
-        let mut binding = self.blocked_ippaddr.write().await;
+    async fn remove_blocked_address(&self, addrs: &IpAddr) {
+        let mut binding =
+            self.blocked_ippaddr.write().await;
         binding.remove(&addrs);
     }
 }
 
 impl Socks5Proxy {
-    pub fn new(port: u16) -> Self {
+    pub fn new(
+        addrs: SocketAddr,
+        global: Arc<GlobalPolicy>,
+        resolver: Arc<Resolver>,
+    ) -> Self {
+        let avaliable_users =
+            Arc::new(RwLock::new(Users::new()));
+        let blocked_ippaddr =
+            Arc::new(RwLock::new(HashSet::new()));
+
+        let policy = ProxyPolicy::new(
+            global,
+            Arc::clone(&avaliable_users),
+            Arc::clone(&blocked_ippaddr),
+        );
+
         Self {
-            port,
+            addrs,
             avaliable_auth_methods: Arc::new(RwLock::new(
                 HashSet::new(),
             )),
-            avaliable_users: Arc::new(RwLock::new(
-                HashSet::new(),
+            authenticator: Arc::new(Mutex::new(
+                AuthChain::memory(&policy),
             )),
-
-            blocked_ippaddr: Arc::new(RwLock::new(HashSet::new())),
+            policy,
+            resolver,
+            avaliable_users,
+            blocked_ippaddr,
 
             max_bandwith: Arc::new(AtomicU64::new(0)),
 
             // TODO: Set a minimum of value
             bandwith: Arc::new(AtomicU64::new(0)),
+
+            rate_limiter: TokenBucket::new(None),
+            connection_rate_limit: Arc::new(Mutex::new(
+                None,
+            )),
+            connect_options: Arc::new(Mutex::new(
+                ConnectOptions::default(),
+            )),
+            timeouts: Arc::new(Mutex::new(
+                Timeouts::default(),
+            )),
+            connection_limits: ConnectionLimiter::new(),
+            bind_options: Arc::new(Mutex::new(
+                BindOptions::default(),
+            )),
+            socks4: Arc::new(AtomicBool::new(false)),
+            client_acl: Arc::new(
+                Mutex::new(Arc::default()),
+            ),
+
+            accept_task: Arc::new(Mutex::new(None)),
+            sessions: Sessions::new(),
         }
     }
 
@@ -197,6 +492,56 @@ impl Socks5Proxy {
         self.bandwith.clone()
     }
 
+    /// Bucket for the connection rate limit, one per client
+    /// connection
+    fn connection_limiter(&self) -> Arc<TokenBucket> {
+        TokenBucket::new(*self.connection_rate_limit.lock())
+    }
+
+    /// Limits and counters relayed bytes of a connection
+    /// pass
+    fn limiters(
+        &self,
+        connection: &Arc<TokenBucket>,
+        user: Option<&User>,
+        stats: &SessionStats,
+    ) -> Limiters {
+        let mut limiters = Limiters::default();
+
+        limiters.push_cap(
+            self.bandwith(),
+            Arc::clone(&self.max_bandwith),
+        );
+        limiters.push_counter(stats.bytes());
+        limiters.push(Arc::clone(&self.rate_limiter));
+        limiters.push(Arc::clone(connection));
+        if let Some(user) = user {
+            limiters.push(Arc::clone(&user.rate_limiter));
+            limiters.push_quota(Arc::clone(&user.quota));
+        }
+
+        limiters
+    }
+
+    /// Enabled auth methods a client may use, `allowed` as
+    /// the client ACL gave them
+    async fn auth_methods_for(
+        &self,
+        allowed: &Option<HashSet<u8>>,
+    ) -> HashSet<u8> {
+        self.avaliable_auth_methods
+            .read()
+            .await
+            .iter()
+            .filter(|method| {
+                allowed
+                    .as_ref()
+                    .is_none_or(|a| a.contains(method))
+            })
+            .copied()
+            .collect()
+    }
+
     /// Checking has banthwith access more
     fn has_bandwith(&self) -> bool {
         self.max_bandwith.load(Ordering::Relaxed)
@@ -259,27 +604,86 @@ async fn handle_conn(
     proxy: Arc<RwLock<Socks5Proxy>>,
     mut socket: TcpStream,
     addr: SocketAddr,
+    stats: Arc<SessionStats>,
+    proxy_permit: Option<ConnectionPermit>,
+    auth_methods: Option<HashSet<u8>>,
 ) -> ProxyResult<()> {
-    let mut buf: [u8; 258] = [0; 258];
-
-    read_message(&mut socket, &mut buf).await;
-
-    let auth_request = AuthRequest::from_bytes(&buf);
-
-    if let Err(_e) = auth_request {
-        error!("Error while parsing auth request: {}", _e);
+    // Greeting, auth and request have to arrive in time
+    let (timeouts, limiter, socks4) = {
+        let proxy_read = proxy.read().await;
+        let timeouts = *proxy_read.timeouts.lock();
+        (
+            timeouts,
+            Arc::clone(&proxy_read.connection_limits),
+            proxy_read.socks4.load(Ordering::Relaxed),
+        )
+    };
+    let deadline = Instant::now() + timeouts.handshake();
+
+    // Held until the connection ends, a missing one gets
+    // the request refused
+    let mut rejected = proxy_permit.is_none();
+    let mut permits: Vec<ConnectionPermit> =
+        proxy_permit.into_iter().collect();
+
+    if !take_slot(
+        &limiter,
+        Slot::Ip(addr.ip()),
+        deadline,
+        &mut permits,
+        &mut rejected,
+    )
+    .await
+    {
         close_socket(&mut socket).await;
         return Ok(());
     }
 
-    // Safe Unwrap
-    let auth_request = auth_request.unwrap();
+    // SOCKS4 has no greeting, the version byte tells the
+    // protocols apart. With SOCKS4 disabled the greeting
+    // parser refuses it.
+    let version = match handshake_step(
+        deadline,
+        peek_version(&socket),
+    )
+    .await
+    {
+        Ok(version) => version,
+        Err(_e) => {
+            close_socket(&mut socket).await;
+            return Err(_e);
+        }
+    };
+    if socks4 && version == SOCKET4_VERSION {
+        return socks4_handler(
+            &proxy,
+            &mut socket,
+            &stats,
+            deadline,
+            &mut permits,
+            rejected,
+            &auth_methods,
+        )
+        .await;
+    }
 
+    let auth_request = match handshake_step(
+        deadline,
+        read_auth_request(&mut socket),
+    )
+    .await
+    {
+        Ok(auth_request) => auth_request,
+        Err(_e) => {
+            close_socket(&mut socket).await;
+            return Err(_e);
+        }
+    };
 
     let proxy_read = proxy.read().await;
     let proxy_auth_methods =
-        proxy_read.avaliable_auth_methods.read().await;
-
+        proxy_read.auth_methods_for(&auth_methods).await;
+    let mut user = None;
 
     if auth_request
         .methods
@@ -293,25 +697,67 @@ async fn handle_conn(
             AuthMethods::UsernamePassword,
         );
         send_message(&mut socket, &resp.to_byte()).await;
-        read_message(&mut socket, &mut buf).await;
 
-        let (username, password) = parse_user_pass(&buf);
+        let req = match handshake_step(
+            deadline,
+            read_user_pass_request(&mut socket),
+        )
+        .await
+        {
+            Ok(req) => req,
+            Err(_e) => {
+                let reply = UserPassReply::new(
+                    UserPassStatus::Failure,
+                );
+                send_message(&mut socket, &reply.to_byte())
+                    .await;
+                close_socket(&mut socket).await;
+                return Err(_e);
+            }
+        };
 
         // Check username And password access
-        let users = proxy_read.avaliable_users.read().await;
+        let authenticator =
+            Arc::clone(&proxy_read.authenticator.lock());
+
+        user = authenticator
+            .login(&req.username, &req.password)
+            .await;
+
+        let status = match user {
+            Some(_) => UserPassStatus::Success,
+            None => UserPassStatus::Failure,
+        };
+        send_message(
+            &mut socket,
+            &UserPassReply::new(status).to_byte(),
+        )
+        .await;
 
-        if !User::check_user_pass(
-            &users,
-            username.clone(),
-            password.clone(),
-        ) {
+        // Client must close the connection after a failure
+        if user.is_none() {
             error!(
-                "USERNAME: {}, PASS: {}  - Not valid",
-                username, password
+                "USERNAME: {} - Not valid",
+                req.username
             );
             close_socket(&mut socket).await;
             return Ok(());
         }
+
+        stats.set_user(&req.username);
+
+        if !take_slot(
+            &limiter,
+            Slot::User(req.username.clone()),
+            deadline,
+            &mut permits,
+            &mut rejected,
+        )
+        .await
+        {
+            close_socket(&mut socket).await;
+            return Ok(());
+        }
     } else if auth_request
         .methods
         .contains(&AuthMethods::NoAuth.to_byte())
@@ -319,75 +765,295 @@ async fn handle_conn(
             .contains(&AuthMethods::NoAuth.to_byte())
     {
         info!("NO AUTH");
+        let resp = AuthReply::new(
+            SOCKET5_VERSION,
+            AuthMethods::NoAuth,
+        );
+        send_message(&mut socket, &resp.to_byte()).await;
     } else {
+        let resp = AuthReply::new(
+            SOCKET5_VERSION,
+            AuthMethods::NotAcceptable,
+        );
+        send_message(&mut socket, &resp.to_byte()).await;
         close_socket(&mut socket).await;
         return Ok(());
     }
 
-    // Drop buffer
-    let _ = buf;
-
-    // Validate access to client
-    let resp = AuthReply::new(
-        SOCKET5_VERSION,
-        ReplyType::Succeeded.to_byte(),
+    let limiters = proxy_read.limiters(
+        &proxy_read.connection_limiter(),
+        user.as_ref(),
+        &stats,
     );
+    drop(proxy_read);
+
+    command_handler(
+        &proxy,
+        &mut socket,
+        &limiters,
+        deadline,
+        rejected,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Takes a slot of the connection limits, false when the
+/// connection has to be closed
+async fn take_slot(
+    limiter: &Arc<ConnectionLimiter>,
+    slot: Slot,
+    deadline: Instant,
+    permits: &mut Vec<ConnectionPermit>,
+    rejected: &mut bool,
+) -> bool {
+    match limiter.admit(slot, deadline).await {
+        Ok(permit) => {
+            permits.push(permit);
+            true
+        }
+        Err(WhenFull::Reject) => {
+            *rejected = true;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// First byte the client sends, left in the socket
+async fn peek_version(
+    socket: &TcpStream,
+) -> ProxyResult<u8> {
+    let mut buf = [0; 1];
+    match socket.peek(&mut buf).await? {
+        0 => Err(ProxyError::TruncatedError(
+            "Connection closed before VER".to_string(),
+        )),
+        _ => Ok(buf[0]),
+    }
+}
+
+/// Serves a SOCKS4 or SOCKS4a request.
+///
+/// SOCKS4 has no method negotiation, the USERID is all the
+/// client sends. With username/password auth enabled a
+/// USERID of the form `name:password` logs in as that user,
+/// with no auth enabled any other USERID is let through.
+async fn socks4_handler(
+    proxy: &Arc<RwLock<Socks5Proxy>>,
+    socket: &mut TcpStream,
+    stats: &SessionStats,
+    deadline: Instant,
+    permits: &mut Vec<ConnectionPermit>,
+    mut rejected: bool,
+    auth_methods: &Option<HashSet<u8>>,
+) -> ProxyResult<()> {
+    let req = match handshake_step(
+        deadline,
+        read_socks4_request(socket),
+    )
+    .await
+    {
+        Ok(req) => req,
+        Err(
+            _e @ (ProxyError::ParseError(_)
+            | ProxyError::OversizedError(_)),
+        ) => {
+            send_error_reply(
+                socket,
+                SOCKET4_VERSION,
+                ReplyType::GeneralFailure,
+            )
+            .await;
+            return Err(_e);
+        }
+        Err(_e) => {
+            close_socket(socket).await;
+            return Err(_e);
+        }
+    };
+
+    let proxy_read = proxy.read().await;
+    let proxy_auth_methods =
+        proxy_read.auth_methods_for(auth_methods).await;
+    let mut user = None;
+
+    match req.user_id.split_once(':') {
+        Some((user_name, password))
+            if proxy_auth_methods.contains(
+                &AuthMethods::UsernamePassword.to_byte(),
+            ) =>
+        {
+            let authenticator = Arc::clone(
+                &proxy_read.authenticator.lock(),
+            );
+
+            user = authenticator
+                .login(user_name, password)
+                .await;
+
+            if user.is_none() {
+                error!(
+                    "USERNAME: {} - Not valid",
+                    user_name
+                );
+                send_user_mismatch(socket).await;
+                return Ok(());
+            }
+
+            stats.set_user(user_name);
 
-    send_message(&mut socket, &resp.to_byte()).await;
+            if !take_slot(
+                &proxy_read.connection_limits,
+                Slot::User(user_name.to_string()),
+                deadline,
+                permits,
+                &mut rejected,
+            )
+            .await
+            {
+                close_socket(socket).await;
+                return Ok(());
+            }
+        }
+        _ if proxy_auth_methods
+            .contains(&AuthMethods::NoAuth.to_byte()) =>
+        {
+            info!("NO AUTH");
+        }
+        _ => {
+            error!("USERID: {} - Not valid", req.user_id);
+            send_user_mismatch(socket).await;
+            return Ok(());
+        }
+    }
 
-    command_handler(&proxy, &mut socket).await;
+    let limiters = proxy_read.limiters(
+        &proxy_read.connection_limiter(),
+        user.as_ref(),
+        stats,
+    );
+    drop(proxy_read);
 
-    let counter =
-        counter!("some_metric_name", "service" => "http");
-    counter.increment(1);
-    // TODO
+    dispatch(
+        proxy,
+        socket,
+        req.into_request(),
+        &limiters,
+        rejected,
+    )
+    .await;
 
     Ok(())
 }
 
+/// Refuses a SOCKS4 USERID and closes the connection
+async fn send_user_mismatch(socket: &mut TcpStream) {
+    let unspecified =
+        SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 0);
+    let reply = Socks4Reply::from_socket_addr(
+        SOCKS4_USER_MISMATCH,
+        &unspecified,
+    );
+    send_message(socket, &reply.to_bytes()).await;
+    close_socket(socket).await;
+}
+
 async fn command_handler(
     proxy: &Arc<RwLock<Socks5Proxy>>,
     socket: &mut TcpStream,
-) {
-    // Buffer that min for read any request
-    let mut buf: [u8; 262] = [0; 262];
-
-    read_message(socket, &mut buf).await;
+    limiters: &Limiters,
+    deadline: Instant,
+    rejected: bool,
+) -> ProxyResult<()> {
+    let buf = match handshake_step(
+        deadline,
+        read_request(socket),
+    )
+    .await
+    {
+        Ok(buf) => buf,
+        Err(_e @ ProxyError::OversizedError(_)) => {
+            send_error_reply(
+                socket,
+                SOCKET5_VERSION,
+                ReplyType::GeneralFailure,
+            )
+            .await;
+            return Err(_e);
+        }
+        Err(_e) => {
+            close_socket(socket).await;
+            return Err(_e);
+        }
+    };
+    let n = buf.len();
 
-    // Read request
     let req = Request::from_bytes(&buf);
 
-    // Drop buffer
-    let _ = buf;
-
     if let Err(_e) = req {
         error!("Error while parsing request: {}", _e);
-        close_socket(socket).await;
-        return;
+        let reply = if n > 1
+            && CommandType::from_byte(buf[1]).is_err()
+        {
+            ReplyType::CommandNotSupported
+        } else if n > 3
+            && AddressType::from_byte(buf[3]).is_err()
+        {
+            ReplyType::AddressTypeNotSupported
+        } else {
+            ReplyType::GeneralFailure
+        };
+        send_error_reply(socket, SOCKET5_VERSION, reply)
+            .await;
+        return Ok(());
     }
 
     // Safe Unwrap
     let req = req.unwrap();
 
-    match req.cmd {
-        CommandType::Connect => {
-            // Replay for accepting
-            let reply = Reply::new(
-                ReplyType::Succeeded,
-                req.atyp.clone(),
-                req.dst_addr.clone(),
-                req.dst_port,
-            );
+    dispatch(proxy, socket, req, limiters, rejected).await;
+
+    Ok(())
+}
 
-            send_message(socket, &reply.to_bytes()).await;
+/// Runs a parsed request, SOCKS4 ones included
+async fn dispatch(
+    proxy: &Arc<RwLock<Socks5Proxy>>,
+    socket: &mut TcpStream,
+    req: Request,
+    limiters: &Limiters,
+    rejected: bool,
+) {
+    if rejected || limiters.quota_exceeded() {
+        error!("Connection limit or user quota exceeded");
+        send_error_reply(
+            socket,
+            req.version,
+            ReplyType::ConnectionNotAllowed,
+        )
+        .await;
+        return;
+    }
 
-            // Connect two stream
-            cmd_connect_handler(&proxy, socket, req).await;
+    match req.cmd {
+        CommandType::Connect => {
+            // Connect two stream, replies once the outbound
+            // connection is up
+            cmd_connect_handler(
+                proxy, socket, req, limiters,
+            )
+            .await;
         }
         CommandType::Bind => {
-            cmd_bind_handler(&proxy, socket, req).await;
+            cmd_bind_handler(proxy, socket, req, limiters)
+                .await;
+        }
+        CommandType::UdpAssociate => {
+            cmd_udp_associate(proxy, socket, req, limiters)
+                .await;
         }
-        CommandType::UdpAssociate => {}
     }
 }
 
@@ -396,195 +1062,641 @@ async fn cmd_connect_handler(
     proxy: &Arc<RwLock<Socks5Proxy>>,
     socket: &mut TcpStream,
     req: Request,
+    limiters: &Limiters,
 ) {
-    let Some(adrs) = req.dst_socket_addr else {
-        close_socket(socket).await;
+    let proxy_read = proxy.read().await;
+
+    // Denied host names are not even resolved
+    let host = req.domain();
+    if let Some(host) = &host {
+        let dst = Destination::host(host, req.dst_port);
+        if !proxy_read.policy.allows(&dst).await {
+            error!("Denied host: {}", host);
+            send_error_reply(
+                socket,
+                req.version,
+                ReplyType::ConnectionNotAllowed,
+            )
+            .await;
+            return;
+        }
+    }
+
+    let addrs =
+        match resolve_target(&proxy_read.resolver, &req)
+            .await
+        {
+            Ok(addrs) => addrs,
+            Err(_e) => {
+                error!("{}", _e);
+                send_error_reply(
+                    socket,
+                    req.version,
+                    ReplyType::HostUnreachable,
+                )
+                .await;
+                return;
+            }
+        };
+
+    // Blocked addresses are skipped, a domain may still
+    // have others
+    let mut allowed = Vec::with_capacity(addrs.len());
+    for adrs in addrs {
+        let dst =
+            Destination::resolved(host.as_deref(), &adrs);
+        if proxy_read.policy.allows(&dst).await {
+            allowed.push(adrs);
+        } else {
+            error!("Blocked address: {}", adrs);
+        }
+    }
+    if allowed.is_empty() {
+        send_error_reply(
+            socket,
+            req.version,
+            ReplyType::ConnectionNotAllowed,
+        )
+        .await;
+        return;
+    }
+
+    if !proxy_read.has_bandwith() {
+        error!("Proxy has not have limit of bandwith");
+        send_error_reply(
+            socket,
+            req.version,
+            ReplyType::ConnectionNotAllowed,
+        )
+        .await;
+        return;
+    }
+
+    let options = proxy_read.connect_options();
+    let timeouts = *proxy_read.timeouts.lock();
+    drop(proxy_read);
+
+    let connect = time::timeout(
+        timeouts.connect(),
+        connect_happy_eyeballs(&allowed, &options),
+    );
+    let remote_socket = match connect
+        .await
+        .unwrap_or_else(|_| Err(timed_out("connect")))
+    {
+        Ok(remote_socket) => remote_socket,
+        Err(_e) => {
+            error!(
+                "Can not connect to {:?}: {}",
+                allowed, _e
+            );
+            send_error_reply(
+                socket,
+                req.version,
+                ReplyType::from_io_error(&_e),
+            )
+            .await;
+            return;
+        }
+    };
+
+    // Address the proxy connects from
+    let Ok(bind_addrs) = remote_socket.local_addr() else {
+        send_error_reply(
+            socket,
+            req.version,
+            ReplyType::GeneralFailure,
+        )
+        .await;
         return;
     };
+    send_reply(
+        socket,
+        req.version,
+        ReplyType::Succeeded,
+        &bind_addrs,
+    )
+    .await;
+
+    if let Err(_e) = copy_bidirectional_timeout(
+        socket,
+        remote_socket,
+        limiters,
+        &timeouts,
+    )
+    .await
+    {
+        info!("Relay closed: {}", _e);
+    }
 
+    // Handle results if needed
+}
 
+/// Waits for one connection from the peer named in DST.ADDR
+/// as described in RFC 1928 section 4.
+///
+/// The first reply carries the address the proxy listens
+/// on, the second one the address of the peer that
+/// connected. Connections from other addresses are dropped,
+/// the port is not compared since a peer such as an FTP
+/// server connects from another port than the one the
+/// client knows. An unspecified DST.ADDR accepts any peer.
+async fn cmd_bind_handler(
+    proxy: &Arc<RwLock<Socks5Proxy>>,
+    socket: &mut TcpStream,
+    req: Request,
+    limiters: &Limiters,
+) {
     let proxy_read = proxy.read().await;
 
-    // Check blocked address
-    if proxy_read.blocked_ippaddr.read().await.contains(&adrs){
-        // TODO send message
-        return ;
+    // Denied host names are not even resolved
+    let host = req.domain();
+    if let Some(host) = &host {
+        let dst = Destination::host(host, req.dst_port);
+        if !proxy_read.policy.allows(&dst).await {
+            error!("Denied BIND host: {}", host);
+            send_error_reply(
+                socket,
+                req.version,
+                ReplyType::ConnectionNotAllowed,
+            )
+            .await;
+            return;
+        }
     }
-    
 
+    let peers =
+        match resolve_target(&proxy_read.resolver, &req)
+            .await
+        {
+            Ok(addrs) => addrs,
+            Err(_e) => {
+                error!("{}", _e);
+                send_error_reply(
+                    socket,
+                    req.version,
+                    ReplyType::HostUnreachable,
+                )
+                .await;
+                return;
+            }
+        };
 
-    let Ok(remote_socket) = TcpStream::connect(adrs).await
-    else {
-        // TODO
-        // Return error message
+    // An unspecified address is no destination, the peer
+    // that connects is checked instead
+    let any_peer =
+        peers.iter().any(|a| a.ip().is_unspecified());
+    let mut expected = Vec::with_capacity(peers.len());
+    for adrs in peers {
+        let dst =
+            Destination::resolved(host.as_deref(), &adrs);
+        if any_peer || proxy_read.policy.allows(&dst).await
+        {
+            expected.push(adrs.ip());
+        } else {
+            error!("Blocked BIND peer: {}", adrs);
+        }
+    }
+    if expected.is_empty() {
+        send_error_reply(
+            socket,
+            req.version,
+            ReplyType::ConnectionNotAllowed,
+        )
+        .await;
         return;
-    };
-
+    }
 
     if !proxy_read.has_bandwith() {
         error!("Proxy has not have limit of bandwith");
+        send_error_reply(
+            socket,
+            req.version,
+            ReplyType::ConnectionNotAllowed,
+        )
+        .await;
         return;
     }
 
-    let bandwith = proxy_read.bandwith();
+    let options = *proxy_read.bind_options.lock();
+    let timeouts = *proxy_read.timeouts.lock();
+    let policy = Arc::clone(&proxy_read.policy);
+    drop(proxy_read);
 
-    let (mut src_read, mut src_write) = io::split(socket);
-    let (mut dest_read, mut dest_write) =
-        io::split(remote_socket);
+    let Ok(local_addrs) = socket.local_addr() else {
+        send_error_reply(
+            socket,
+            req.version,
+            ReplyType::GeneralFailure,
+        )
+        .await;
+        return;
+    };
 
-    let copy_to_destination = async {
-        if let Ok(usage) =
-            io::copy(&mut src_read, &mut dest_write).await
-        {
-            update_bandwith_usage(bandwith.clone(), usage);
+    let (listener, bound) =
+        match options.listen(local_addrs.ip()).await {
+            Ok(listening) => listening,
+            Err(_e) => {
+                error!("Can not listen for BIND: {}", _e);
+                send_error_reply(
+                    socket,
+                    req.version,
+                    ReplyType::GeneralFailure,
+                )
+                .await;
+                return;
+            }
         };
+
+    send_reply(
+        socket,
+        req.version,
+        ReplyType::Succeeded,
+        &bound,
+    )
+    .await;
+
+    let accept = async {
+        loop {
+            let (remote_socket, remote_addrs) =
+                listener.accept().await?;
+
+            // Checked as the destination the client asked
+            // for, the peer connects from any port
+            let ip = remote_addrs.ip();
+            let peer = SocketAddr::new(ip, req.dst_port);
+            if (any_peer || expected.contains(&ip))
+                && policy
+                    .allows(&Destination::resolved(
+                        host.as_deref(),
+                        &peer,
+                    ))
+                    .await
+            {
+                return Ok((remote_socket, remote_addrs));
+            }
+
+            info!(
+                "BIND on {} dropped unexpected peer {}",
+                bound, remote_addrs
+            );
+        }
     };
 
-    let copy_to_source = async {
-        if let Ok(usage) =
-            io::copy(&mut dest_read, &mut src_write).await
-        {
-            update_bandwith_usage(bandwith.clone(), usage);
-        };
+    let accepted = tokio::select! {
+        accepted = time::timeout(options.accept_timeout(), accept) => {
+            accepted.unwrap_or_else(|_| Err(timed_out("bind_accept")))
+        }
+        // Nothing to wait for once the client is gone
+        _ = wait_closed(socket) => {
+            info!("BIND on {} abandoned by the client", bound);
+            return;
+        }
     };
 
-    tokio::join!(copy_to_destination, copy_to_source);
+    let (remote_socket, remote_addrs) = match accepted {
+        Ok(accepted) => accepted,
+        Err(_e) => {
+            error!("BIND on {} failed: {}", bound, _e);
+            send_error_reply(
+                socket,
+                req.version,
+                ReplyType::from_io_error(&_e),
+            )
+            .await;
+            return;
+        }
+    };
+    drop(listener);
 
-    // Handle results if needed
+    send_reply(
+        socket,
+        req.version,
+        ReplyType::Succeeded,
+        &remote_addrs,
+    )
+    .await;
+
+    if let Err(_e) = copy_bidirectional_timeout(
+        socket,
+        remote_socket,
+        limiters,
+        &timeouts,
+    )
+    .await
+    {
+        info!("Relay closed: {}", _e);
+    }
 }
 
-async fn cmd_bind_handler(
+/// Resolves once the client closed the connection, data it
+/// sends is left in the socket
+async fn wait_closed(socket: &TcpStream) {
+    let mut buf = [0; 1];
+    match socket.peek(&mut buf).await {
+        Ok(0) | Err(_) => {}
+        Ok(_) => std::future::pending().await,
+    }
+}
+
+/// Relays UDP datagrams for a client as described in RFC 1928 section 7.
+///
+/// A relay socket is bound on the interface the client connected to and its
+/// address is sent back as BND.ADDR/BND.PORT. Datagrams coming from the client
+/// are decapsulated and forwarded to their DST.ADDR, datagrams coming from
+/// anywhere else are wrapped in a UDP header and sent back to the client.
+///
+/// The association lives as long as the controlling TCP connection, once the
+/// client closes it (or sends anything on it) the relay socket is dropped.
+async fn cmd_udp_associate(
     proxy: &Arc<RwLock<Socks5Proxy>>,
     socket: &mut TcpStream,
     req: Request,
+    limiters: &Limiters,
 ) {
-    let Some(bind_addrs) = req.dst_socket_addr else {
-        // TODO
-        // Handle
+    let (Ok(local_addrs), Ok(peer_addrs)) =
+        (socket.local_addr(), socket.peer_addr())
+    else {
+        close_socket(socket).await;
         return;
     };
 
-    let Ok(listener) = TcpListener::bind(bind_addrs).await
+    let Ok(relay) = UdpSocket::bind(SocketAddr::new(
+        local_addrs.ip(),
+        0,
+    ))
+    .await
     else {
-        // TODO
-        // Handle
+        let reply = Reply::new(
+            ReplyType::GeneralFailure,
+            req.atyp.clone(),
+            req.dst_addr.clone(),
+            req.dst_port,
+        );
+        send_message(socket, &reply.to_bytes()).await;
+        close_socket(socket).await;
         return;
     };
 
-    let Ok(local_addrs) = listener.local_addr() else {
-        // TODO
-        // Handle
+    let Ok(relay_addrs) = relay.local_addr() else {
+        close_socket(socket).await;
         return;
     };
 
-    // Return accept
-    let reply = Reply::new(
+    let reply = Reply::from_socket_addr(
         ReplyType::Succeeded,
-        req.atyp.clone(),
-        req.dst_addr.clone(),
-        req.dst_port,
+        &relay_addrs,
     );
     send_message(socket, &reply.to_bytes()).await;
 
-    // Accept connection
-    let Ok((remote_socket, remote_addr)) =
-        listener.accept().await
-    else {
-        // TODO
-        // Handle
-        return;
-    };
-
-    let addrs = match remote_addr.ip() {
-        IpAddr::V4(ipv4) => ipv4.octets().to_vec(),
-        IpAddr::V6(ipv6) => ipv6.octets().to_vec(),
-    };
-
-    let reply = Reply::new(
-        ReplyType::Succeeded,
-        req.atyp.clone(),
-        addrs,
-        remote_addr.port(),
+    info!(
+        "UDP association for {} relaying on {}",
+        peer_addrs, relay_addrs
     );
-    send_message(socket, &reply.to_bytes()).await;
 
-    let proxy_read = proxy.read().await;
+    // Client may announce the address it will send from,
+    // zeros mean it is not known yet
+    let mut client_addrs =
+        req.dst_socket_addr.filter(|a| {
+            !a.ip().is_unspecified() && a.port() != 0
+        });
 
-    if !proxy_read.has_bandwith() {
-        error!("Proxy has not have limit of bandwith");
-        return;
-    }
+    let (policy, resolver, timeouts) = {
+        let proxy_read = proxy.read().await;
+        let timeouts = *proxy_read.timeouts.lock();
+        (
+            Arc::clone(&proxy_read.policy),
+            Arc::clone(&proxy_read.resolver),
+            timeouts,
+        )
+    };
 
-    let bandwith = proxy_read.bandwith();
+    let mut control_buf: [u8; 1] = [0; 1];
+    let mut buf = vec![0u8; UDP_MAX_DATAGRAM];
+
+    // Datagrams in either direction keep the association
+    let started = Instant::now();
+    let mut last_datagram = started;
+
+    loop {
+        let idle = async {
+            match timeouts.idle() {
+                Some(idle) => {
+                    time::sleep_until(last_datagram + idle)
+                        .await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        let lifetime = async {
+            match timeouts.max_lifetime() {
+                Some(lifetime) => {
+                    time::sleep_until(started + lifetime)
+                        .await
+                }
+                None => std::future::pending().await,
+            }
+        };
 
-    let (mut src_read, mut src_write) = io::split(socket);
-    let (mut dest_read, mut dest_write) =
-        io::split(remote_socket);
+        tokio::select! {
+            // Any data or EOF on the control connection ends the association
+            _ = socket.read(&mut control_buf) => break,
+
+            _ = idle => {
+                info!(
+                    "UDP association for {}: {}",
+                    peer_addrs,
+                    timed_out("idle")
+                );
+                break;
+            }
 
-    let copy_to_destination = async {
-        if let Ok(usage) =
-            io::copy(&mut src_read, &mut dest_write).await
-        {
-            update_bandwith_usage(bandwith.clone(), usage);
-        };
-    };
+            _ = lifetime => {
+                info!(
+                    "UDP association for {}: {}",
+                    peer_addrs,
+                    timed_out("max_lifetime")
+                );
+                break;
+            }
 
-    let copy_to_source = async {
-        if let Ok(usage) =
-            io::copy(&mut dest_read, &mut src_write).await
-        {
-            update_bandwith_usage(bandwith.clone(), usage);
-        };
-    };
+            received = relay.recv_from(&mut buf) => {
+                let Ok((len, from)) = received else {
+                    continue;
+                };
+                last_datagram = Instant::now();
+
+                let from_client = match client_addrs {
+                    Some(client) => client == from,
+                    None => from.ip() == peer_addrs.ip(),
+                };
+
+                if from_client {
+                    client_addrs.get_or_insert(from);
+
+                    let Ok(udp_req) =
+                        UdpRequest::from_bytes(&buf[..len])
+                    else {
+                        continue;
+                    };
+
+                    // Fragmentation is not supported, drop
+                    if udp_req.frag != 0 {
+                        continue;
+                    }
+
+                    let host = udp_req.domain();
+                    if let Some(host) = &host {
+                        let dst = Destination::host(host, udp_req.dst_port);
+                        if !policy.allows(&dst).await {
+                            continue;
+                        }
+                    }
+
+                    let Some(target) =
+                        resolve_udp_target(&resolver, &udp_req).await
+                    else {
+                        continue;
+                    };
+
+                    let dst = Destination::resolved(host.as_deref(), &target);
+                    if !policy.allows(&dst).await {
+                        continue;
+                    }
+
+                    if limiters.exhausted() {
+                        info!("Limit reached, closing UDP association");
+                        break;
+                    }
+
+                    limiters
+                        .consume(udp_req.data.len() as u64)
+                        .await;
+
+                    let _ = relay.send_to(&udp_req.data, target).await;
+                } else if let Some(client) = client_addrs {
+                    let udp_reply = UdpReply::from_socket_addr(
+                        from,
+                        buf[..len].to_vec(),
+                    );
+
+                    if limiters.exhausted() {
+                        info!("Limit reached, closing UDP association");
+                        break;
+                    }
+
+                    limiters.consume(len as u64).await;
+
+                    let _ = relay
+                        .send_to(&udp_reply.to_bytes(), client)
+                        .await;
+                }
+            }
+        }
+    }
 
-    tokio::join!(copy_to_destination, copy_to_source);
+    info!("UDP association for {} closed", peer_addrs);
 }
 
-pub async fn cmd_udp_associate(
-    socket: &mut TcpStream,
-    req: Request,
-) {
-    // TODO
+/// Resolves DST.ADDR/DST.PORT of a UDP request header
+async fn resolve_udp_target(
+    resolver: &Resolver,
+    req: &UdpRequest,
+) -> Option<SocketAddr> {
+    match req.atyp {
+        AddressType::IPv4 => {
+            let octets: [u8; 4] =
+                req.dst_addr.as_slice().try_into().ok()?;
+            Some(SocketAddr::new(
+                IpAddr::from(octets),
+                req.dst_port,
+            ))
+        }
+        AddressType::IPv6 => {
+            let octets: [u8; 16] =
+                req.dst_addr.as_slice().try_into().ok()?;
+            Some(SocketAddr::new(
+                IpAddr::from(octets),
+                req.dst_port,
+            ))
+        }
+        AddressType::DomainName => {
+            let domain = req.domain()?;
+            resolver
+                .resolve(&domain, req.dst_port)
+                .await
+                .ok()?
+                .into_iter()
+                .next()
+        }
+    }
 }
 
-fn parse_user_pass(buf: &[u8]) -> (String, String) {
-    let username_length = buf[1] as usize;
-    let password_length = buf[2 + username_length] as usize;
+async fn send_message(socket: &mut TcpStream, msg: &[u8]) {
+    if let Err(_e) = socket.write(msg).await {
+        error!("Socket response writing error: {}", _e);
+        return;
+    }
+}
 
-    // Correctly slice the buffer to extract username and password as Vec<u8>
-    let username = buf[2..2 + username_length].to_vec();
-    let password = buf[3 + username_length
-        ..3 + username_length + password_length]
-        .to_vec();
+/// Handshake read that has to be done before `deadline`
+async fn handshake_step<T>(
+    deadline: Instant,
+    step: impl Future<Output = ProxyResult<T>>,
+) -> ProxyResult<T> {
+    time::timeout_at(deadline, step).await.unwrap_or_else(
+        |_| {
+            Err(ProxyError::TimeoutError(
+                timed_out("handshake").to_string(),
+            ))
+        },
+    )
+}
 
-    let (username, password) = (
-        String::from_utf8(username).unwrap_or_default(),
-        String::from_utf8(password).unwrap_or_default(),
-    );
+/// Addresses of a request target, domains are resolved
+async fn resolve_target(
+    resolver: &Resolver,
+    req: &Request,
+) -> Result<Vec<SocketAddr>, String> {
+    if let Some(addrs) = req.dst_socket_addr {
+        return Ok(vec![addrs]);
+    }
 
-    (username, password)
+    let domain = req
+        .domain()
+        .ok_or_else(|| "Invalid domain name".to_string())?;
+    resolver.resolve(&domain, req.dst_port).await
 }
 
-async fn read_message(
+/// Sends a reply in the SOCKS `version` of the request
+async fn send_reply(
     socket: &mut TcpStream,
-    buf: &mut [u8],
-) -> usize {
-    let bytes_read = match socket.read(buf).await {
-        Ok(n) => n,
-        Err(_e) => {
-            error!("Socket reading error: {}", _e);
-            close_socket(socket).await;
-            0
-        }
+    version: u8,
+    reply: ReplyType,
+    addrs: &SocketAddr,
+) {
+    let bytes = if version == SOCKET4_VERSION {
+        Socks4Reply::from_socket_addr(
+            reply.to_socks4(),
+            addrs,
+        )
+        .to_bytes()
+    } else {
+        Reply::from_socket_addr(reply, addrs).to_bytes()
     };
-    bytes_read
+    send_message(socket, &bytes).await;
 }
 
-async fn send_message(socket: &mut TcpStream, msg: &[u8]) {
-    if let Err(_e) = socket.write(msg).await {
-        error!("Socket response writing error: {}", _e);
-        return;
-    }
+/// Sends a failure reply and closes the connection
+async fn send_error_reply(
+    socket: &mut TcpStream,
+    version: u8,
+    reply: ReplyType,
+) {
+    let unspecified =
+        SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 0);
+    send_reply(socket, version, reply, &unspecified).await;
+    close_socket(socket).await;
 }
 
 async fn close_socket(socket: &mut TcpStream) {
@@ -595,9 +1707,492 @@ async fn close_socket(socket: &mut TcpStream) {
     }
 }
 
-fn update_bandwith_usage(
-    bandwith: Arc<AtomicU64>,
-    used_bandwith: u64,
-) {
-    bandwith.fetch_add(used_bandwith, Ordering::Relaxed);
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::proxies::{
+        dns::DnsConfig, utils::ssrf::SsrfProtection,
+    };
+
+    /// Proxy allowed to reach loopback targets
+    fn proxy(
+        timeouts: Timeouts,
+    ) -> Arc<RwLock<Socks5Proxy>> {
+        let proxy = Socks5Proxy::new(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::default(),
+            Resolver::new(&DnsConfig::default()),
+        );
+        proxy
+            .policy
+            .set_ssrf(SsrfProtection {
+                enabled: false,
+                exceptions: Vec::new(),
+            })
+            .unwrap();
+        *proxy.timeouts.lock() = timeouts;
+        proxy
+            .max_bandwith
+            .store(u64::MAX, Ordering::Relaxed);
+        Arc::new(RwLock::new(proxy))
+    }
+
+    /// Client side of a control connection and the server
+    /// side handed to the proxy
+    async fn control() -> (TcpStream, TcpStream) {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(
+            listener.local_addr().unwrap(),
+        )
+        .await
+        .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// Runs a UDP ASSOCIATE for `announced` and returns the
+    /// control connection, the relay address and the task
+    /// serving the association
+    async fn associate(
+        timeouts: Timeouts,
+        announced: SocketAddr,
+    ) -> (TcpStream, SocketAddr, JoinHandle<()>) {
+        let proxy = proxy(timeouts);
+        let (mut client, mut server) = control().await;
+
+        let mut bytes = vec![SOCKET5_VERSION, 0x03, 0x00];
+        bytes.extend(socks_addr(announced));
+        let req = Request::from_bytes(&bytes).unwrap();
+
+        let task = tokio::spawn(async move {
+            cmd_udp_associate(
+                &proxy,
+                &mut server,
+                req,
+                &Limiters::default(),
+            )
+            .await;
+        });
+
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [SOCKET5_VERSION, 0, 0, 1]);
+        let relay = SocketAddr::new(
+            IpAddr::from([
+                reply[4], reply[5], reply[6], reply[7],
+            ]),
+            u16::from_be_bytes([reply[8], reply[9]]),
+        );
+
+        (client, relay, task)
+    }
+
+    /// ATYP, DST.ADDR and DST.PORT of an IPv4 address
+    fn socks_addr(addrs: SocketAddr) -> Vec<u8> {
+        let IpAddr::V4(ip) = addrs.ip() else {
+            unreachable!()
+        };
+        let mut bytes = vec![0x01];
+        bytes.extend(ip.octets());
+        bytes.extend(addrs.port().to_be_bytes());
+        bytes
+    }
+
+    /// Client datagram for `target`
+    fn datagram(
+        frag: u8,
+        target: SocketAddr,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut bytes = vec![0x00, 0x00, frag];
+        bytes.extend(socks_addr(target));
+        bytes.extend(data);
+        bytes
+    }
+
+    async fn udp_socket(ip: &str) -> UdpSocket {
+        UdpSocket::bind(format!("{}:0", ip)).await.unwrap()
+    }
+
+    /// Next datagram, `None` when nothing arrives shortly
+    async fn recv(socket: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buf = [0; 1024];
+        let (len, _) = time::timeout(
+            Duration::from_millis(300),
+            socket.recv_from(&mut buf),
+        )
+        .await
+        .ok()?
+        .unwrap();
+        Some(buf[..len].to_vec())
+    }
+
+    fn unknown() -> SocketAddr {
+        "0.0.0.0:0".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn udp_relays_with_reply_header() {
+        let (_control, relay, _task) =
+            associate(Timeouts::default(), unknown()).await;
+        let client = udp_socket("127.0.0.1").await;
+        let target = udp_socket("127.0.0.1").await;
+        let target_addrs = target.local_addr().unwrap();
+
+        client
+            .send_to(
+                &datagram(0, target_addrs, b"ping"),
+                relay,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            recv(&target).await.as_deref(),
+            Some(&b"ping"[..])
+        );
+
+        target.send_to(b"pong", relay).await.unwrap();
+        assert_eq!(
+            recv(&client).await,
+            Some(datagram(0, target_addrs, b"pong"))
+        );
+    }
+
+    #[tokio::test]
+    async fn udp_drops_fragments() {
+        let (_control, relay, _task) =
+            associate(Timeouts::default(), unknown()).await;
+        let client = udp_socket("127.0.0.1").await;
+        let target = udp_socket("127.0.0.1").await;
+        let target_addrs = target.local_addr().unwrap();
+
+        for (frag, data) in [
+            (1, &b"first"[..]),
+            (0, &b"second"[..]),
+        ] {
+            client
+                .send_to(
+                    &datagram(frag, target_addrs, data),
+                    relay,
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            recv(&target).await.as_deref(),
+            Some(&b"second"[..])
+        );
+        assert_eq!(recv(&target).await, None);
+    }
+
+    #[tokio::test]
+    async fn udp_source_locked_to_client() {
+        let (_control, relay, _task) =
+            associate(Timeouts::default(), unknown()).await;
+        let client = udp_socket("127.0.0.1").await;
+        let target = udp_socket("127.0.0.1").await;
+        let target_addrs = target.local_addr().unwrap();
+
+        // Another host can not take the association over
+        // before the client sent anything
+        let stranger = udp_socket("127.0.0.2").await;
+        stranger
+            .send_to(
+                &datagram(0, target_addrs, b"stranger"),
+                relay,
+            )
+            .await
+            .unwrap();
+        assert_eq!(recv(&target).await, None);
+
+        client
+            .send_to(
+                &datagram(0, target_addrs, b"client"),
+                relay,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            recv(&target).await.as_deref(),
+            Some(&b"client"[..])
+        );
+
+        // Once locked, another port of the client host is
+        // a remote peer and its datagrams go to the client
+        let other = udp_socket("127.0.0.1").await;
+        let other_addrs = other.local_addr().unwrap();
+        other
+            .send_to(
+                &datagram(0, target_addrs, b"other"),
+                relay,
+            )
+            .await
+            .unwrap();
+        assert_eq!(recv(&target).await, None);
+        assert_eq!(
+            recv(&client).await,
+            Some(datagram(
+                0,
+                other_addrs,
+                &datagram(0, target_addrs, b"other"),
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn udp_announced_source_is_used() {
+        let client = udp_socket("127.0.0.1").await;
+        let (_control, relay, _task) = associate(
+            Timeouts::default(),
+            client.local_addr().unwrap(),
+        )
+        .await;
+        let target = udp_socket("127.0.0.1").await;
+        let target_addrs = target.local_addr().unwrap();
+
+        let other = udp_socket("127.0.0.1").await;
+        other
+            .send_to(
+                &datagram(0, target_addrs, b"other"),
+                relay,
+            )
+            .await
+            .unwrap();
+        assert_eq!(recv(&target).await, None);
+
+        client
+            .send_to(
+                &datagram(0, target_addrs, b"client"),
+                relay,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            recv(&target).await.as_deref(),
+            Some(&b"client"[..])
+        );
+    }
+
+    /// Seconds until the association ended by itself
+    async fn teardown(timeouts: Timeouts) -> u64 {
+        let (mut control, _relay, task) =
+            associate(timeouts, unknown()).await;
+        let started = Instant::now();
+
+        time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("association not closed")
+            .unwrap();
+        // The control connection goes with it
+        assert_eq!(
+            control.read(&mut [0; 1]).await.unwrap(),
+            0
+        );
+
+        started.elapsed().as_secs()
+    }
+
+    #[tokio::test]
+    async fn udp_idle_timeout_closes() {
+        let timeouts = Timeouts {
+            idle: 1,
+            ..Default::default()
+        };
+        assert_eq!(teardown(timeouts).await, 1);
+    }
+
+    #[tokio::test]
+    async fn udp_max_lifetime_closes() {
+        let timeouts = Timeouts {
+            idle: 0,
+            max_lifetime: 1,
+            ..Default::default()
+        };
+        assert_eq!(teardown(timeouts).await, 1);
+    }
+
+    #[tokio::test]
+    async fn udp_control_close_ends_association() {
+        let (control, _relay, task) =
+            associate(Timeouts::default(), unknown()).await;
+        drop(control);
+
+        time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("association not closed")
+            .unwrap();
+    }
+
+    /// Reads a SOCKS5 reply for an IPv4 address, its code
+    /// and address
+    async fn read_reply(
+        client: &mut TcpStream,
+    ) -> (u8, SocketAddr) {
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[0], SOCKET5_VERSION);
+        assert_eq!(reply[3], 0x01);
+        let addrs = SocketAddr::new(
+            IpAddr::from([
+                reply[4], reply[5], reply[6], reply[7],
+            ]),
+            u16::from_be_bytes([reply[8], reply[9]]),
+        );
+        (reply[1], addrs)
+    }
+
+    /// Runs a BIND for peers at `expected` and returns the
+    /// control connection, the address listened on and the
+    /// task serving the request
+    async fn bind(
+        options: BindOptions,
+        expected: &str,
+    ) -> (TcpStream, SocketAddr, JoinHandle<()>) {
+        let proxy = proxy(Timeouts::default());
+        *proxy.read().await.bind_options.lock() = options;
+        let (mut client, mut server) = control().await;
+
+        let mut bytes = vec![SOCKET5_VERSION, 0x02, 0x00];
+        bytes.extend(socks_addr(expected.parse().unwrap()));
+        let req = Request::from_bytes(&bytes).unwrap();
+
+        let task = tokio::spawn(async move {
+            cmd_bind_handler(
+                &proxy,
+                &mut server,
+                req,
+                &Limiters::default(),
+            )
+            .await;
+        });
+
+        let (code, bound) = read_reply(&mut client).await;
+        assert_eq!(code, ReplyType::Succeeded.to_byte());
+
+        (client, bound, task)
+    }
+
+    /// Connection to `bound` from `ip`
+    async fn connect_from(
+        ip: &str,
+        bound: SocketAddr,
+    ) -> TcpStream {
+        let socket =
+            tokio::net::TcpSocket::new_v4().unwrap();
+        socket
+            .bind(format!("{}:0", ip).parse().unwrap())
+            .unwrap();
+        socket.connect(bound).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn bind_replies_twice_and_relays() {
+        let (mut client, bound, _task) =
+            bind(BindOptions::default(), "127.0.0.1:0")
+                .await;
+        assert_eq!(
+            bound.ip(),
+            IpAddr::from([127, 0, 0, 1])
+        );
+
+        let mut peer =
+            connect_from("127.0.0.1", bound).await;
+        let (code, remote) = read_reply(&mut client).await;
+        assert_eq!(code, ReplyType::Succeeded.to_byte());
+        assert_eq!(remote, peer.local_addr().unwrap());
+
+        peer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        client.write_all(b"pong").await.unwrap();
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[tokio::test]
+    async fn bind_drops_unexpected_peer() {
+        let (mut client, bound, _task) =
+            bind(BindOptions::default(), "127.0.0.2:0")
+                .await;
+
+        // Closed without a reply to the client
+        let mut stranger =
+            connect_from("127.0.0.1", bound).await;
+        assert_eq!(
+            stranger.read(&mut [0; 1]).await.unwrap(),
+            0
+        );
+
+        let peer = connect_from("127.0.0.2", bound).await;
+        let (code, remote) = read_reply(&mut client).await;
+        assert_eq!(code, ReplyType::Succeeded.to_byte());
+        assert_eq!(remote, peer.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn bind_accept_timeout() {
+        let options = BindOptions {
+            accept_timeout: 1,
+            ..Default::default()
+        };
+        let (mut client, _bound, task) =
+            bind(options, "127.0.0.1:0").await;
+
+        let (code, _) = read_reply(&mut client).await;
+        assert_eq!(code, ReplyType::TtlExpired.to_byte());
+        assert_eq!(
+            client.read(&mut [0; 1]).await.unwrap(),
+            0
+        );
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_relay_releases_proxy() {
+        let proxy = proxy(Timeouts::default());
+        let target =
+            TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, mut server) = control().await;
+
+        let mut bytes = vec![SOCKET5_VERSION, 0x01, 0x00];
+        bytes.extend(socks_addr(
+            target.local_addr().unwrap(),
+        ));
+        let req = Request::from_bytes(&bytes).unwrap();
+
+        let relayed = Arc::clone(&proxy);
+        tokio::spawn(async move {
+            cmd_connect_handler(
+                &relayed,
+                &mut server,
+                req,
+                &Limiters::default(),
+            )
+            .await;
+        });
+
+        let (code, _) = read_reply(&mut client).await;
+        assert_eq!(code, ReplyType::Succeeded.to_byte());
+        let (mut remote, _) =
+            target.accept().await.unwrap();
+
+        // The proxy can be changed while the relay runs
+        let _write = time::timeout(
+            Duration::from_secs(1),
+            proxy.write(),
+        )
+        .await
+        .expect("proxy locked by the relay");
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
 }
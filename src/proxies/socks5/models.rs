@@ -1,9 +1,14 @@
 use std::convert::TryInto;
-use std::net::ToSocketAddrs;
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 
-use tracing::{debug, error, info};
+use tracing::{debug, info};
+
+use super::constant::{
+    SOCKET4_VERSION, SOCKS4_GRANTED, SOCKS4_REJECTED,
+    SOCKS4_REPLY_VERSION, USER_PASS_VERSION,
+};
 
 #[derive(Debug)]
 pub enum Commands {
@@ -130,6 +135,36 @@ impl ReplyType {
             )),
         }
     }
+
+    /// SOCKS4 has a single failure code
+    pub fn to_socks4(&self) -> u8 {
+        match self {
+            ReplyType::Succeeded => SOCKS4_GRANTED,
+            _ => SOCKS4_REJECTED,
+        }
+    }
+
+    /// Reply for a failed outbound connection
+    pub fn from_io_error(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => {
+                ReplyType::ConnectionRefused
+            }
+            io::ErrorKind::HostUnreachable => {
+                ReplyType::HostUnreachable
+            }
+            io::ErrorKind::NetworkUnreachable => {
+                ReplyType::NetworkUnreachable
+            }
+            io::ErrorKind::TimedOut => {
+                ReplyType::TtlExpired
+            }
+            io::ErrorKind::PermissionDenied => {
+                ReplyType::ConnectionNotAllowed
+            }
+            _ => ReplyType::GeneralFailure,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -159,6 +194,21 @@ impl AddressType {
             )),
         }
     }
+
+    /// Address type and raw address bytes of a socket
+    /// address, as used in BND.ADDR and UDP headers.
+    pub fn from_socket_addr(
+        addr: &SocketAddr,
+    ) -> (Self, Vec<u8>) {
+        match addr.ip() {
+            IpAddr::V4(ipv4) => {
+                (AddressType::IPv4, ipv4.octets().to_vec())
+            }
+            IpAddr::V6(ipv6) => {
+                (AddressType::IPv6, ipv6.octets().to_vec())
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -304,6 +354,115 @@ impl AuthReply {
     }
 }
 
+/// +----+------+----------+------+----------+
+/// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+/// +----+------+----------+------+----------+
+/// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+/// +----+------+----------+------+----------+
+#[derive(Debug)]
+pub struct UserPassRequest {
+    pub username: String,
+    pub password: String,
+}
+
+impl UserPassRequest {
+    pub fn from_bytes(
+        bytes: &[u8],
+    ) -> Result<Self, String> {
+        if bytes.len() < 2 {
+            return Err(
+                "Not enough bytes for UserPassRequest"
+                    .to_string(),
+            );
+        }
+
+        let version = bytes[0];
+        if version != USER_PASS_VERSION {
+            return Err(format!(
+                "Unsupported sub-negotiation version: {}",
+                version
+            ));
+        }
+
+        // RFC 1929 fields are 1 to 255 bytes long
+        let ulen = bytes[1] as usize;
+        if ulen == 0 {
+            return Err("Empty UNAME".to_string());
+        }
+        // UNAME and PLEN
+        if bytes.len() < 3 + ulen {
+            return Err(
+                "Not enough bytes for UNAME".to_string()
+            );
+        }
+        let username = &bytes[2..2 + ulen];
+
+        let plen = bytes[2 + ulen] as usize;
+        if plen == 0 {
+            return Err("Empty PASSWD".to_string());
+        }
+        if bytes.len() < 3 + ulen + plen {
+            return Err(
+                "Not enough bytes for PASSWD".to_string()
+            );
+        }
+        let password = &bytes[3 + ulen..3 + ulen + plen];
+
+        let username = String::from_utf8(username.to_vec())
+            .map_err(|_e| {
+                format!("Invalid UNAME: {}", _e)
+            })?;
+        let password = String::from_utf8(password.to_vec())
+            .map_err(|_e| {
+                format!("Invalid PASSWD: {}", _e)
+            })?;
+
+        Ok(Self { username, password })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserPassStatus {
+    Success,
+    Failure,
+}
+
+impl UserPassStatus {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            UserPassStatus::Success => 0x00,
+            UserPassStatus::Failure => 0x01,
+        }
+    }
+}
+
+/// +----+--------+
+/// |VER | STATUS |
+/// +----+--------+
+/// | 1  |   1    |
+/// +----+--------+
+#[derive(Debug)]
+pub struct UserPassReply {
+    version: u8,
+    status: UserPassStatus,
+}
+
+impl UserPassReply {
+    pub fn new(status: UserPassStatus) -> Self {
+        Self {
+            version: USER_PASS_VERSION,
+            status,
+        }
+    }
+
+    pub fn to_byte(&self) -> Vec<u8> {
+        vec![
+            self.version,
+            self.status.to_byte(),
+        ]
+    }
+}
+
 /// +----+-----+-------+------+----------+----------+
 /// |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
 /// +----+-----+-------+------+----------+----------+
@@ -318,7 +477,7 @@ pub struct Request {
     pub dst_addr: Vec<u8>,
     pub dst_port: u16,
 
-    // dst_socket
+    // Only set for IPv4 and IPv6 targets
     pub dst_socket_addr: Option<SocketAddr>,
 }
 
@@ -356,7 +515,6 @@ impl Request {
     pub fn from_bytes(
         bytes: &[u8],
     ) -> Result<Self, String> {
-
         // Check if there are enough bytes to read
         if bytes.len() < 7 {
             return Err(
@@ -415,7 +573,7 @@ impl Request {
         };
 
         // Check if we have enough bytes for the destination address and port
-        let required_length = 7 + dst_addr_length;
+        let required_length = 6 + dst_addr_length;
         if bytes.len() < required_length {
             return Err(format!(
                 "Not enough bytes for destination address: required {}, found {}",
@@ -472,36 +630,8 @@ impl Request {
                     Some(SocketAddr::new(addrs, dst_port))
                 }
             }
-            AddressType::DomainName => {
-                if let Ok(domain) =
-                    String::from_utf8(dst_addr.to_vec())
-                {
-                    let addr = format!(
-                        "{}:{}",
-                        domain.trim(),
-                        dst_port
-                    );
-
-                    match addr.to_socket_addrs() {
-                        Ok(mut addrs) => {
-                            if let Some(socket_addr) =
-                                addrs.next()
-                            {
-                                Some(socket_addr)
-                            } else {
-                                error!("No addresses found for domain: {}", addr);
-                                None
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to resolve socket address: {}", e);
-                            None
-                        }
-                    }
-                } else {
-                    None
-                }
-            }
+            // Resolved by the proxy, see `domain`
+            AddressType::DomainName => None,
         };
 
         Ok(Self {
@@ -515,6 +645,17 @@ impl Request {
             dst_socket_addr: socket_addr,
         })
     }
+
+    /// Target domain name when ATYP is a domain
+    pub fn domain(&self) -> Option<String> {
+        match self.atyp {
+            AddressType::DomainName => String::from_utf8(
+                self.dst_addr.get(1..)?.to_vec(),
+            )
+            .ok(),
+            _ => None,
+        }
+    }
 }
 
 /// +----+-----+-------+------+----------+----------+
@@ -550,6 +691,17 @@ impl Reply {
         }
     }
 
+    /// Reply carrying a socket address as BND.ADDR and
+    /// BND.PORT, failures use the unspecified address
+    pub fn from_socket_addr(
+        reply: ReplyType,
+        addr: &SocketAddr,
+    ) -> Self {
+        let (atyp, bnd_addr) =
+            AddressType::from_socket_addr(addr);
+        Self::new(reply, atyp, bnd_addr, addr.port())
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.push(self.version);
@@ -601,6 +753,17 @@ impl UdpRequest {
         }
     }
 
+    /// Target domain name when ATYP is a domain
+    pub fn domain(&self) -> Option<String> {
+        match self.atyp {
+            AddressType::DomainName => String::from_utf8(
+                self.dst_addr.get(1..)?.to_vec(),
+            )
+            .ok(),
+            _ => None,
+        }
+    }
+
     /// Serializes the `UdpRequest` into a byte array.
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
@@ -635,11 +798,12 @@ impl UdpRequest {
                 "Invalid address type".to_string()
             })?;
 
-        // Determine destination address length
+        // Determine destination address length, domain
+        // names keep their length prefix like `Request`
         let (dst_addr_length, addr_offset) = match atyp {
             AddressType::IPv4 => (4, 4),
             AddressType::DomainName => {
-                (bytes[4] as usize + 1, 5)
+                (bytes[4] as usize + 1, 4)
             }
             AddressType::IPv6 => (16, 4),
         };
@@ -683,6 +847,17 @@ pub struct UdpReply {
 }
 
 impl UdpReply {
+    /// Wraps a datagram received from `source` so it can be
+    /// relayed back to the client.
+    pub fn from_socket_addr(
+        source: SocketAddr,
+        data: Vec<u8>,
+    ) -> Self {
+        let (atyp, dst_addr) =
+            AddressType::from_socket_addr(&source);
+        Self::new(atyp, dst_addr, source.port(), data)
+    }
+
     /// Constructs a new `UdpReply`.
     pub fn new(
         atyp: AddressType,
@@ -716,3 +891,418 @@ impl UdpReply {
         bytes
     }
 }
+
+// SOCKS4
+
+/// +----+----+----+----+----+----+----+----+----+....+----+
+/// | VN | CD | DSTPORT |      DSTIP        | USERID  |NULL|
+/// +----+----+----+----+----+----+----+----+----+....+----+
+/// | 1  | 1  |    2    |        4          |variable | 1  |
+/// +----+----+----+----+----+----+----+----+----+....+----+
+///
+/// SOCKS4a sets DSTIP to 0.0.0.x, x not zero, and sends the
+/// domain name after USERID, NUL terminated as well.
+#[derive(Debug)]
+pub struct Socks4Request {
+    pub cmd: CommandType,
+    pub dst_port: u16,
+    pub dst_ip: Ipv4Addr,
+    pub user_id: String,
+    pub domain: Option<String>,
+}
+
+impl Socks4Request {
+    /// DSTIP of a SOCKS4a request
+    pub fn is_socks4a(ip: &Ipv4Addr) -> bool {
+        let octets = ip.octets();
+        octets[..3] == [0, 0, 0] && octets[3] != 0
+    }
+
+    /// `header` is VN to DSTIP, `user_id` and `domain`
+    /// without their NUL
+    pub fn from_parts(
+        header: &[u8],
+        user_id: &[u8],
+        domain: Option<&[u8]>,
+    ) -> Result<Self, String> {
+        if header.len() < 8 {
+            return Err(
+                "Not enough bytes for Socks4Request"
+                    .to_string(),
+            );
+        }
+        if header[0] != SOCKET4_VERSION {
+            return Err(format!(
+                "Unsupported version: {}",
+                header[0]
+            ));
+        }
+
+        // UDP ASSOCIATE does not exist in SOCKS4
+        let cmd = match header[1] {
+            0x01 => CommandType::Connect,
+            0x02 => CommandType::Bind,
+            _ => {
+                return Err(format!(
+                    "Invalid command type: {}",
+                    header[1]
+                ))
+            }
+        };
+
+        let user_id = String::from_utf8(user_id.to_vec())
+            .map_err(|_e| {
+            format!("Invalid USERID: {}", _e)
+        })?;
+        let domain = domain
+            .map(|domain| {
+                String::from_utf8(domain.to_vec()).map_err(
+                    |_e| {
+                        format!(
+                            "Invalid domain name: {}",
+                            _e
+                        )
+                    },
+                )
+            })
+            .transpose()?;
+
+        Ok(Self {
+            cmd,
+            dst_port: u16::from_be_bytes([
+                header[2], header[3],
+            ]),
+            dst_ip: Ipv4Addr::new(
+                header[4], header[5], header[6], header[7],
+            ),
+            user_id,
+            domain,
+        })
+    }
+
+    /// The same request in SOCKS5 terms, replies go out in
+    /// SOCKS4 since the version is kept
+    pub fn into_request(self) -> Request {
+        let cmd = self.cmd;
+        let mut req = match self.domain {
+            Some(domain) => {
+                let mut dst_addr = vec![domain.len() as u8];
+                dst_addr
+                    .extend_from_slice(domain.as_bytes());
+                Request::new(
+                    cmd,
+                    AddressType::DomainName,
+                    dst_addr,
+                    self.dst_port,
+                )
+            }
+            None => {
+                let mut req = Request::new(
+                    cmd,
+                    AddressType::IPv4,
+                    self.dst_ip.octets().to_vec(),
+                    self.dst_port,
+                );
+                req.dst_socket_addr =
+                    Some(SocketAddr::new(
+                        IpAddr::V4(self.dst_ip),
+                        self.dst_port,
+                    ));
+                req
+            }
+        };
+        req.version = SOCKET4_VERSION;
+        req
+    }
+}
+
+/// +----+----+----+----+----+----+----+----+
+/// | VN | CD | DSTPORT |      DSTIP        |
+/// +----+----+----+----+----+----+----+----+
+/// | 1  | 1  |    2    |        4          |
+/// +----+----+----+----+----+----+----+----+
+#[derive(Debug)]
+pub struct Socks4Reply {
+    code: u8,
+    dst_port: u16,
+    dst_ip: Ipv4Addr,
+}
+
+impl Socks4Reply {
+    /// IPv6 addresses can not be sent, 0.0.0.0 tells the
+    /// client to use the address of the proxy
+    pub fn from_socket_addr(
+        code: u8,
+        addr: &SocketAddr,
+    ) -> Self {
+        let dst_ip = match addr.ip() {
+            IpAddr::V4(ipv4) => ipv4,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        Self {
+            code,
+            dst_port: addr.port(),
+            dst_ip,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![
+            SOCKS4_REPLY_VERSION,
+            self.code,
+        ];
+        bytes.extend_from_slice(
+            &self.dst_port.to_be_bytes(),
+        );
+        bytes.extend_from_slice(&self.dst_ip.octets());
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: [u8; 8] = [
+        0x04, 0x01, 0x00, 0x50, 0x7f, 0x00, 0x00, 0x01,
+    ];
+
+    #[test]
+    fn socks4_request_from_parts() {
+        let req = Socks4Request::from_parts(
+            &HEADER, b"user", None,
+        )
+        .unwrap();
+
+        assert!(matches!(req.cmd, CommandType::Connect));
+        assert_eq!(req.dst_port, 80);
+        assert_eq!(req.dst_ip, Ipv4Addr::LOCALHOST);
+        assert_eq!(req.user_id, "user");
+        assert_eq!(req.domain, None);
+
+        let req = req.into_request();
+        assert_eq!(req.version, SOCKET4_VERSION);
+        assert_eq!(
+            req.dst_socket_addr,
+            Some("127.0.0.1:80".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn socks4a_request_from_parts() {
+        let header = [
+            0x04, 0x02, 0x01, 0xbb, 0x00, 0x00, 0x00, 0x01,
+        ];
+        let req = Socks4Request::from_parts(
+            &header,
+            b"",
+            Some(b"example.com"),
+        )
+        .unwrap();
+
+        assert!(matches!(req.cmd, CommandType::Bind));
+        assert_eq!(req.user_id, "");
+        assert_eq!(
+            req.domain.as_deref(),
+            Some("example.com")
+        );
+
+        let req = req.into_request();
+        assert!(matches!(
+            req.atyp,
+            AddressType::DomainName
+        ));
+        assert_eq!(
+            req.domain().as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(req.dst_port, 443);
+        assert_eq!(req.dst_socket_addr, None);
+    }
+
+    #[test]
+    fn invalid_socks4_request() {
+        assert!(Socks4Request::from_parts(
+            &HEADER[..7],
+            b"",
+            None
+        )
+        .is_err());
+
+        let mut header = HEADER;
+        header[0] = 0x05;
+        assert!(Socks4Request::from_parts(
+            &header, b"", None
+        )
+        .is_err());
+
+        // UDP ASSOCIATE and unknown commands
+        for cmd in [0x00, 0x03, 0xff] {
+            let mut header = HEADER;
+            header[1] = cmd;
+            assert!(
+                Socks4Request::from_parts(
+                    &header, b"", None
+                )
+                .is_err(),
+                "command {}",
+                cmd
+            );
+        }
+
+        assert!(Socks4Request::from_parts(
+            &HEADER,
+            b"\xff\xfe",
+            None
+        )
+        .is_err());
+        assert!(Socks4Request::from_parts(
+            &HEADER,
+            b"",
+            Some(b"\xff\xfe")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn socks4a_addresses() {
+        for (ip, socks4a) in [
+            ([0, 0, 0, 1], true),
+            ([0, 0, 0, 255], true),
+            ([0, 0, 0, 0], false),
+            ([0, 0, 1, 1], false),
+            ([127, 0, 0, 1], false),
+        ] {
+            assert_eq!(
+                Socks4Request::is_socks4a(&Ipv4Addr::from(
+                    ip
+                )),
+                socks4a,
+                "{:?}",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn socks4_reply_bytes() {
+        let reply = Socks4Reply::from_socket_addr(
+            SOCKS4_GRANTED,
+            &"192.0.2.1:1080".parse().unwrap(),
+        );
+        assert_eq!(
+            reply.to_bytes(),
+            [0x00, 0x5a, 0x04, 0x38, 192, 0, 2, 1]
+        );
+
+        // IPv6 addresses are sent as 0.0.0.0
+        let reply = Socks4Reply::from_socket_addr(
+            SOCKS4_REJECTED,
+            &"[2001:db8::1]:80".parse().unwrap(),
+        );
+        assert_eq!(
+            reply.to_bytes(),
+            [0x00, 0x5b, 0x00, 0x50, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn reply_types_to_socks4() {
+        for byte in 0x00..=0x08 {
+            let reply = ReplyType::from_byte(byte).unwrap();
+            let expected = match byte {
+                0x00 => 0x5a,
+                _ => 0x5b,
+            };
+            assert_eq!(
+                reply.to_socks4(),
+                expected,
+                "{:?}",
+                reply
+            );
+        }
+    }
+
+    #[test]
+    fn reply_types_from_io_errors() {
+        for (kind, reply) in [
+            (
+                io::ErrorKind::ConnectionRefused,
+                ReplyType::ConnectionRefused,
+            ),
+            (
+                io::ErrorKind::HostUnreachable,
+                ReplyType::HostUnreachable,
+            ),
+            (
+                io::ErrorKind::NetworkUnreachable,
+                ReplyType::NetworkUnreachable,
+            ),
+            (
+                io::ErrorKind::TimedOut,
+                ReplyType::TtlExpired,
+            ),
+            (
+                io::ErrorKind::PermissionDenied,
+                ReplyType::ConnectionNotAllowed,
+            ),
+            (
+                io::ErrorKind::ConnectionReset,
+                ReplyType::GeneralFailure,
+            ),
+            (
+                io::ErrorKind::Other,
+                ReplyType::GeneralFailure,
+            ),
+        ] {
+            let err = io::Error::from(kind);
+            assert_eq!(
+                ReplyType::from_io_error(&err).to_byte(),
+                reply.to_byte(),
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn user_pass_request() {
+        let req = UserPassRequest::from_bytes(
+            b"\x01\x04user\x06secret",
+        )
+        .unwrap();
+
+        assert_eq!(req.username, "user");
+        assert_eq!(req.password, "secret");
+    }
+
+    #[test]
+    fn invalid_user_pass_request() {
+        let frame = b"\x01\x04user\x06secret";
+        for len in [0, 1, 2, 5, 6, frame.len() - 1] {
+            assert!(
+                UserPassRequest::from_bytes(&frame[..len])
+                    .is_err(),
+                "{} bytes",
+                len
+            );
+        }
+
+        for frame in [
+            // Version
+            &b"\x05\x04user\x06secret"[..],
+            // Empty UNAME or PASSWD
+            b"\x01\x00\x06secret",
+            b"\x01\x04user\x00",
+            // UNAME and PASSWD not UTF-8
+            b"\x01\x02\xff\xfe\x06secret",
+            b"\x01\x04user\x02\xff\xfe",
+        ] {
+            assert!(
+                UserPassRequest::from_bytes(frame).is_err(),
+                "{:?}",
+                frame
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{io, net::TcpListener};
+
+// Seconds a BIND waits for the peer to connect
+const DEFAULT_ACCEPT_TIMEOUT: u64 = 60;
+
+// Rotates the first port tried so concurrent binds do not
+// all probe the same ones
+static NEXT_PORT: AtomicUsize = AtomicUsize::new(0);
+
+/// Ports a BIND listener may use, both ends included
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+/// Where BIND requests listen for the incoming connection
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct BindOptions {
+    // Address listened on and reported to the client, the
+    // one the client connected to when not set
+    #[serde(default)]
+    pub address: Option<IpAddr>,
+    // Any free port when not set
+    #[serde(default)]
+    pub ports: Option<PortRange>,
+    // Seconds to wait for the peer
+    #[serde(default = "default_accept_timeout")]
+    pub accept_timeout: u64,
+}
+
+fn default_accept_timeout() -> u64 {
+    DEFAULT_ACCEPT_TIMEOUT
+}
+
+impl Default for BindOptions {
+    fn default() -> Self {
+        Self {
+            address: None,
+            ports: None,
+            accept_timeout: default_accept_timeout(),
+        }
+    }
+}
+
+impl BindOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.accept_timeout == 0 {
+            return Err(
+                "accept_timeout must be greater than 0"
+                    .to_string(),
+            );
+        }
+        if let Some(ports) = &self.ports {
+            if ports.start == 0 || ports.start > ports.end {
+                return Err(format!(
+                    "invalid port range {}-{}",
+                    ports.start, ports.end
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn accept_timeout(&self) -> Duration {
+        Duration::from_secs(self.accept_timeout)
+    }
+
+    /// Listener for a BIND request of a client connected to
+    /// `local`, along with the address to report. An
+    /// unspecified `address` listens everywhere and reports
+    /// `local`.
+    pub async fn listen(
+        &self,
+        local: IpAddr,
+    ) -> io::Result<(TcpListener, SocketAddr)> {
+        let ip = self.address.unwrap_or(local);
+
+        let listener = match self.ports {
+            None => TcpListener::bind((ip, 0)).await?,
+            Some(ports) => {
+                bind_in_range(ip, ports.start, ports.end)
+                    .await?
+            }
+        };
+
+        let mut bound = listener.local_addr()?;
+        if bound.ip().is_unspecified() {
+            bound.set_ip(local);
+        }
+
+        Ok((listener, bound))
+    }
+}
+
+async fn bind_in_range(
+    ip: IpAddr,
+    start: u16,
+    end: u16,
+) -> io::Result<TcpListener> {
+    let len = usize::from(end - start) + 1;
+    let first = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+
+    for i in 0..len {
+        let port = start + ((first + i) % len) as u16;
+        match TcpListener::bind((ip, port)).await {
+            Ok(listener) => return Ok(listener),
+            Err(_e)
+                if _e.kind()
+                    == io::ErrorKind::AddrInUse => {}
+            Err(_e) => return Err(_e),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("No free port in {}-{}", start, end),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn listens_in_port_range() {
+        // A port that was free a moment ago
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let options = BindOptions {
+            ports: Some(PortRange {
+                start: port,
+                end: port,
+            }),
+            ..Default::default()
+        };
+        let local = IpAddr::from([127, 0, 0, 1]);
+
+        let (_listener, bound) =
+            options.listen(local).await.unwrap();
+        assert_eq!(bound, SocketAddr::new(local, port));
+
+        let err = options.listen(local).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[tokio::test]
+    async fn unspecified_address_reports_local() {
+        let options = BindOptions {
+            address: Some(IpAddr::from([0, 0, 0, 0])),
+            ..Default::default()
+        };
+        let local = IpAddr::from([127, 0, 0, 1]);
+
+        let (listener, bound) =
+            options.listen(local).await.unwrap();
+        assert!(listener
+            .local_addr()
+            .unwrap()
+            .ip()
+            .is_unspecified());
+        assert_eq!(bound.ip(), local);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+};
+
+use ipnet::IpNet;
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::rules::{parse_cidr, RuleAction};
+use crate::proxies::auth::AuthMethod;
+
+/// Client addresses a proxy serves, checked as soon as a
+/// connection is accepted
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAcl {
+    // The first matching rule decides
+    #[serde(default)]
+    pub rules: Vec<ClientRule>,
+    // Clients no rule matches
+    #[serde(default = "default_action")]
+    pub default: RuleAction,
+}
+
+fn default_action() -> RuleAction {
+    RuleAction::Allow
+}
+
+impl Default for ClientAcl {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            default: default_action(),
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct ClientRule {
+    pub action: RuleAction,
+    // Client prefix, `10.0.0.0/8`
+    pub cidr: String,
+    // Methods a client allowed by the rule may use, every
+    // enabled one when not set
+    #[serde(default)]
+    pub auth_methods: Option<Vec<AuthMethod>>,
+}
+
+/// What an accepted client may do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientAccess {
+    Denied,
+    // Auth method codes the client may use, every enabled
+    // one when `None`
+    Allowed(Option<HashSet<u8>>),
+}
+
+/// A `ClientAcl` ready to be checked
+#[derive(Debug, Default)]
+pub struct ClientAclSet {
+    acl: ClientAcl,
+    rules: Vec<(IpNet, ClientAccess)>,
+}
+
+impl ClientAclSet {
+    pub fn compile(acl: ClientAcl) -> Result<Self, String> {
+        let mut rules = Vec::with_capacity(acl.rules.len());
+
+        for (idx, rule) in acl.rules.iter().enumerate() {
+            let invalid = |msg: String| {
+                format!("rules[{}]: {}", idx, msg)
+            };
+
+            let cidr =
+                parse_cidr(&rule.cidr).map_err(invalid)?;
+
+            let access = match (rule.action, &rule.auth_methods)
+            {
+                (RuleAction::Deny, None) => ClientAccess::Denied,
+                (RuleAction::Deny, Some(_)) => {
+                    return Err(invalid(
+                        "auth_methods only apply to allow rules"
+                            .to_string(),
+                    ))
+                }
+                (RuleAction::Allow, Some(methods))
+                    if methods.is_empty() =>
+                {
+                    return Err(invalid(
+                        "auth_methods must not be empty"
+                            .to_string(),
+                    ))
+                }
+                (RuleAction::Allow, methods) => {
+                    ClientAccess::Allowed(methods.as_ref().map(
+                        |methods| {
+                            methods
+                                .iter()
+                                .copied()
+                                .map(AuthMethod::to_byte)
+                                .collect()
+                        },
+                    ))
+                }
+            };
+
+            rules.push((cidr, access));
+        }
+
+        Ok(Self { acl, rules })
+    }
+
+    pub fn acl(&self) -> ClientAcl {
+        self.acl.clone()
+    }
+
+    pub fn check(&self, ip: &IpAddr) -> ClientAccess {
+        // IPv4 clients of a dual stack listener show up
+        // mapped
+        let ip = match ip {
+            IpAddr::V6(v6) => {
+                v6.to_ipv4_mapped().map_or(*ip, IpAddr::V4)
+            }
+            IpAddr::V4(_) => *ip,
+        };
+
+        self.rules
+            .iter()
+            .find(|(cidr, _)| cidr.contains(&ip))
+            .map(|(_, access)| access.clone())
+            .unwrap_or(match self.acl.default {
+                RuleAction::Allow => {
+                    ClientAccess::Allowed(None)
+                }
+                RuleAction::Deny => ClientAccess::Denied,
+            })
+    }
+}
+
+/// Logs and counts a client the ACL refused
+pub fn client_refused(addr: &SocketAddr) {
+    info!("Refused client {}", addr);
+    counter!("proxier_clients_refused_total").increment(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        action: RuleAction,
+        cidr: &str,
+        auth_methods: Option<Vec<AuthMethod>>,
+    ) -> ClientRule {
+        ClientRule {
+            action,
+            cidr: cidr.to_string(),
+            auth_methods,
+        }
+    }
+
+    fn check(acl: &ClientAclSet, ip: &str) -> ClientAccess {
+        acl.check(&ip.parse().unwrap())
+    }
+
+    fn methods(codes: &[u8]) -> ClientAccess {
+        ClientAccess::Allowed(Some(
+            codes.iter().copied().collect(),
+        ))
+    }
+
+    #[test]
+    fn allows_everyone_by_default() {
+        let acl = ClientAclSet::default();
+
+        assert_eq!(
+            check(&acl, "203.0.113.1"),
+            ClientAccess::Allowed(None)
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let acl = ClientAclSet::compile(ClientAcl {
+            rules: vec![
+                rule(
+                    RuleAction::Allow,
+                    "10.1.0.0/16",
+                    None,
+                ),
+                rule(RuleAction::Deny, "10.0.0.0/8", None),
+                rule(
+                    RuleAction::Allow,
+                    "0.0.0.0/0",
+                    Some(vec![
+                        AuthMethod::UsernamePassword,
+                    ]),
+                ),
+            ],
+            default: RuleAction::Deny,
+        })
+        .unwrap();
+
+        assert_eq!(
+            check(&acl, "10.1.2.3"),
+            ClientAccess::Allowed(None)
+        );
+        assert_eq!(
+            check(&acl, "10.2.0.1"),
+            ClientAccess::Denied
+        );
+        assert_eq!(
+            check(&acl, "203.0.113.1"),
+            methods(&[0x02])
+        );
+        // No IPv6 rule, the default applies
+        assert_eq!(
+            check(&acl, "2001:db8::1"),
+            ClientAccess::Denied
+        );
+    }
+
+    #[test]
+    fn default_applies_to_unmatched_clients() {
+        let acl = ClientAclSet::compile(ClientAcl {
+            rules: vec![rule(
+                RuleAction::Allow,
+                "127.0.0.1",
+                None,
+            )],
+            default: RuleAction::Deny,
+        })
+        .unwrap();
+
+        assert_eq!(
+            check(&acl, "127.0.0.1"),
+            ClientAccess::Allowed(None)
+        );
+        assert_eq!(
+            check(&acl, "127.0.0.2"),
+            ClientAccess::Denied
+        );
+    }
+
+    #[test]
+    fn mapped_clients_match_ipv4_rules() {
+        let acl = ClientAclSet::compile(ClientAcl {
+            rules: vec![rule(
+                RuleAction::Deny,
+                "192.0.2.0/24",
+                None,
+            )],
+            default: RuleAction::Allow,
+        })
+        .unwrap();
+
+        assert_eq!(
+            check(&acl, "::ffff:192.0.2.7"),
+            ClientAccess::Denied
+        );
+    }
+
+    #[test]
+    fn narrows_auth_methods() {
+        let acl = ClientAclSet::compile(ClientAcl {
+            rules: vec![rule(
+                RuleAction::Allow,
+                "10.0.0.0/8",
+                Some(vec![
+                    AuthMethod::NoAuth,
+                    AuthMethod::UsernamePassword,
+                ]),
+            )],
+            default: RuleAction::Allow,
+        })
+        .unwrap();
+
+        assert_eq!(
+            check(&acl, "10.0.0.1"),
+            methods(&[0x00, 0x02])
+        );
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let cases = [
+            (
+                rule(RuleAction::Allow, "nope", None),
+                "rules[0]: invalid cidr `nope`",
+            ),
+            (
+                rule(
+                    RuleAction::Deny,
+                    "10.0.0.0/8",
+                    Some(vec![AuthMethod::NoAuth]),
+                ),
+                "rules[0]: auth_methods only apply to allow rules",
+            ),
+            (
+                rule(RuleAction::Allow, "10.0.0.0/8", Some(Vec::new())),
+                "rules[0]: auth_methods must not be empty",
+            ),
+        ];
+        for (rule, msg) in cases {
+            let err = ClientAclSet::compile(ClientAcl {
+                rules: vec![rule],
+                default: RuleAction::Allow,
+            })
+            .unwrap_err();
+            assert_eq!(err, msg);
+        }
+    }
+
+    #[test]
+    fn keeps_the_acl_as_written() {
+        let written = ClientAcl {
+            rules: vec![rule(
+                RuleAction::Deny,
+                "10.0.0.0/8",
+                None,
+            )],
+            default: RuleAction::Deny,
+        };
+        let acl =
+            ClientAclSet::compile(written.clone()).unwrap();
+
+        assert_eq!(acl.acl(), written);
+    }
+}
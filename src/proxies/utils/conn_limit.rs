@@ -0,0 +1,396 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use metrics::counter;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::Notify,
+    time::{self, Instant},
+};
+use tracing::info;
+
+/// What happens to a connection over a limit
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum WhenFull {
+    // Wait for a slot until the handshake times out, the
+    // proxy stops accepting while it is full
+    Queue,
+    // Finish the handshake and refuse the request with
+    // "connection not allowed"
+    #[default]
+    Reject,
+    // Close the connection right away
+    Close,
+}
+
+/// Concurrent connections of a proxy, `None` is unlimited
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionLimits {
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+    #[serde(default)]
+    pub max_connections_per_user: Option<usize>,
+    #[serde(default)]
+    pub when_full: WhenFull,
+}
+
+impl ConnectionLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        let limits = [
+            ("max_connections", self.max_connections),
+            (
+                "max_connections_per_ip",
+                self.max_connections_per_ip,
+            ),
+            (
+                "max_connections_per_user",
+                self.max_connections_per_user,
+            ),
+        ];
+        for (name, limit) in limits {
+            if limit == Some(0) {
+                return Err(format!(
+                    "{} must be greater than 0",
+                    name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// What a connection is counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Slot {
+    Proxy,
+    Ip(IpAddr),
+    User(String),
+}
+
+impl Slot {
+    fn kind(&self) -> &'static str {
+        match self {
+            Slot::Proxy => "proxy",
+            Slot::Ip(_) => "ip",
+            Slot::User(_) => "user",
+        }
+    }
+}
+
+/// Counts the connections of a proxy against its limits
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    limits: Mutex<ConnectionLimits>,
+    used: Mutex<HashMap<Slot, usize>>,
+    // Notified each time a slot is freed or limits change
+    released: Notify,
+}
+
+/// Slot taken until dropped
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    slot: Slot,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        {
+            let mut used = self.limiter.used.lock();
+            if let Some(n) = used.get_mut(&self.slot) {
+                *n -= 1;
+                if *n == 0 {
+                    used.remove(&self.slot);
+                }
+            }
+        }
+        self.limiter.released.notify_waiters();
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn limits(&self) -> ConnectionLimits {
+        *self.limits.lock()
+    }
+
+    /// Lowered limits apply to new connections only
+    pub fn set_limits(&self, limits: ConnectionLimits) {
+        *self.limits.lock() = limits;
+        self.released.notify_waiters();
+    }
+
+    fn max(&self, slot: &Slot) -> Option<usize> {
+        let limits = self.limits.lock();
+        match slot {
+            Slot::Proxy => limits.max_connections,
+            Slot::Ip(_) => limits.max_connections_per_ip,
+            Slot::User(_) => {
+                limits.max_connections_per_user
+            }
+        }
+    }
+
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        slot: Slot,
+    ) -> Option<ConnectionPermit> {
+        let max = self.max(&slot);
+
+        let mut used = self.used.lock();
+        let n = used.entry(slot.clone()).or_default();
+        if max.is_some_and(|max| *n >= max) {
+            return None;
+        }
+        *n += 1;
+
+        Some(ConnectionPermit {
+            limiter: Arc::clone(self),
+            slot,
+        })
+    }
+
+    /// Waits until the slot has room
+    pub async fn acquire(
+        self: &Arc<Self>,
+        slot: Slot,
+    ) -> ConnectionPermit {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            if let Some(permit) =
+                self.try_acquire(slot.clone())
+            {
+                return permit;
+            }
+
+            released.await;
+        }
+    }
+
+    /// Takes the slot as `when_full` says, a queued
+    /// connection waits until `deadline`. The error is what
+    /// to do with the connection, `Close` once a queued one
+    /// ran out of time.
+    pub async fn admit(
+        self: &Arc<Self>,
+        slot: Slot,
+        deadline: Instant,
+    ) -> Result<ConnectionPermit, WhenFull> {
+        let when_full = self.limits().when_full;
+
+        let permit = match when_full {
+            WhenFull::Queue => time::timeout_at(
+                deadline,
+                self.acquire(slot.clone()),
+            )
+            .await
+            .ok(),
+            _ => self.try_acquire(slot.clone()),
+        };
+
+        permit.ok_or_else(|| {
+            limit_reached(&slot);
+            match when_full {
+                WhenFull::Reject => WhenFull::Reject,
+                _ => WhenFull::Close,
+            }
+        })
+    }
+}
+
+/// Logs and counts a connection over a limit
+pub fn limit_reached(slot: &Slot) {
+    info!("Connection limit reached for {:?}", slot);
+    counter!(
+        "proxier_connection_limit_reached_total",
+        "limit" => slot.kind()
+    )
+    .increment(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limiter(
+        limits: ConnectionLimits,
+    ) -> Arc<ConnectionLimiter> {
+        let limiter = ConnectionLimiter::new();
+        limiter.set_limits(limits);
+        limiter
+    }
+
+    fn ip(addrs: &str) -> Slot {
+        Slot::Ip(addrs.parse().unwrap())
+    }
+
+    fn full(
+        when_full: WhenFull,
+    ) -> (Arc<ConnectionLimiter>, ConnectionPermit) {
+        let limiter = limiter(ConnectionLimits {
+            max_connections: Some(1),
+            when_full,
+            ..Default::default()
+        });
+        let permit =
+            limiter.try_acquire(Slot::Proxy).unwrap();
+        (limiter, permit)
+    }
+
+    fn soon() -> Instant {
+        Instant::now() + Duration::from_millis(100)
+    }
+
+    #[test]
+    fn global_limit() {
+        let limiter = limiter(ConnectionLimits {
+            max_connections: Some(2),
+            ..Default::default()
+        });
+
+        let first =
+            limiter.try_acquire(Slot::Proxy).unwrap();
+        let _second =
+            limiter.try_acquire(Slot::Proxy).unwrap();
+        assert!(limiter.try_acquire(Slot::Proxy).is_none());
+
+        drop(first);
+        assert!(limiter.try_acquire(Slot::Proxy).is_some());
+    }
+
+    #[test]
+    fn per_ip_limit() {
+        let limiter = limiter(ConnectionLimits {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        });
+
+        let _a =
+            limiter.try_acquire(ip("192.0.2.1")).unwrap();
+        assert!(limiter
+            .try_acquire(ip("192.0.2.1"))
+            .is_none());
+        assert!(limiter
+            .try_acquire(ip("192.0.2.2"))
+            .is_some());
+        // Other slots are not limited
+        assert!(limiter.try_acquire(Slot::Proxy).is_some());
+    }
+
+    #[test]
+    fn permits_released_on_drop() {
+        let limiter = limiter(ConnectionLimits {
+            max_connections: Some(1),
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        });
+
+        let permits = [
+            limiter.try_acquire(Slot::Proxy).unwrap(),
+            limiter.try_acquire(ip("192.0.2.1")).unwrap(),
+        ];
+        assert_eq!(limiter.used.lock().len(), 2);
+
+        drop(permits);
+        assert!(limiter.used.lock().is_empty());
+        assert!(limiter.try_acquire(Slot::Proxy).is_some());
+        assert!(limiter
+            .try_acquire(ip("192.0.2.1"))
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn full_rejects_or_closes() {
+        let (limiter, _permit) = full(WhenFull::Reject);
+        assert!(matches!(
+            limiter.admit(Slot::Proxy, soon()).await,
+            Err(WhenFull::Reject)
+        ));
+
+        let (limiter, _permit) = full(WhenFull::Close);
+        assert!(matches!(
+            limiter.admit(Slot::Proxy, soon()).await,
+            Err(WhenFull::Close)
+        ));
+    }
+
+    #[tokio::test]
+    async fn full_queues_until_released() {
+        let (limiter, permit) = full(WhenFull::Queue);
+
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(20)).await;
+            drop(permit);
+        });
+
+        assert!(limiter
+            .admit(Slot::Proxy, soon())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn full_queue_closes_at_deadline() {
+        let (limiter, _permit) = full(WhenFull::Queue);
+
+        let started = Instant::now();
+        assert!(matches!(
+            limiter.admit(Slot::Proxy, soon()).await,
+            Err(WhenFull::Close)
+        ));
+        assert!(
+            started.elapsed() >= Duration::from_millis(100)
+        );
+    }
+
+    #[tokio::test]
+    async fn raised_limit_wakes_queue() {
+        let (limiter, _permit) = full(WhenFull::Queue);
+
+        let raised = Arc::clone(&limiter);
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(20)).await;
+            raised.set_limits(ConnectionLimits {
+                max_connections: Some(2),
+                when_full: WhenFull::Queue,
+                ..Default::default()
+            });
+        });
+
+        assert!(limiter
+            .admit(Slot::Proxy, soon())
+            .await
+            .is_ok());
+    }
+}
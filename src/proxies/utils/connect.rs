@@ -0,0 +1,328 @@
+use std::{net::SocketAddr, time::Duration};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{io, net::TcpStream, time};
+
+// RFC 8305 recommends 250ms between connection attempts,
+// at least 10ms and no more than 2s
+const DEFAULT_ATTEMPT_DELAY: u64 = 250;
+const MIN_ATTEMPT_DELAY: u64 = 10;
+const MAX_ATTEMPT_DELAY: u64 = 2000;
+
+/// Address families a proxy connects to targets over
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPreference {
+    // Both, IPv6 attempts start first
+    #[default]
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+/// How a proxy connects to targets with several addresses
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectOptions {
+    #[serde(default)]
+    pub ip_preference: IpPreference,
+
+    // Milliseconds before the next address is tried while
+    // earlier attempts are still pending
+    #[serde(default = "default_attempt_delay")]
+    pub attempt_delay: u64,
+}
+
+fn default_attempt_delay() -> u64 {
+    DEFAULT_ATTEMPT_DELAY
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            ip_preference: IpPreference::default(),
+            attempt_delay: default_attempt_delay(),
+        }
+    }
+}
+
+impl ConnectOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_ATTEMPT_DELAY..=MAX_ATTEMPT_DELAY)
+            .contains(&self.attempt_delay)
+        {
+            return Err(format!(
+                "attempt_delay must be between {} and {} ms",
+                MIN_ATTEMPT_DELAY, MAX_ATTEMPT_DELAY
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Addresses in the order they are tried, families
+    /// alternate starting with the preferred one
+    fn order(
+        &self,
+        addrs: &[SocketAddr],
+    ) -> Vec<SocketAddr> {
+        let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.iter().partition(|a| a.is_ipv6());
+
+        let (first, second) = match self.ip_preference {
+            IpPreference::PreferIpv6 => (v6, v4),
+            IpPreference::Ipv4Only => (v4, Vec::new()),
+            IpPreference::Ipv6Only => (v6, Vec::new()),
+        };
+
+        let mut ordered =
+            Vec::with_capacity(first.len() + second.len());
+        let (mut first, mut second) =
+            (first.into_iter(), second.into_iter());
+        loop {
+            match (first.next(), second.next()) {
+                (None, None) => break,
+                (a, b) => {
+                    ordered.extend(a.into_iter().chain(b))
+                }
+            }
+        }
+
+        ordered
+    }
+}
+
+/// Races connections to the addresses as described in
+/// RFC 8305. A new attempt starts every `attempt_delay` or
+/// as soon as one fails, the first connection established
+/// wins and the others are dropped. The error is the one of
+/// the last attempt that failed.
+pub async fn connect_happy_eyeballs(
+    addrs: &[SocketAddr],
+    options: &ConnectOptions,
+) -> io::Result<TcpStream> {
+    let mut pending = options.order(addrs).into_iter();
+    if pending.len() == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::NetworkUnreachable,
+            "No address of an allowed family",
+        ));
+    }
+
+    let delay =
+        Duration::from_millis(options.attempt_delay);
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addrs) => {
+                    attempts.push(TcpStream::connect(addrs))
+                }
+                None => {
+                    return Err(last_error.unwrap_or_else(
+                        || {
+                            io::Error::other(
+                            "No connection attempt made",
+                        )
+                        },
+                    ))
+                }
+            }
+        }
+
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(socket) => return Ok(socket),
+                Err(_e) => {
+                    last_error = Some(_e);
+                    // Next address right away
+                    if let Some(addrs) = pending.next() {
+                        attempts.push(TcpStream::connect(addrs));
+                    }
+                }
+            },
+            _ = time::sleep(delay), if pending.len() > 0 => {
+                if let Some(addrs) = pending.next() {
+                    attempts.push(TcpStream::connect(addrs));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpListener, time::Instant};
+
+    use super::*;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    fn options(
+        ip_preference: IpPreference,
+    ) -> ConnectOptions {
+        ConnectOptions {
+            ip_preference,
+            ..Default::default()
+        }
+    }
+
+    /// Local address nothing listens on
+    async fn closed_port() -> SocketAddr {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn families_interleave() {
+        let mixed = addrs(&[
+            "192.0.2.1:80",
+            "192.0.2.2:80",
+            "192.0.2.3:80",
+            "[2001:db8::1]:80",
+            "[2001:db8::2]:80",
+        ]);
+
+        assert_eq!(
+            options(IpPreference::PreferIpv6).order(&mixed),
+            addrs(&[
+                "[2001:db8::1]:80",
+                "192.0.2.1:80",
+                "[2001:db8::2]:80",
+                "192.0.2.2:80",
+                "192.0.2.3:80",
+            ])
+        );
+        assert_eq!(
+            options(IpPreference::Ipv4Only).order(&mixed),
+            addrs(&[
+                "192.0.2.1:80",
+                "192.0.2.2:80",
+                "192.0.2.3:80",
+            ])
+        );
+        assert_eq!(
+            options(IpPreference::Ipv6Only).order(&mixed),
+            addrs(&[
+                "[2001:db8::1]:80",
+                "[2001:db8::2]:80"
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn pending_attempt_staggers_next() {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        // Discard-only prefix, connecting never completes
+        let unroutable: SocketAddr =
+            "[100::1]:80".parse().unwrap();
+
+        // Without an IPv6 route the attempt fails at once
+        // and there is nothing to stagger
+        if time::timeout(
+            Duration::from_millis(100),
+            TcpStream::connect(unroutable),
+        )
+        .await
+        .is_ok()
+        {
+            return;
+        }
+
+        let options = ConnectOptions::default();
+        let started = Instant::now();
+        let socket = connect_happy_eyeballs(
+            &[local, unroutable],
+            &options,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(socket.peer_addr().unwrap(), local);
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed
+                >= Duration::from_millis(
+                    DEFAULT_ATTEMPT_DELAY
+                ),
+            "{:?}",
+            elapsed
+        );
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn failed_attempt_falls_back_at_once() {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+
+        let started = Instant::now();
+        let socket = connect_happy_eyeballs(
+            &[closed_port().await, local],
+            &ConnectOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(socket.peer_addr().unwrap(), local);
+        assert!(
+            started.elapsed()
+                < Duration::from_millis(
+                    DEFAULT_ATTEMPT_DELAY
+                )
+        );
+    }
+
+    #[tokio::test]
+    async fn every_attempt_failing_errors() {
+        let result = connect_happy_eyeballs(
+            &[
+                closed_port().await,
+                closed_port().await,
+            ],
+            &ConnectOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+
+        // No address of the allowed family
+        let result = connect_happy_eyeballs(
+            &addrs(&["[2001:db8::1]:80"]),
+            &options(IpPreference::Ipv4Only),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().kind(),
+            io::ErrorKind::NetworkUnreachable
+        );
+    }
+}
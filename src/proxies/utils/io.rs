@@ -1,8 +1,84 @@
-use tokio::net::TcpListener;
+use std::{net::SocketAddr, time::Duration};
 
-pub async fn is_port_in_use(port: u16) -> bool {
-    let address = format!("127.0.0.1:{}", port);
-    match TcpListener::bind(&address).await {
+use metrics::counter;
+use tokio::{
+    io::{
+        self, AsyncRead, AsyncReadExt, AsyncWrite,
+        AsyncWriteExt,
+    },
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tracing::error;
+
+use super::rate_limit::Limiters;
+
+// Relay chunk size, limits are applied per chunk
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+// Pause after a failed accept, doubled on each failure
+const ACCEPT_BACKOFF_MIN: Duration =
+    Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Relays both directions until both peers closed their
+/// side. An error in either direction, including a used up
+/// limit, ends the whole relay.
+pub async fn copy_bidirectional_limited<A, B>(
+    a: A,
+    b: B,
+    limiters: &Limiters,
+) -> io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_read, mut a_write) = io::split(a);
+    let (mut b_read, mut b_write) = io::split(b);
+
+    let a_to_b = async {
+        copy_limited(&mut a_read, &mut b_write, limiters)
+            .await?;
+        b_write.shutdown().await
+    };
+
+    let b_to_a = async {
+        copy_limited(&mut b_read, &mut a_write, limiters)
+            .await?;
+        a_write.shutdown().await
+    };
+
+    tokio::try_join!(a_to_b, b_to_a).map(|_| ())
+}
+
+/// Accepts the next connection. Errors, running out of
+/// file descriptors for one, are retried after a pause
+/// instead of spinning.
+pub async fn accept_with_backoff(
+    listener: &TcpListener,
+) -> (TcpStream, SocketAddr) {
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+
+    loop {
+        match listener.accept().await {
+            Ok(conn) => return conn,
+            Err(_e) => {
+                error!(
+                    "Accept error, retrying in {:?}: {}",
+                    backoff, _e
+                );
+                counter!("proxier_accept_errors_total")
+                    .increment(1);
+                time::sleep(backoff).await;
+                backoff =
+                    (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+        }
+    }
+}
+
+pub async fn is_port_in_use(addrs: SocketAddr) -> bool {
+    match TcpListener::bind(addrs).await {
         Ok(listener) => {
             drop(listener); // Release the listener
             false
@@ -10,3 +86,34 @@ pub async fn is_port_in_use(port: u16) -> bool {
         Err(_) => true,
     }
 }
+
+/// Same as `io::copy` but every chunk is counted and has to
+/// pass the limiters before it is written
+pub async fn copy_limited<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limiters: &Limiters,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0; RELAY_BUFFER_SIZE];
+    let mut copied = 0;
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.flush().await?;
+            return Ok(copied);
+        }
+
+        if limiters.exhausted() {
+            return Err(io::Error::other("Limit reached"));
+        }
+        limiters.consume(n as u64).await;
+
+        writer.write_all(&buf[..n]).await?;
+        copied += n as u64;
+    }
+}
@@ -1 +1,12 @@
+pub mod bind;
+pub mod client_acl;
+pub mod conn_limit;
+pub mod connect;
 pub mod io;
+pub mod policy;
+pub mod quota;
+pub mod rate_limit;
+pub mod rules;
+pub mod sessions;
+pub mod ssrf;
+pub mod timeout;
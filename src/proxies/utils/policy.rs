@@ -0,0 +1,481 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::models::users::{User, Users};
+
+use super::{
+    rules::{
+        Destination, Rule, RuleAction, RuleSet, Verdict,
+    },
+    ssrf::{
+        reaches, ssrf_refused, SsrfGuard, SsrfProtection,
+    },
+};
+
+/// Users, blocked addresses and destination rules of the
+/// manager, inherited by every proxy
+#[derive(Debug, Default)]
+pub struct GlobalPolicy {
+    pub users: RwLock<Users>,
+    pub blocked_ippaddr: RwLock<HashSet<IpAddr>>,
+    // Replaced as a whole
+    pub rules: Mutex<Arc<RuleSet>>,
+    // Admin api listener, never a destination
+    pub admin: Mutex<Option<SocketAddr>>,
+}
+
+/// What a proxy takes from the global policy
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct Inheritance {
+    #[serde(default = "inherit")]
+    pub users: bool,
+    #[serde(default = "inherit")]
+    pub blocked: bool,
+}
+
+fn inherit() -> bool {
+    true
+}
+
+impl Default for Inheritance {
+    fn default() -> Self {
+        Self {
+            users: inherit(),
+            blocked: inherit(),
+        }
+    }
+}
+
+/// Where an entry of the effective policy comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyScope {
+    Global,
+    Proxy,
+}
+
+/// Users and blocks a proxy actually applies
+#[derive(Debug, Clone)]
+pub struct EffectivePolicy {
+    pub inherit: Inheritance,
+    pub users: Vec<(PolicyScope, User)>,
+    pub blocked: Vec<(PolicyScope, IpAddr)>,
+    // In the order they are evaluated
+    pub rules: Vec<(PolicyScope, Rule)>,
+}
+
+/// Layers the users and blocks of a proxy over the global
+/// ones. Proxy users shadow global users with the same name,
+/// blocked addresses of both layers apply. Global rules are
+/// evaluated after the ones of the proxy and inherited
+/// along with the blocked addresses.
+#[derive(Debug)]
+pub struct ProxyPolicy {
+    global: Arc<GlobalPolicy>,
+    users: Arc<RwLock<Users>>,
+    blocked_ippaddr: Arc<RwLock<HashSet<IpAddr>>>,
+    rules: Mutex<Arc<RuleSet>>,
+    ssrf: Mutex<Arc<SsrfGuard>>,
+    inherit: Mutex<Inheritance>,
+    // Users checked by external auth backends by name, kept
+    // so limits and usage last across connections
+    external_users: Mutex<HashMap<String, User>>,
+}
+
+impl ProxyPolicy {
+    pub fn new(
+        global: Arc<GlobalPolicy>,
+        users: Arc<RwLock<Users>>,
+        blocked_ippaddr: Arc<RwLock<HashSet<IpAddr>>>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            global,
+            users,
+            blocked_ippaddr,
+            rules: Mutex::new(Arc::default()),
+            ssrf: Mutex::new(
+                Arc::new(SsrfGuard::default()),
+            ),
+            inherit: Mutex::new(Inheritance::default()),
+            external_users: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn inheritance(&self) -> Inheritance {
+        *self.inherit.lock()
+    }
+
+    pub fn set_inheritance(&self, inherit: Inheritance) {
+        *self.inherit.lock() = inherit;
+    }
+
+    pub async fn find_user(
+        &self,
+        user_name: &str,
+    ) -> Option<User> {
+        let own = User::find_user_by_name(
+            &*self.users.read().await,
+            user_name.to_string(),
+        );
+        if own.is_some() || !self.inheritance().users {
+            return own;
+        }
+
+        User::find_user_by_name(
+            &*self.global.users.read().await,
+            user_name.to_string(),
+        )
+    }
+
+    /// User for a name accepted by an external backend, the
+    /// same one on every login
+    pub fn external_user(&self, user_name: &str) -> User {
+        self.external_users
+            .lock()
+            .entry(user_name.to_string())
+            .or_insert_with(|| User::external(user_name))
+            .clone()
+    }
+
+    /// `::ffff:a.b.c.d` is blocked along with a.b.c.d
+    pub async fn is_blocked(&self, addrs: &IpAddr) -> bool {
+        let addrs = &addrs.to_canonical();
+        if self.blocked_ippaddr.read().await.contains(addrs)
+        {
+            return true;
+        }
+
+        self.inheritance().blocked
+            && self
+                .global
+                .blocked_ippaddr
+                .read()
+                .await
+                .contains(addrs)
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.rules.lock().rules()
+    }
+
+    pub fn set_rules(
+        &self,
+        rules: Vec<Rule>,
+    ) -> Result<(), String> {
+        *self.rules.lock() =
+            Arc::new(RuleSet::compile(rules)?);
+        Ok(())
+    }
+
+    pub fn ssrf(&self) -> SsrfProtection {
+        self.ssrf.lock().options()
+    }
+
+    pub fn set_ssrf(
+        &self,
+        options: SsrfProtection,
+    ) -> Result<(), String> {
+        *self.ssrf.lock() =
+            Arc::new(SsrfGuard::compile(options)?);
+        Ok(())
+    }
+
+    /// Whether a destination may be connected to. A host
+    /// name is checked before it is resolved and each of
+    /// its addresses again afterwards, blocked addresses,
+    /// the admin api and the ones SSRF protection refuses
+    /// are refused
+    /// whatever the rules say. Destinations no rule matches
+    /// are allowed.
+    pub async fn allows(
+        &self,
+        dst: &Destination<'_>,
+    ) -> bool {
+        if let Some(ip) = &dst.ip {
+            if self.is_blocked(ip).await {
+                return false;
+            }
+
+            let addrs = SocketAddr::new(*ip, dst.port);
+            let admin = *self.global.admin.lock();
+            if admin.is_some_and(|a| reaches(&a, &addrs)) {
+                ssrf_refused(&addrs, "admin");
+                return false;
+            }
+
+            let ssrf = Arc::clone(&self.ssrf.lock());
+            if let Some(range) = ssrf.refuses(ip) {
+                ssrf_refused(&addrs, range);
+                return false;
+            }
+        }
+
+        let own = Arc::clone(&self.rules.lock());
+        let verdict = match own.evaluate(dst) {
+            Verdict::NoMatch
+                if self.inheritance().blocked =>
+            {
+                let global =
+                    Arc::clone(&self.global.rules.lock());
+                global.evaluate(dst)
+            }
+            verdict => verdict,
+        };
+
+        verdict != Verdict::Matched(RuleAction::Deny)
+    }
+
+    pub async fn effective(&self) -> EffectivePolicy {
+        let inherit = self.inheritance();
+
+        let mut users: Vec<(PolicyScope, User)> = self
+            .users
+            .read()
+            .await
+            .values()
+            .map(|u| (PolicyScope::Proxy, u.clone()))
+            .collect();
+        if inherit.users {
+            let global = self.global.users.read().await;
+            let inherited: Vec<(PolicyScope, User)> =
+                global
+                    .values()
+                    .filter(|g| {
+                        !users.iter().any(|(_, u)| {
+                            u.user_name == g.user_name
+                        })
+                    })
+                    .map(|u| {
+                        (PolicyScope::Global, u.clone())
+                    })
+                    .collect();
+            users.extend(inherited);
+        }
+        users.sort_by(|a, b| {
+            a.1.user_name.cmp(&b.1.user_name)
+        });
+
+        let mut blocked: Vec<(PolicyScope, IpAddr)> = self
+            .blocked_ippaddr
+            .read()
+            .await
+            .iter()
+            .map(|a| (PolicyScope::Proxy, *a))
+            .collect();
+        if inherit.blocked {
+            let global =
+                self.global.blocked_ippaddr.read().await;
+            let inherited: Vec<(PolicyScope, IpAddr)> =
+                global
+                    .iter()
+                    .filter(|g| {
+                        !blocked
+                            .iter()
+                            .any(|(_, a)| a == *g)
+                    })
+                    .map(|a| (PolicyScope::Global, *a))
+                    .collect();
+            blocked.extend(inherited);
+        }
+        blocked.sort_by_key(|(_, a)| *a);
+
+        let mut rules: Vec<(PolicyScope, Rule)> = self
+            .rules()
+            .into_iter()
+            .map(|r| (PolicyScope::Proxy, r))
+            .collect();
+        if inherit.blocked {
+            let global = self.global.rules.lock().rules();
+            rules.extend(
+                global
+                    .into_iter()
+                    .map(|r| (PolicyScope::Global, r)),
+            );
+        }
+
+        EffectivePolicy {
+            inherit,
+            users,
+            blocked,
+            rules,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> (Arc<GlobalPolicy>, Arc<ProxyPolicy>) {
+        let global = Arc::new(GlobalPolicy::default());
+        let policy = ProxyPolicy::new(
+            Arc::clone(&global),
+            Arc::default(),
+            Arc::default(),
+        );
+        (global, policy)
+    }
+
+    fn domain(action: RuleAction, domain: &str) -> Rule {
+        Rule {
+            action,
+            cidr: None,
+            ports: None,
+            domain: Some(domain.to_string()),
+            regex: None,
+        }
+    }
+
+    fn public(host: &str) -> Destination<'_> {
+        Destination::resolved(
+            Some(host),
+            &"93.184.216.34:80".parse().unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn proxy_rules_come_before_global_ones() {
+        let (global, policy) = policy();
+        *global.rules.lock() = Arc::new(
+            RuleSet::compile(vec![domain(
+                RuleAction::Deny,
+                "*.example.com",
+            )])
+            .unwrap(),
+        );
+
+        assert!(
+            !policy.allows(&public("a.example.com")).await
+        );
+
+        policy
+            .set_rules(vec![domain(
+                RuleAction::Allow,
+                "a.example.com",
+            )])
+            .unwrap();
+        assert!(
+            policy.allows(&public("a.example.com")).await
+        );
+        assert!(
+            !policy.allows(&public("b.example.com")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn global_rules_follow_blocked_inheritance() {
+        let (global, policy) = policy();
+        *global.rules.lock() = Arc::new(
+            RuleSet::compile(vec![domain(
+                RuleAction::Deny,
+                "example.com",
+            )])
+            .unwrap(),
+        );
+
+        policy.set_inheritance(Inheritance {
+            users: true,
+            blocked: false,
+        });
+
+        assert!(
+            policy.allows(&public("example.com")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn mapped_addresses_are_blocked() {
+        let (global, policy) = policy();
+        global
+            .blocked_ippaddr
+            .write()
+            .await
+            .insert("1.2.3.4".parse().unwrap());
+
+        for addrs in ["1.2.3.4", "::ffff:1.2.3.4"] {
+            assert!(
+                policy
+                    .is_blocked(&addrs.parse().unwrap())
+                    .await,
+                "{}",
+                addrs
+            );
+        }
+        assert!(
+            !policy
+                .is_blocked(
+                    &"::ffff:1.2.3.5".parse().unwrap()
+                )
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn blocked_addresses_win_over_rules() {
+        let (global, policy) = policy();
+        policy
+            .set_rules(vec![domain(
+                RuleAction::Allow,
+                "example.com",
+            )])
+            .unwrap();
+        global
+            .blocked_ippaddr
+            .write()
+            .await
+            .insert("93.184.216.34".parse().unwrap());
+
+        assert!(
+            !policy.allows(&public("example.com")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn allow_rules_do_not_lift_ssrf_protection() {
+        let (global, policy) = policy();
+        policy
+            .set_rules(vec![domain(
+                RuleAction::Allow,
+                "intranet.local",
+            )])
+            .unwrap();
+        let intranet = Destination::resolved(
+            Some("intranet.local"),
+            &"10.0.0.1:80".parse().unwrap(),
+        );
+        assert!(!policy.allows(&intranet).await);
+
+        policy
+            .set_ssrf(SsrfProtection {
+                enabled: true,
+                exceptions: vec!["10.0.0.0/8".to_string()],
+            })
+            .unwrap();
+        assert!(policy.allows(&intranet).await);
+
+        // The admin api stays out of reach
+        *global.admin.lock() =
+            Some("10.0.0.1:9090".parse().unwrap());
+        let admin = Destination::resolved(
+            None,
+            &"10.0.0.1:9090".parse().unwrap(),
+        );
+        assert!(!policy.allows(&admin).await);
+    }
+}
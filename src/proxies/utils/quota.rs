@@ -0,0 +1,492 @@
+use std::collections::VecDeque;
+
+use chrono::{
+    DateTime, Datelike, Duration, DurationRound, Months,
+    TimeZone, Utc,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+// Rolling windows are tracked in this many slots
+const ROLLING_SLOTS: u64 = 60;
+// Longest rolling window, a year
+const MAX_ROLLING_WINDOW: u64 = 366 * 24 * 60 * 60;
+
+/// Bytes a user may transfer per period
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaLimit {
+    pub bytes: u64,
+    pub period: QuotaPeriod,
+}
+
+/// Calendar periods reset at UTC boundaries, a rolling
+/// window counts the last `n` seconds
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+    Rolling(u64),
+}
+
+impl QuotaLimit {
+    pub fn validate(&self) -> Result<(), String> {
+        if let QuotaPeriod::Rolling(window) = self.period {
+            if window == 0 || window > MAX_ROLLING_WINDOW {
+                return Err(format!(
+                    "rolling window must be between 1 and {} seconds",
+                    MAX_ROLLING_WINDOW
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Quota and usage as exposed by the api
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaUsage {
+    pub limit: Option<QuotaLimit>,
+    pub used: u64,
+    // When the usage drops next, none without a period
+    pub resets_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct QuotaState {
+    limit: Option<QuotaLimit>,
+    used: u64,
+    // Start of the calendar period `used` belongs to
+    period_start: Option<DateTime<Utc>>,
+    // (slot start, bytes) of a rolling window, oldest first
+    slots: VecDeque<(DateTime<Utc>, u64)>,
+}
+
+/// Traffic quota of a user, usage is counted even without a
+/// limit
+#[derive(Debug)]
+pub struct Quota {
+    state: Mutex<QuotaState>,
+}
+
+impl Quota {
+    pub fn new(limit: Option<QuotaLimit>) -> Self {
+        let quota = Self {
+            state: Mutex::new(QuotaState {
+                limit: None,
+                used: 0,
+                period_start: None,
+                slots: VecDeque::new(),
+            }),
+        };
+        quota.set_limit(limit);
+        quota
+    }
+
+    /// Changes the limit, usage is kept unless the period
+    /// changed
+    pub fn set_limit(&self, limit: Option<QuotaLimit>) {
+        let mut state = self.state.lock();
+
+        let period =
+            |l: Option<QuotaLimit>| l.map(|l| l.period);
+        if period(state.limit) != period(limit) {
+            state.used = 0;
+            state.slots.clear();
+            state.period_start = limit.and_then(|l| {
+                period_start(l.period, Utc::now())
+            });
+        }
+
+        state.limit = limit;
+    }
+
+    pub fn add_usage(&self, bytes: u64) {
+        let now = Utc::now();
+        let mut state = self.state.lock();
+
+        roll(&mut state, now);
+        state.used += bytes;
+
+        if let Some(QuotaPeriod::Rolling(window)) =
+            state.limit.map(|l| l.period)
+        {
+            let slot = slot_start(window, now);
+            match state.slots.back_mut() {
+                Some((start, used)) if *start == slot => {
+                    *used += bytes
+                }
+                _ => state.slots.push_back((slot, bytes)),
+            }
+        }
+    }
+
+    /// True when a limit is set and used up
+    pub fn is_exceeded(&self) -> bool {
+        let mut state = self.state.lock();
+
+        roll(&mut state, Utc::now());
+        state.limit.is_some_and(|l| state.used >= l.bytes)
+    }
+
+    pub fn reset(&self) {
+        let mut state = self.state.lock();
+
+        state.used = 0;
+        state.slots.clear();
+    }
+
+    pub fn usage(&self) -> QuotaUsage {
+        let now = Utc::now();
+        let mut state = self.state.lock();
+
+        roll(&mut state, now);
+
+        let resets_at =
+            state.limit.and_then(|l| match l.period {
+                QuotaPeriod::Rolling(window) => {
+                    state.slots.front().map(|(start, _)| {
+                        *start
+                            + slot_len(window)
+                            + seconds(window)
+                    })
+                }
+                period => period_end(period, now),
+            });
+
+        QuotaUsage {
+            limit: state.limit,
+            used: state.used,
+            resets_at,
+        }
+    }
+}
+
+/// Drops usage that belongs to past periods
+fn roll(state: &mut QuotaState, now: DateTime<Utc>) {
+    let Some(limit) = state.limit else {
+        return;
+    };
+
+    match limit.period {
+        QuotaPeriod::Rolling(window) => {
+            let cutoff = now - seconds(window);
+            while let Some((start, used)) =
+                state.slots.front().copied()
+            {
+                if start + slot_len(window) > cutoff {
+                    break;
+                }
+                state.used =
+                    state.used.saturating_sub(used);
+                state.slots.pop_front();
+            }
+        }
+        period => {
+            let start = period_start(period, now);
+            if start != state.period_start {
+                state.used = 0;
+                state.period_start = start;
+            }
+        }
+    }
+}
+
+fn period_start(
+    period: QuotaPeriod,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    match period {
+        QuotaPeriod::Daily => {
+            now.duration_trunc(Duration::days(1)).ok()
+        }
+        QuotaPeriod::Monthly => Utc
+            .with_ymd_and_hms(
+                now.year(),
+                now.month(),
+                1,
+                0,
+                0,
+                0,
+            )
+            .single(),
+        QuotaPeriod::Rolling(_) => None,
+    }
+}
+
+fn period_end(
+    period: QuotaPeriod,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let start = period_start(period, now)?;
+
+    match period {
+        QuotaPeriod::Daily => {
+            Some(start + Duration::days(1))
+        }
+        QuotaPeriod::Monthly => {
+            start.checked_add_months(Months::new(1))
+        }
+        QuotaPeriod::Rolling(_) => None,
+    }
+}
+
+fn seconds(secs: u64) -> Duration {
+    Duration::seconds(secs.min(MAX_ROLLING_WINDOW) as i64)
+}
+
+fn slot_len(window: u64) -> Duration {
+    seconds((window / ROLLING_SLOTS).max(1))
+}
+
+fn slot_start(
+    window: u64,
+    now: DateTime<Utc>,
+) -> DateTime<Utc> {
+    now.duration_trunc(slot_len(window)).unwrap_or(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn limit(
+        bytes: u64,
+        period: QuotaPeriod,
+    ) -> Option<QuotaLimit> {
+        Some(QuotaLimit { bytes, period })
+    }
+
+    fn state(period: QuotaPeriod, used: u64) -> QuotaState {
+        QuotaState {
+            limit: limit(1000, period),
+            used,
+            period_start: None,
+            slots: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn exceeded_once_used_up() {
+        let quota =
+            Quota::new(limit(100, QuotaPeriod::Daily));
+
+        quota.add_usage(99);
+        assert!(!quota.is_exceeded());
+        quota.add_usage(1);
+        assert!(quota.is_exceeded());
+        assert_eq!(quota.usage().used, 100);
+    }
+
+    #[test]
+    fn no_limit_still_counts() {
+        let quota = Quota::new(None);
+
+        quota.add_usage(u32::MAX as u64);
+
+        assert!(!quota.is_exceeded());
+        assert_eq!(quota.usage().used, u32::MAX as u64);
+        assert_eq!(quota.usage().resets_at, None);
+    }
+
+    #[test]
+    fn reset_clears_usage() {
+        let quota = Quota::new(limit(
+            100,
+            QuotaPeriod::Rolling(3600),
+        ));
+        quota.add_usage(150);
+        assert!(quota.is_exceeded());
+
+        quota.reset();
+
+        assert!(!quota.is_exceeded());
+        assert_eq!(quota.usage().used, 0);
+        assert_eq!(quota.usage().resets_at, None);
+    }
+
+    #[test]
+    fn set_limit_keeps_usage_of_the_same_period() {
+        let quota =
+            Quota::new(limit(100, QuotaPeriod::Monthly));
+        quota.add_usage(80);
+
+        quota.set_limit(limit(50, QuotaPeriod::Monthly));
+        assert_eq!(quota.usage().used, 80);
+        assert!(quota.is_exceeded());
+
+        quota.set_limit(limit(50, QuotaPeriod::Daily));
+        assert_eq!(quota.usage().used, 0);
+        assert!(!quota.is_exceeded());
+    }
+
+    #[test]
+    fn daily_rolls_at_utc_midnight() {
+        let mut state = state(QuotaPeriod::Daily, 0);
+        state.period_start = period_start(
+            QuotaPeriod::Daily,
+            at("2024-05-01T10:00:00Z"),
+        );
+        state.used = 500;
+
+        roll(&mut state, at("2024-05-01T23:59:59Z"));
+        assert_eq!(state.used, 500);
+
+        roll(&mut state, at("2024-05-02T00:00:00Z"));
+        assert_eq!(state.used, 0);
+        assert_eq!(
+            state.period_start,
+            Some(at("2024-05-02T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn monthly_rolls_on_the_first() {
+        let mut state = state(QuotaPeriod::Monthly, 0);
+        state.period_start = period_start(
+            QuotaPeriod::Monthly,
+            at("2024-01-31T12:00:00Z"),
+        );
+        state.used = 500;
+
+        roll(&mut state, at("2024-01-31T23:59:59Z"));
+        assert_eq!(state.used, 500);
+
+        roll(&mut state, at("2024-02-01T00:00:01Z"));
+        assert_eq!(state.used, 0);
+    }
+
+    #[test]
+    fn period_ends() {
+        let cases = [
+            (
+                QuotaPeriod::Daily,
+                "2024-02-28T13:00:00Z",
+                "2024-02-29T00:00:00Z",
+            ),
+            (
+                QuotaPeriod::Monthly,
+                "2024-01-31T13:00:00Z",
+                "2024-02-01T00:00:00Z",
+            ),
+            (
+                QuotaPeriod::Monthly,
+                "2024-12-15T00:00:00Z",
+                "2025-01-01T00:00:00Z",
+            ),
+        ];
+        for (period, now, end) in cases {
+            assert_eq!(
+                period_end(period, at(now)),
+                Some(at(end)),
+                "{}",
+                now
+            );
+        }
+
+        assert_eq!(
+            period_end(
+                QuotaPeriod::Rolling(60),
+                Utc::now()
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn rolling_window_drops_old_slots() {
+        let now = at("2024-05-01T12:00:00Z");
+        let mut state = state(QuotaPeriod::Rolling(600), 0);
+        state.slots = VecDeque::from([
+            (now - Duration::seconds(900), 100),
+            (now - Duration::seconds(610), 20),
+            (now - Duration::seconds(300), 3),
+        ]);
+        state.used = 123;
+
+        roll(&mut state, now);
+
+        assert_eq!(state.used, 3);
+        assert_eq!(state.slots.len(), 1);
+    }
+
+    #[test]
+    fn rolling_usage_shares_slots() {
+        let quota = Quota::new(limit(
+            1000,
+            QuotaPeriod::Rolling(3600),
+        ));
+
+        quota.add_usage(10);
+        quota.add_usage(20);
+
+        let state = quota.state.lock();
+        assert_eq!(state.used, 30);
+        assert_eq!(state.slots.len(), 1);
+        assert_eq!(state.slots[0].1, 30);
+    }
+
+    #[test]
+    fn rolling_resets_when_the_oldest_slot_expires() {
+        let quota = Quota::new(limit(
+            1000,
+            QuotaPeriod::Rolling(3600),
+        ));
+        quota.add_usage(10);
+
+        let start = quota.state.lock().slots[0].0;
+        let usage = quota.usage();
+
+        assert_eq!(
+            usage.resets_at,
+            Some(start + Duration::seconds(60 + 3600))
+        );
+    }
+
+    #[test]
+    fn validate_rolling_window() {
+        for window in [0, MAX_ROLLING_WINDOW + 1] {
+            let quota = QuotaLimit {
+                bytes: 1,
+                period: QuotaPeriod::Rolling(window),
+            };
+            assert!(
+                quota.validate().is_err(),
+                "{}",
+                window
+            );
+        }
+
+        let quota = QuotaLimit {
+            bytes: 1,
+            period: QuotaPeriod::Rolling(
+                MAX_ROLLING_WINDOW,
+            ),
+        };
+        assert!(quota.validate().is_ok());
+    }
+}
@@ -0,0 +1,412 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+use super::quota::Quota;
+
+/// Throughput limit in bytes per second
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    // Bytes per second
+    pub rate: u64,
+    // Bytes that can pass at once after being idle,
+    // one second of traffic when not set
+    #[serde(default)]
+    pub burst: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn burst(&self) -> u64 {
+        self.burst.unwrap_or(self.rate)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rate == 0 {
+            return Err(
+                "rate must be greater than 0".to_string()
+            );
+        }
+        if self.burst == Some(0) {
+            return Err(
+                "burst must be greater than 0".to_string()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Rate limits of a proxy, `None` is unlimited
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimits {
+    // Shared by every connection of the proxy
+    #[serde(default)]
+    pub proxy: Option<RateLimit>,
+    // Applied to each connection on its own
+    #[serde(default)]
+    pub connection: Option<RateLimit>,
+}
+
+impl RateLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(limit) = &self.proxy {
+            limit
+                .validate()
+                .map_err(|_e| format!("proxy: {}", _e))?;
+        }
+        if let Some(limit) = &self.connection {
+            limit.validate().map_err(|_e| {
+                format!("connection: {}", _e)
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct BucketState {
+    limit: Option<RateLimit>,
+    // Negative while callers wait for tokens they already
+    // took
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket, the limit can be changed while connections
+/// use it
+#[derive(Debug)]
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(limit: Option<RateLimit>) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(BucketState {
+                limit,
+                tokens: limit
+                    .map_or(0.0, |l| l.burst() as f64),
+                last_refill: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn limit(&self) -> Option<RateLimit> {
+        self.state.lock().limit
+    }
+
+    pub fn set_limit(&self, limit: Option<RateLimit>) {
+        let mut state = self.state.lock();
+
+        state.limit = limit;
+        state.tokens =
+            limit.map_or(0.0, |l| l.burst() as f64);
+        state.last_refill = Instant::now();
+    }
+
+    /// Takes `n` tokens and returns how long the caller has
+    /// to wait before using them
+    fn reserve(&self, n: u64) -> Duration {
+        let mut state = self.state.lock();
+
+        let Some(limit) = state.limit else {
+            return Duration::ZERO;
+        };
+        let rate = limit.rate.max(1) as f64;
+
+        let now = Instant::now();
+        let elapsed = now
+            .duration_since(state.last_refill)
+            .as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate)
+            .min(limit.burst() as f64);
+        state.last_refill = now;
+
+        state.tokens -= n as f64;
+
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate)
+        }
+    }
+}
+
+/// Limits and counters relayed bytes pass: rate limit
+/// buckets, quotas, byte caps and plain counters
+#[derive(Debug, Clone, Default)]
+pub struct Limiters {
+    buckets: Vec<Arc<TokenBucket>>,
+    quotas: Vec<Arc<Quota>>,
+    // (used, max) such as the proxy bandwith cap
+    caps: Vec<(Arc<AtomicU64>, Arc<AtomicU64>)>,
+    counters: Vec<Arc<AtomicU64>>,
+}
+
+impl Limiters {
+    pub fn push(&mut self, bucket: Arc<TokenBucket>) {
+        self.buckets.push(bucket);
+    }
+
+    pub fn push_quota(&mut self, quota: Arc<Quota>) {
+        self.quotas.push(quota);
+    }
+
+    pub fn push_cap(
+        &mut self,
+        used: Arc<AtomicU64>,
+        max: Arc<AtomicU64>,
+    ) {
+        self.caps.push((used, max));
+    }
+
+    pub fn push_counter(
+        &mut self,
+        counter: Arc<AtomicU64>,
+    ) {
+        self.counters.push(counter);
+    }
+
+    pub fn quota_exceeded(&self) -> bool {
+        self.quotas.iter().any(|quota| quota.is_exceeded())
+    }
+
+    /// True when a quota or a byte cap is used up, relays
+    /// stop at that point
+    pub fn exhausted(&self) -> bool {
+        self.quota_exceeded()
+            || self.caps.iter().any(|(used, max)| {
+                used.load(Ordering::Relaxed)
+                    >= max.load(Ordering::Relaxed)
+            })
+    }
+
+    /// Counts `n` bytes and waits until they are allowed by
+    /// every bucket
+    pub async fn consume(&self, n: u64) {
+        self.quotas
+            .iter()
+            .for_each(|quota| quota.add_usage(n));
+        self.caps
+            .iter()
+            .map(|(used, _)| used)
+            .chain(&self.counters)
+            .for_each(|counter| {
+                counter.fetch_add(n, Ordering::Relaxed);
+            });
+
+        let wait = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.reserve(n))
+            .max()
+            .unwrap_or_default();
+
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(
+        rate: u64,
+        burst: Option<u64>,
+    ) -> Option<RateLimit> {
+        Some(RateLimit { rate, burst })
+    }
+
+    /// Pretends the last refill was `ago`
+    fn rewind(bucket: &TokenBucket, ago: Duration) {
+        bucket.state.lock().last_refill =
+            Instant::now() - ago;
+    }
+
+    #[test]
+    fn burst_passes_at_once() {
+        let bucket =
+            TokenBucket::new(limit(1000, Some(4000)));
+
+        assert_eq!(bucket.reserve(4000), Duration::ZERO);
+
+        let wait = bucket.reserve(1000);
+        assert!(
+            wait > Duration::from_millis(900)
+                && wait <= Duration::from_secs(1),
+            "{:?}",
+            wait
+        );
+    }
+
+    #[test]
+    fn burst_defaults_to_one_second() {
+        let bucket = TokenBucket::new(limit(1000, None));
+
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+        assert!(bucket.reserve(1) > Duration::ZERO);
+    }
+
+    #[test]
+    fn waits_grow_with_debt() {
+        let bucket =
+            TokenBucket::new(limit(1000, Some(1000)));
+        bucket.reserve(1000);
+
+        let first = bucket.reserve(500);
+        let second = bucket.reserve(500);
+
+        assert!(
+            second > first + Duration::from_millis(490)
+        );
+    }
+
+    #[test]
+    fn refills_with_time() {
+        let bucket =
+            TokenBucket::new(limit(1000, Some(1000)));
+        bucket.reserve(1000);
+
+        rewind(&bucket, Duration::from_millis(500));
+        assert_eq!(bucket.reserve(400), Duration::ZERO);
+        assert!(bucket.reserve(200) > Duration::ZERO);
+    }
+
+    #[test]
+    fn refill_stops_at_burst() {
+        let bucket =
+            TokenBucket::new(limit(1000, Some(1000)));
+
+        rewind(&bucket, Duration::from_secs(60));
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+        assert!(bucket.reserve(100) > Duration::ZERO);
+    }
+
+    #[test]
+    fn unlimited_never_waits() {
+        let bucket = TokenBucket::new(None);
+
+        assert_eq!(
+            bucket.reserve(u32::MAX as u64),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn new_limit_starts_full() {
+        let bucket =
+            TokenBucket::new(limit(1000, Some(1000)));
+        bucket.reserve(5000);
+
+        bucket.set_limit(limit(2000, Some(2000)));
+
+        assert_eq!(bucket.limit(), limit(2000, Some(2000)));
+        assert_eq!(bucket.reserve(2000), Duration::ZERO);
+
+        bucket.set_limit(None);
+        assert_eq!(
+            bucket.reserve(1_000_000),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn validate() {
+        assert!(RateLimit {
+            rate: 0,
+            burst: None
+        }
+        .validate()
+        .is_err());
+        assert!(RateLimit {
+            rate: 1,
+            burst: Some(0)
+        }
+        .validate()
+        .is_err());
+
+        let limits = RateLimits {
+            proxy: None,
+            connection: Some(RateLimit {
+                rate: 0,
+                burst: None,
+            }),
+        };
+        assert_eq!(
+            limits.validate(),
+            Err("connection: rate must be greater than 0"
+                .to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn consume_counts_and_caps() {
+        let used = Arc::new(AtomicU64::new(0));
+        let max = Arc::new(AtomicU64::new(100));
+        let counter = Arc::new(AtomicU64::new(0));
+
+        let mut limiters = Limiters::default();
+        limiters
+            .push_cap(Arc::clone(&used), Arc::clone(&max));
+        limiters.push_counter(Arc::clone(&counter));
+
+        limiters.consume(60).await;
+        assert_eq!(used.load(Ordering::Relaxed), 60);
+        assert_eq!(counter.load(Ordering::Relaxed), 60);
+        assert!(!limiters.exhausted());
+
+        limiters.consume(40).await;
+        assert!(limiters.exhausted());
+
+        max.store(200, Ordering::Relaxed);
+        assert!(!limiters.exhausted());
+    }
+
+    #[tokio::test]
+    async fn consume_waits_for_the_slowest_bucket() {
+        let mut limiters = Limiters::default();
+        limiters
+            .push(TokenBucket::new(limit(1_000_000, None)));
+        limiters.push(TokenBucket::new(limit(
+            1000,
+            Some(1000),
+        )));
+
+        let started = Instant::now();
+        limiters.consume(1100).await;
+
+        assert!(
+            started.elapsed() >= Duration::from_millis(90)
+        );
+    }
+}
@@ -0,0 +1,659 @@
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::IpNet;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::bind::PortRange;
+
+/// What a matching rule does with a connection
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    Allow,
+    Deny,
+}
+
+/// Destination rule, it matches when every field that is
+/// set does. A rule with only an action matches anything.
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub action: RuleAction,
+    // Prefix of the resolved address, `10.0.0.0/8`, a bare
+    // address matches only itself
+    #[serde(default)]
+    pub cidr: Option<String>,
+    // Destination ports, both ends included
+    #[serde(default)]
+    pub ports: Option<PortRange>,
+    // Requested host name, `*.example.com` for any of its
+    // subdomains
+    #[serde(default)]
+    pub domain: Option<String>,
+    // Has to match the whole requested host name, which is
+    // lowercase
+    #[serde(default)]
+    pub regex: Option<String>,
+}
+
+impl Rule {
+    pub fn validate(&self) -> Result<(), String> {
+        Matcher::compile(self).map(|_| ())
+    }
+}
+
+#[derive(Debug)]
+enum DomainPattern {
+    Exact(String),
+    // `.example.com` of `*.example.com`
+    Suffix(String),
+}
+
+impl DomainPattern {
+    fn parse(domain: &str) -> Result<Self, String> {
+        let domain = normalize(domain);
+        let (pattern, name) = match domain
+            .strip_prefix("*.")
+        {
+            Some(name) => (
+                DomainPattern::Suffix(format!(".{}", name)),
+                name,
+            ),
+            None => (
+                DomainPattern::Exact(domain.clone()),
+                domain.as_str(),
+            ),
+        };
+
+        if name.is_empty()
+            || name.contains('*')
+            || name.split('.').any(str::is_empty)
+        {
+            return Err(format!(
+                "invalid domain `{}`",
+                domain
+            ));
+        }
+
+        Ok(pattern)
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            DomainPattern::Exact(name) => host == name,
+            DomainPattern::Suffix(suffix) => {
+                host.len() > suffix.len()
+                    && host.ends_with(suffix.as_str())
+            }
+        }
+    }
+}
+
+/// `10.0.0.0/8`, a bare address is a prefix of its own
+pub fn parse_cidr(cidr: &str) -> Result<IpNet, String> {
+    cidr.parse::<IpNet>()
+        .or_else(|_| {
+            cidr.parse::<IpAddr>().map(IpNet::from)
+        })
+        .map_err(|_e| format!("invalid cidr `{}`", cidr))
+}
+
+/// Host names compare lowercase and without the root dot
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+/// A rule ready to be matched
+#[derive(Debug)]
+struct Matcher {
+    cidr: Option<IpNet>,
+    ports: Option<PortRange>,
+    domain: Option<DomainPattern>,
+    regex: Option<Regex>,
+}
+
+impl Matcher {
+    fn compile(rule: &Rule) -> Result<Self, String> {
+        let cidr = rule
+            .cidr
+            .as_deref()
+            .map(parse_cidr)
+            .transpose()?;
+
+        if let Some(ports) = &rule.ports {
+            if ports.start > ports.end {
+                return Err(format!(
+                    "invalid port range {}-{}",
+                    ports.start, ports.end
+                ));
+            }
+        }
+
+        let domain = rule
+            .domain
+            .as_deref()
+            .map(DomainPattern::parse)
+            .transpose()?;
+
+        let regex = rule
+            .regex
+            .as_deref()
+            .map(|regex| {
+                // Checked on its own first so errors point
+                // into the pattern as written
+                Regex::new(regex)
+                    .and_then(|_| {
+                        Regex::new(&format!(
+                            "^(?:{})$",
+                            regex
+                        ))
+                    })
+                    .map_err(|_e| {
+                        format!(
+                            "invalid regex `{}`: {}",
+                            regex, _e
+                        )
+                    })
+            })
+            .transpose()?;
+
+        Ok(Self {
+            cidr,
+            ports: rule.ports,
+            domain,
+            regex,
+        })
+    }
+
+    /// `None` when it depends on an address not known yet
+    fn matches(&self, dst: &Destination) -> Option<bool> {
+        if let Some(ports) = &self.ports {
+            if dst.port < ports.start
+                || dst.port > ports.end
+            {
+                return Some(false);
+            }
+        }
+
+        if self.domain.is_some() || self.regex.is_some() {
+            // Requests for an address have no host name
+            let Some(host) = dst.host else {
+                return Some(false);
+            };
+            let host = normalize(host);
+
+            if self
+                .domain
+                .as_ref()
+                .is_some_and(|d| !d.matches(&host))
+                || self
+                    .regex
+                    .as_ref()
+                    .is_some_and(|r| !r.is_match(&host))
+            {
+                return Some(false);
+            }
+        }
+
+        match (&self.cidr, dst.ip) {
+            (Some(_), None) => None,
+            // `::ffff:a.b.c.d` reaches a.b.c.d
+            (Some(cidr), Some(ip)) => Some(
+                cidr.contains(&ip)
+                    || cidr.contains(&ip.to_canonical()),
+            ),
+            (None, _) => Some(true),
+        }
+    }
+}
+
+/// What rules are matched against, a host name has no
+/// address until it is resolved
+#[derive(Debug, Clone, Copy)]
+pub struct Destination<'a> {
+    pub host: Option<&'a str>,
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+}
+
+impl<'a> Destination<'a> {
+    /// Requested host name, before it is resolved
+    pub fn host(host: &'a str, port: u16) -> Self {
+        Self {
+            host: Some(host),
+            ip: None,
+            port,
+        }
+    }
+
+    /// An address to connect to, along with the host name
+    /// it was resolved from
+    pub fn resolved(
+        host: Option<&'a str>,
+        addrs: &SocketAddr,
+    ) -> Self {
+        Self {
+            host,
+            ip: Some(addrs.ip()),
+            port: addrs.port(),
+        }
+    }
+}
+
+/// Outcome of the rules for a destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Matched(RuleAction),
+    NoMatch,
+    // A rule on the address comes first, only known once
+    // the host name is resolved
+    NeedsAddress,
+}
+
+/// Ordered rules, the first matching one decides
+#[derive(Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<(Rule, Matcher)>,
+}
+
+impl RuleSet {
+    pub fn compile(
+        rules: Vec<Rule>,
+    ) -> Result<Self, String> {
+        let rules = rules
+            .into_iter()
+            .enumerate()
+            .map(|(idx, rule)| {
+                let matcher = Matcher::compile(&rule)
+                    .map_err(|_e| {
+                        format!("rules[{}]: {}", idx, _e)
+                    })?;
+                Ok((rule, matcher))
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self { rules })
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.rules
+            .iter()
+            .map(|(rule, _)| rule.clone())
+            .collect()
+    }
+
+    pub fn evaluate(&self, dst: &Destination) -> Verdict {
+        for (rule, matcher) in &self.rules {
+            match matcher.matches(dst) {
+                Some(true) => {
+                    return Verdict::Matched(rule.action)
+                }
+                Some(false) => {}
+                None => return Verdict::NeedsAddress,
+            }
+        }
+
+        Verdict::NoMatch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: RuleAction) -> Rule {
+        Rule {
+            action,
+            cidr: None,
+            ports: None,
+            domain: None,
+            regex: None,
+        }
+    }
+
+    fn allow() -> Rule {
+        rule(RuleAction::Allow)
+    }
+
+    fn deny() -> Rule {
+        rule(RuleAction::Deny)
+    }
+
+    fn resolved<'a>(
+        host: Option<&'a str>,
+        addrs: &str,
+    ) -> Destination<'a> {
+        Destination::resolved(host, &addrs.parse().unwrap())
+    }
+
+    const DENY: Verdict =
+        Verdict::Matched(RuleAction::Deny);
+    const ALLOW: Verdict =
+        Verdict::Matched(RuleAction::Allow);
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = RuleSet::compile(vec![
+            Rule {
+                domain: Some("intranet.example.com".into()),
+                ..allow()
+            },
+            Rule {
+                domain: Some("*.example.com".into()),
+                ..deny()
+            },
+        ])
+        .unwrap();
+
+        let dst =
+            Destination::host("intranet.example.com", 80);
+        assert_eq!(rules.evaluate(&dst), ALLOW);
+        let dst = Destination::host("www.example.com", 80);
+        assert_eq!(rules.evaluate(&dst), DENY);
+        let dst = Destination::host("example.org", 80);
+        assert_eq!(rules.evaluate(&dst), Verdict::NoMatch);
+    }
+
+    #[test]
+    fn empty_rules_match_nothing() {
+        let rules = RuleSet::default();
+
+        let dst = resolved(None, "10.0.0.1:80");
+        assert_eq!(rules.evaluate(&dst), Verdict::NoMatch);
+    }
+
+    #[test]
+    fn rule_without_fields_matches_anything() {
+        let rules = RuleSet::compile(vec![deny()]).unwrap();
+
+        let dst = Destination::host("example.com", 443);
+        assert_eq!(rules.evaluate(&dst), DENY);
+    }
+
+    #[test]
+    fn cidr() {
+        let rules = RuleSet::compile(vec![
+            Rule {
+                cidr: Some("10.0.0.0/8".into()),
+                ..deny()
+            },
+            Rule {
+                cidr: Some("2001:db8::1".into()),
+                ..deny()
+            },
+        ])
+        .unwrap();
+
+        let cases = [
+            ("10.1.2.3:80", DENY),
+            ("11.0.0.1:80", Verdict::NoMatch),
+            ("[2001:db8::1]:80", DENY),
+            ("[2001:db8::2]:80", Verdict::NoMatch),
+        ];
+        for (addrs, verdict) in cases {
+            let dst = resolved(None, addrs);
+            assert_eq!(
+                rules.evaluate(&dst),
+                verdict,
+                "{}",
+                addrs
+            );
+        }
+    }
+
+    #[test]
+    fn cidr_matches_mapped_addresses() {
+        let rules = RuleSet::compile(vec![Rule {
+            cidr: Some("10.0.0.0/8".into()),
+            ..deny()
+        }])
+        .unwrap();
+
+        let dst = resolved(None, "[::ffff:10.0.0.1]:80");
+        assert_eq!(rules.evaluate(&dst), DENY);
+        let dst = resolved(None, "[::ffff:11.0.0.1]:80");
+        assert_eq!(rules.evaluate(&dst), Verdict::NoMatch);
+    }
+
+    #[test]
+    fn cidr_needs_the_address() {
+        let rules = RuleSet::compile(vec![
+            Rule {
+                cidr: Some("10.0.0.0/8".into()),
+                ..deny()
+            },
+            Rule {
+                domain: Some("example.com".into()),
+                ..allow()
+            },
+        ])
+        .unwrap();
+
+        let dst = Destination::host("example.com", 80);
+        assert_eq!(
+            rules.evaluate(&dst),
+            Verdict::NeedsAddress
+        );
+
+        let dst =
+            resolved(Some("example.com"), "10.0.0.1:80");
+        assert_eq!(rules.evaluate(&dst), DENY);
+        let dst =
+            resolved(Some("example.com"), "1.1.1.1:80");
+        assert_eq!(rules.evaluate(&dst), ALLOW);
+    }
+
+    #[test]
+    fn ports_include_both_ends() {
+        let rules = RuleSet::compile(vec![Rule {
+            ports: Some(PortRange { start: 25, end: 27 }),
+            ..deny()
+        }])
+        .unwrap();
+
+        for (port, verdict) in [
+            (24, Verdict::NoMatch),
+            (25, DENY),
+            (27, DENY),
+            (28, Verdict::NoMatch),
+        ] {
+            let dst =
+                Destination::host("mail.example.com", port);
+            assert_eq!(
+                rules.evaluate(&dst),
+                verdict,
+                "{}",
+                port
+            );
+        }
+    }
+
+    #[test]
+    fn every_field_has_to_match() {
+        let rules = RuleSet::compile(vec![Rule {
+            cidr: Some("127.0.0.0/8".into()),
+            ports: Some(PortRange {
+                start: 9100,
+                end: 9100,
+            }),
+            ..deny()
+        }])
+        .unwrap();
+
+        let dst = resolved(None, "127.0.0.1:9100");
+        assert_eq!(rules.evaluate(&dst), DENY);
+        let dst = resolved(None, "127.0.0.1:9101");
+        assert_eq!(rules.evaluate(&dst), Verdict::NoMatch);
+        let dst = resolved(None, "10.0.0.1:9100");
+        assert_eq!(rules.evaluate(&dst), Verdict::NoMatch);
+    }
+
+    #[test]
+    fn domain_patterns() {
+        let rules = RuleSet::compile(vec![
+            Rule {
+                domain: Some("Example.COM.".into()),
+                ..deny()
+            },
+            Rule {
+                domain: Some("*.evil.test".into()),
+                ..deny()
+            },
+        ])
+        .unwrap();
+
+        let cases = [
+            ("example.com", DENY),
+            ("EXAMPLE.com.", DENY),
+            ("www.example.com", Verdict::NoMatch),
+            ("a.evil.test", DENY),
+            ("a.b.evil.test", DENY),
+            ("evil.test", Verdict::NoMatch),
+            ("notevil.test", Verdict::NoMatch),
+        ];
+        for (host, verdict) in cases {
+            let dst = Destination::host(host, 80);
+            assert_eq!(
+                rules.evaluate(&dst),
+                verdict,
+                "{}",
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn host_rules_skip_address_requests() {
+        let rules = RuleSet::compile(vec![Rule {
+            domain: Some("*.example.com".into()),
+            ..deny()
+        }])
+        .unwrap();
+
+        let dst = resolved(None, "93.184.216.34:80");
+        assert_eq!(rules.evaluate(&dst), Verdict::NoMatch);
+    }
+
+    #[test]
+    fn regex_matches_the_whole_host() {
+        let rules = RuleSet::compile(vec![Rule {
+            regex: Some(
+                "ads?[0-9]*\\.example\\.com".into(),
+            ),
+            ..deny()
+        }])
+        .unwrap();
+
+        let cases = [
+            ("ad.example.com", DENY),
+            ("ads12.example.com", DENY),
+            ("ADS1.example.com", DENY),
+            ("bads.example.com", Verdict::NoMatch),
+            ("ad.example.com.evil", Verdict::NoMatch),
+        ];
+        for (host, verdict) in cases {
+            let dst = Destination::host(host, 80);
+            assert_eq!(
+                rules.evaluate(&dst),
+                verdict,
+                "{}",
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let cases = [
+            (
+                Rule {
+                    cidr: Some("10.0.0.0/33".into()),
+                    ..deny()
+                },
+                "invalid cidr `10.0.0.0/33`",
+            ),
+            (
+                Rule {
+                    ports: Some(PortRange {
+                        start: 9,
+                        end: 1,
+                    }),
+                    ..deny()
+                },
+                "invalid port range 9-1",
+            ),
+            (
+                Rule {
+                    domain: Some("*.".into()),
+                    ..deny()
+                },
+                "invalid domain `*`",
+            ),
+            (
+                Rule {
+                    domain: Some("a.*.com".into()),
+                    ..deny()
+                },
+                "invalid domain `a.*.com`",
+            ),
+            (
+                Rule {
+                    domain: Some("a..com".into()),
+                    ..deny()
+                },
+                "invalid domain `a..com`",
+            ),
+        ];
+        for (rule, msg) in cases {
+            assert_eq!(
+                rule.validate(),
+                Err(msg.to_string())
+            );
+        }
+
+        let err = Rule {
+            regex: Some("(".into()),
+            ..deny()
+        }
+        .validate()
+        .unwrap_err();
+        assert!(
+            err.starts_with("invalid regex `(`"),
+            "{}",
+            err
+        );
+
+        let err = RuleSet::compile(vec![
+            allow(),
+            Rule {
+                cidr: Some("nope".into()),
+                ..deny()
+            },
+        ])
+        .unwrap_err();
+        assert_eq!(err, "rules[1]: invalid cidr `nope`");
+    }
+
+    #[test]
+    fn bare_address_is_a_prefix() {
+        assert_eq!(
+            parse_cidr("10.0.0.1"),
+            Ok("10.0.0.1/32".parse().unwrap())
+        );
+        assert_eq!(
+            parse_cidr("::1"),
+            Ok("::1/128".parse().unwrap())
+        );
+    }
+}
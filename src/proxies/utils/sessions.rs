@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::{
+    sync::{oneshot, Notify},
+    task::AbortHandle,
+    time,
+};
+
+/// What happens to established sessions when a proxy stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopSessions {
+    // Let them run until they close
+    Keep,
+    // Wait for them up to the deadline, abort the rest
+    Drain(Duration),
+    Abort,
+}
+
+/// Live counters of a session
+#[derive(Debug)]
+pub struct SessionStats {
+    client: SocketAddr,
+    started_at: DateTime<Utc>,
+    user: Mutex<Option<String>>,
+    // Bytes relayed in both directions
+    bytes: Arc<AtomicU64>,
+}
+
+impl SessionStats {
+    fn new(client: SocketAddr) -> Arc<Self> {
+        Arc::new(Self {
+            client,
+            started_at: Utc::now(),
+            user: Mutex::new(None),
+            bytes: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn set_user(&self, user_name: &str) {
+        *self.user.lock() = Some(user_name.to_string());
+    }
+
+    pub fn bytes(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.bytes)
+    }
+
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            client: self.client,
+            user: self.user.lock().clone(),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            started_at: self.started_at,
+        }
+    }
+}
+
+/// Snapshot of a session as exposed by the api
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub client: SocketAddr,
+    pub user: Option<String>,
+    pub bytes: u64,
+    pub started_at: DateTime<Utc>,
+}
+
+/// Tasks serving accepted connections of a proxy
+#[derive(Debug, Default)]
+pub struct Sessions {
+    tasks: Mutex<
+        HashMap<u64, (AbortHandle, Arc<SessionStats>)>,
+    >,
+    next_id: AtomicU64,
+    // Notified each time a session ends
+    closed: Notify,
+}
+
+// Removes a session when its task ends or is aborted
+struct SessionGuard {
+    sessions: Arc<Sessions>,
+    id: u64,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.tasks.lock().remove(&self.id);
+        self.sessions.closed.notify_waiters();
+    }
+}
+
+impl Sessions {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Spawns a session task for a client on the current
+    /// runtime, the task gets the stats it should update
+    pub fn spawn<F, S>(
+        self: &Arc<Self>,
+        client: SocketAddr,
+        session: F,
+    ) where
+        F: FnOnce(Arc<SessionStats>) -> S,
+        S: Future<Output = ()> + Send + 'static,
+    {
+        let stats = SessionStats::new(client);
+        let session = session(Arc::clone(&stats));
+
+        let id =
+            self.next_id.fetch_add(1, Ordering::Relaxed);
+        let guard = SessionGuard {
+            sessions: Arc::clone(self),
+            id,
+        };
+
+        // Sessions wait until their handle is stored, so one
+        // ending right away can not leave a stale entry
+        let (registered, wait_registered) =
+            oneshot::channel();
+        let task = tokio::spawn(async move {
+            let _guard = guard;
+            if wait_registered.await.is_ok() {
+                session.await;
+            }
+        });
+
+        self.tasks
+            .lock()
+            .insert(id, (task.abort_handle(), stats));
+        let _ = registered.send(());
+    }
+
+    pub fn active(&self) -> usize {
+        self.tasks.lock().len()
+    }
+
+    /// Active sessions, oldest first
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .tasks
+            .lock()
+            .values()
+            .map(|(_, stats)| stats.info())
+            .collect();
+
+        sessions.sort_by_key(|s| s.started_at);
+        sessions
+    }
+
+    pub fn abort_all(&self) {
+        let tasks: Vec<AbortHandle> = self
+            .tasks
+            .lock()
+            .drain()
+            .map(|(_, (task, _))| task)
+            .collect();
+
+        tasks.iter().for_each(AbortHandle::abort);
+    }
+
+    /// Waits until every session ended, false when the
+    /// deadline passed first
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        time::timeout(deadline, async {
+            loop {
+                let closed = self.closed.notified();
+                tokio::pin!(closed);
+                closed.as_mut().enable();
+
+                if self.active() == 0 {
+                    return;
+                }
+
+                closed.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    pub async fn close(&self, mode: StopSessions) {
+        match mode {
+            StopSessions::Keep => {}
+            StopSessions::Drain(deadline) => {
+                if !self.drain(deadline).await {
+                    self.abort_all();
+                }
+            }
+            StopSessions::Abort => self.abort_all(),
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use ipnet::IpNet;
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::rules::parse_cidr;
+
+/// Keeps clients away from destinations inside the network
+/// of the proxy, loopback, private, link-local, multicast
+/// and unspecified addresses are refused
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct SsrfProtection {
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    // Prefixes connected to anyway, `10.1.0.0/16`
+    #[serde(default)]
+    pub exceptions: Vec<String>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Default for SsrfProtection {
+    fn default() -> Self {
+        Self {
+            enabled: enabled(),
+            exceptions: Vec::new(),
+        }
+    }
+}
+
+impl SsrfProtection {
+    pub fn validate(&self) -> Result<(), String> {
+        SsrfGuard::compile(self.clone()).map(|_| ())
+    }
+}
+
+/// A `SsrfProtection` ready to be checked
+#[derive(Debug, Default)]
+pub struct SsrfGuard {
+    options: SsrfProtection,
+    exceptions: Vec<IpNet>,
+}
+
+impl SsrfGuard {
+    pub fn compile(
+        options: SsrfProtection,
+    ) -> Result<Self, String> {
+        let exceptions = options
+            .exceptions
+            .iter()
+            .enumerate()
+            .map(|(idx, cidr)| {
+                parse_cidr(cidr).map_err(|_e| {
+                    format!("exceptions[{}]: {}", idx, _e)
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self {
+            options,
+            exceptions,
+        })
+    }
+
+    pub fn options(&self) -> SsrfProtection {
+        self.options.clone()
+    }
+
+    /// Range of an address the proxy must not connect to,
+    /// `None` when it may. IPv4 addresses carried in IPv6
+    /// ones are checked as IPv4.
+    pub fn refuses(
+        &self,
+        ip: &IpAddr,
+    ) -> Option<&'static str> {
+        if !self.options.enabled {
+            return None;
+        }
+
+        let unwrapped = unwrap_v4(ip);
+        let range = match unwrapped {
+            IpAddr::V4(v4) => v4_range(&v4),
+            IpAddr::V6(v6) => v6_range(&v6),
+        }?;
+
+        if self.exceptions.iter().any(|e| {
+            e.contains(ip) || e.contains(&unwrapped)
+        }) {
+            return None;
+        }
+
+        Some(range)
+    }
+}
+
+/// IPv4 address carried in an IPv6 one, `::ffff:a.b.c.d`,
+/// `::a.b.c.d`, NAT64 `64:ff9b::a.b.c.d` and 6to4
+/// `2002:aabb:ccdd::` all reach a.b.c.d
+fn unwrap_v4(ip: &IpAddr) -> IpAddr {
+    let IpAddr::V6(v6) = ip else {
+        return *ip;
+    };
+    let seg = v6.segments();
+    let embedded = |hi: u16, lo: u16| {
+        IpAddr::V4(Ipv4Addr::from(
+            (u32::from(hi) << 16) | u32::from(lo),
+        ))
+    };
+
+    match seg {
+        // `::` and `::1` are not IPv4 compatible ones
+        [0, 0, 0, 0, 0, 0, 0, 0 | 1] => *ip,
+        [0, 0, 0, 0, 0, 0 | 0xffff, hi, lo] => {
+            embedded(hi, lo)
+        }
+        [0x64, 0xff9b, 0, 0, 0, 0, hi, lo] => {
+            embedded(hi, lo)
+        }
+        [0x2002, hi, lo, ..] => embedded(hi, lo),
+        _ => *ip,
+    }
+}
+
+fn v4_range(ip: &Ipv4Addr) -> Option<&'static str> {
+    let [a, b, ..] = ip.octets();
+
+    if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_private() {
+        Some("private")
+    } else if a == 100 && (b & 0xc0) == 64 {
+        // 100.64.0.0/10, carrier-grade NAT
+        Some("shared")
+    } else if ip.is_link_local() {
+        Some("link_local")
+    } else if ip.is_multicast() {
+        Some("multicast")
+    } else if ip.is_broadcast() {
+        Some("broadcast")
+    } else if a == 0 {
+        // Connecting to 0.0.0.0/8 reaches the local host
+        Some("unspecified")
+    } else {
+        None
+    }
+}
+
+fn v6_range(ip: &Ipv6Addr) -> Option<&'static str> {
+    if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_unique_local() {
+        Some("private")
+    } else if (ip.segments()[0] & 0xffc0) == 0xfec0 {
+        // fec0::/10, deprecated site-local
+        Some("private")
+    } else if ip.is_unicast_link_local() {
+        Some("link_local")
+    } else if ip.is_multicast() {
+        Some("multicast")
+    } else if ip.is_unspecified() {
+        Some("unspecified")
+    } else {
+        None
+    }
+}
+
+/// Whether `dst` reaches a listener bound to `listener`,
+/// one bound to an unspecified address listens on every
+/// local address
+pub fn reaches(
+    listener: &SocketAddr,
+    dst: &SocketAddr,
+) -> bool {
+    if listener.port() != dst.port() {
+        return false;
+    }
+
+    let ip = unwrap_v4(&dst.ip());
+    if ip == unwrap_v4(&listener.ip()) {
+        return true;
+    }
+
+    // Only local addresses can be bound
+    listener.ip().is_unspecified()
+        && (ip.is_unspecified()
+            || std::net::UdpSocket::bind((ip, 0)).is_ok())
+}
+
+/// Logs and counts a destination the guard refused
+pub fn ssrf_refused(
+    addr: &SocketAddr,
+    range: &'static str,
+) {
+    info!("Refused {} address {}", range, addr);
+    counter!("proxier_ssrf_refused_total", "range" => range)
+        .increment(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(exceptions: &[&str]) -> SsrfGuard {
+        SsrfGuard::compile(SsrfProtection {
+            enabled: true,
+            exceptions: exceptions
+                .iter()
+                .map(|e| e.to_string())
+                .collect(),
+        })
+        .unwrap()
+    }
+
+    fn refuses(
+        guard: &SsrfGuard,
+        ip: &str,
+    ) -> Option<&'static str> {
+        guard.refuses(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn refuses_every_range() {
+        let guard = guard(&[]);
+        let cases = [
+            ("127.0.0.1", "loopback"),
+            ("127.255.0.9", "loopback"),
+            ("::1", "loopback"),
+            ("10.1.2.3", "private"),
+            ("172.16.0.1", "private"),
+            ("172.31.255.255", "private"),
+            ("192.168.1.1", "private"),
+            ("fd00::1", "private"),
+            ("fec0::1", "private"),
+            ("100.64.0.1", "shared"),
+            ("100.127.255.255", "shared"),
+            ("169.254.169.254", "link_local"),
+            ("fe80::1", "link_local"),
+            ("224.0.0.1", "multicast"),
+            ("239.255.255.250", "multicast"),
+            ("ff02::1", "multicast"),
+            ("255.255.255.255", "broadcast"),
+            ("0.0.0.0", "unspecified"),
+            ("0.1.2.3", "unspecified"),
+            ("::", "unspecified"),
+        ];
+        for (ip, range) in cases {
+            assert_eq!(
+                refuses(&guard, ip),
+                Some(range),
+                "{}",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        let guard = guard(&[]);
+        for ip in [
+            "1.1.1.1",
+            "100.63.255.255",
+            "100.128.0.0",
+            "172.32.0.1",
+            "2606:4700::1111",
+            "64:ff9b::101:101",
+            "2002:101:101::1",
+        ] {
+            assert_eq!(refuses(&guard, ip), None, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn unwraps_ipv4_in_ipv6() {
+        let guard = guard(&[]);
+        let cases = [
+            ("::ffff:127.0.0.1", "loopback"),
+            ("::ffff:169.254.169.254", "link_local"),
+            ("::10.0.0.1", "private"),
+            ("::2", "unspecified"),
+            ("64:ff9b::7f00:1", "loopback"),
+            ("64:ff9b::a9fe:a9fe", "link_local"),
+            ("2002:c0a8:101::1", "private"),
+            ("2002:6440:1::", "shared"),
+        ];
+        for (ip, range) in cases {
+            assert_eq!(
+                refuses(&guard, ip),
+                Some(range),
+                "{}",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn exceptions_allow_their_prefix() {
+        let guard = guard(&[
+            "10.1.0.0/16",
+            "127.0.0.1",
+            "fd00::/8",
+        ]);
+
+        assert_eq!(refuses(&guard, "10.1.2.3"), None);
+        assert_eq!(
+            refuses(&guard, "10.2.0.1"),
+            Some("private")
+        );
+        assert_eq!(refuses(&guard, "127.0.0.1"), None);
+        assert_eq!(
+            refuses(&guard, "::ffff:127.0.0.1"),
+            None
+        );
+        assert_eq!(
+            refuses(&guard, "127.0.0.2"),
+            Some("loopback")
+        );
+        assert_eq!(refuses(&guard, "fd12::1"), None);
+        assert_eq!(
+            refuses(&guard, "fc00::1"),
+            Some("private")
+        );
+    }
+
+    #[test]
+    fn disabled_allows_everything() {
+        let guard = SsrfGuard::compile(SsrfProtection {
+            enabled: false,
+            exceptions: Vec::new(),
+        })
+        .unwrap();
+
+        assert_eq!(refuses(&guard, "127.0.0.1"), None);
+        assert_eq!(
+            refuses(&guard, "169.254.169.254"),
+            None
+        );
+    }
+
+    #[test]
+    fn enabled_by_default() {
+        let guard = SsrfGuard::default();
+
+        assert_eq!(
+            refuses(&guard, "127.0.0.1"),
+            Some("loopback")
+        );
+    }
+
+    #[test]
+    fn invalid_exception() {
+        let err = SsrfProtection {
+            enabled: true,
+            exceptions: vec![
+                "10.0.0.0/8".into(),
+                "nope".into(),
+            ],
+        }
+        .validate()
+        .unwrap_err();
+
+        assert_eq!(
+            err,
+            "exceptions[1]: invalid cidr `nope`"
+        );
+    }
+
+    #[test]
+    fn reaches_the_listener() {
+        let addrs =
+            |a: &str| a.parse::<SocketAddr>().unwrap();
+        let bound = addrs("127.0.0.1:9090");
+
+        assert!(reaches(&bound, &addrs("127.0.0.1:9090")));
+        assert!(reaches(
+            &bound,
+            &addrs("[::ffff:127.0.0.1]:9090")
+        ));
+        assert!(!reaches(&bound, &addrs("127.0.0.1:9091")));
+        assert!(!reaches(&bound, &addrs("127.0.0.2:9090")));
+
+        let public = addrs("203.0.113.7:9090");
+        assert!(reaches(
+            &public,
+            &addrs("203.0.113.7:9090")
+        ));
+        assert!(!reaches(
+            &public,
+            &addrs("203.0.113.8:9090")
+        ));
+
+        let any = addrs("0.0.0.0:9090");
+        assert!(reaches(&any, &addrs("127.0.0.1:9090")));
+        assert!(reaches(&any, &addrs("0.0.0.0:9090")));
+        assert!(!reaches(&any, &addrs("192.0.2.1:9090")));
+        assert!(!reaches(&any, &addrs("127.0.0.1:80")));
+    }
+}
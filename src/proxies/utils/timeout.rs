@@ -0,0 +1,320 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant},
+};
+
+use super::{
+    io::copy_bidirectional_limited, rate_limit::Limiters,
+};
+
+const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_IDLE_TIMEOUT: u64 = 300;
+// One year, deadlines past it would overflow `Instant`
+const MAX_TIMEOUT: u64 = 365 * 24 * 60 * 60;
+
+/// Timeouts of a proxy in seconds, 0 disables `idle` and
+/// `max_lifetime`
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    // From accepting a client until its request is read
+    #[serde(default = "default_handshake")]
+    pub handshake: u64,
+    // Establishing the outbound connection
+    #[serde(default = "default_connect")]
+    pub connect: u64,
+    // Relay without traffic in either direction
+    #[serde(default = "default_idle")]
+    pub idle: u64,
+    // Relay duration, whatever the traffic
+    #[serde(default)]
+    pub max_lifetime: u64,
+}
+
+fn default_handshake() -> u64 {
+    DEFAULT_HANDSHAKE_TIMEOUT
+}
+
+fn default_connect() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT
+}
+
+fn default_idle() -> u64 {
+    DEFAULT_IDLE_TIMEOUT
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: default_handshake(),
+            connect: default_connect(),
+            idle: default_idle(),
+            max_lifetime: 0,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("handshake", self.handshake),
+            ("connect", self.connect),
+            ("idle", self.idle),
+            ("max_lifetime", self.max_lifetime),
+        ] {
+            if value > MAX_TIMEOUT {
+                return Err(format!(
+                    "{} must be at most {}",
+                    name, MAX_TIMEOUT
+                ));
+            }
+        }
+
+        if self.handshake == 0 {
+            return Err("handshake must be greater than 0"
+                .to_string());
+        }
+        if self.connect == 0 {
+            return Err("connect must be greater than 0"
+                .to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn handshake(&self) -> Duration {
+        Duration::from_secs(self.handshake)
+    }
+
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        (self.idle > 0)
+            .then(|| Duration::from_secs(self.idle))
+    }
+
+    pub fn max_lifetime(&self) -> Option<Duration> {
+        (self.max_lifetime > 0)
+            .then(|| Duration::from_secs(self.max_lifetime))
+    }
+}
+
+/// Error of a timeout that fired, counted by `kind`, one of
+/// the `Timeouts` fields
+pub fn timed_out(kind: &'static str) -> io::Error {
+    counter!("proxier_timeouts_total", "kind" => kind)
+        .increment(1);
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("{} timeout", kind),
+    )
+}
+
+/// Last time any of the tracked streams read data
+#[derive(Debug)]
+pub struct Activity {
+    started: Instant,
+    // Milliseconds since `started`
+    last: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            started: Instant::now(),
+            last: AtomicU64::new(0),
+        })
+    }
+
+    fn touch(&self) {
+        let elapsed =
+            self.started.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.started
+            + Duration::from_millis(
+                self.last.load(Ordering::Relaxed),
+            )
+    }
+
+    /// Resolves once nothing was read for `idle`
+    pub async fn idle(&self, idle: Duration) {
+        loop {
+            let deadline = self.last() + idle;
+            if deadline <= Instant::now() {
+                return;
+            }
+            time::sleep_until(deadline).await;
+        }
+    }
+}
+
+/// Stream that records every read in an `Activity`
+#[derive(Debug)]
+pub struct Tracked<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S> Tracked<S> {
+    pub fn new(inner: S, activity: &Arc<Activity>) -> Self {
+        Self {
+            inner,
+            activity: Arc::clone(activity),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll =
+            Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.activity.touch();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// `copy_bidirectional_limited` ended by the idle timeout
+/// and the maximum lifetime with a `TimedOut` error
+pub async fn copy_bidirectional_timeout<A, B>(
+    a: A,
+    b: B,
+    limiters: &Limiters,
+    timeouts: &Timeouts,
+) -> io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let activity = Activity::new();
+    let relay = copy_bidirectional_limited(
+        Tracked::new(a, &activity),
+        Tracked::new(b, &activity),
+        limiters,
+    );
+
+    let idle = async {
+        match timeouts.idle() {
+            Some(idle) => activity.idle(idle).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let lifetime = async {
+        match timeouts.max_lifetime() {
+            Some(lifetime) => time::sleep(lifetime).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        result = relay => result,
+        _ = idle => Err(timed_out("idle")),
+        _ = lifetime => Err(timed_out("max_lifetime")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_bounds() {
+        assert!(Timeouts::default().validate().is_ok());
+
+        let zero = Timeouts {
+            handshake: 0,
+            ..Default::default()
+        };
+        assert!(zero.validate().is_err());
+
+        let max = Timeouts {
+            handshake: MAX_TIMEOUT,
+            connect: MAX_TIMEOUT,
+            idle: MAX_TIMEOUT,
+            max_lifetime: MAX_TIMEOUT,
+        };
+        assert!(max.validate().is_ok());
+        // Deadlines at the bound still fit an `Instant`
+        assert!(Instant::now()
+            .checked_add(max.handshake())
+            .is_some());
+
+        for huge in [
+            Timeouts {
+                handshake: u64::MAX,
+                ..Default::default()
+            },
+            Timeouts {
+                connect: u64::MAX,
+                ..Default::default()
+            },
+            Timeouts {
+                idle: u64::MAX,
+                ..Default::default()
+            },
+            Timeouts {
+                max_lifetime: MAX_TIMEOUT + 1,
+                ..Default::default()
+            },
+        ] {
+            assert!(huge.validate().is_err());
+        }
+    }
+}
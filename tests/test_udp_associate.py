@@ -0,0 +1,80 @@
+import socket
+import struct
+import threading
+
+def udp_echo_server(echo_sock):
+    """
+    Echoes every datagram back to its sender.
+    """
+    try:
+        while True:
+            data, addr = echo_sock.recvfrom(65535)
+            echo_sock.sendto(data, addr)
+    except Exception as e:
+        print(f"Echo server error: {e}")
+
+def test_socks5_udp_associate(proxy_host, proxy_port, message):
+    """
+    Tests SOCKS5 UDP ASSOCIATE functionality.
+    """
+    try:
+        # Start a local UDP echo server as the destination
+        echo_sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        echo_sock.bind(("127.0.0.1", 0))
+        echo_ip, echo_port = echo_sock.getsockname()
+        threading.Thread(target=udp_echo_server, args=(echo_sock,), daemon=True).start()
+
+        # Connect to the proxy
+        sock = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        sock.connect((proxy_host, proxy_port))
+
+        # SOCKS5 handshake
+        sock.sendall(b"\x05\x01\x00")  # SOCKS5, 1 method (no auth)
+        response = sock.recv(2)
+        if response != b"\x05\x00":
+            print(f"Handshake failed: {response}")
+            return
+
+        # Request UDP ASSOCIATE, client address not known yet
+        associate_request = b"\x05\x03\x00\x01" + socket.inet_aton("0.0.0.0") + struct.pack(">H", 0)
+        sock.sendall(associate_request)
+
+        # Receive the UDP ASSOCIATE response
+        response = sock.recv(10)
+        if response[1] != 0x00:
+            print(f"UDP ASSOCIATE request failed: {response[1]}")
+            return
+
+        # Extract the relay IP and port
+        relay_ip = socket.inet_ntoa(response[4:8])
+        relay_port = struct.unpack(">H", response[8:10])[0]
+        print(f"Proxy relaying on {relay_ip}:{relay_port}")
+
+        # Send a datagram to the echo server through the relay
+        udp_sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        udp_sock.settimeout(5)
+        header = b"\x00\x00\x00\x01" + socket.inet_aton(echo_ip) + struct.pack(">H", echo_port)
+        udp_sock.sendto(header + message.encode(), (relay_ip, relay_port))
+
+        # Read the echoed datagram, it comes back with a UDP header
+        data, _ = udp_sock.recvfrom(65535)
+        source_ip = socket.inet_ntoa(data[4:8])
+        source_port = struct.unpack(">H", data[8:10])[0]
+        print(f"Datagram received from {source_ip}:{source_port}")
+        print(f"Message received: {data[10:].decode()}")
+
+        # Closing the TCP connection ends the association
+        sock.close()
+        udp_sock.close()
+
+    except Exception as e:
+        print(f"Test failed: {e}")
+
+# Proxy and test details, the echo server is on loopback so the
+# proxy needs `ssrf = { exceptions = ["127.0.0.1"] }`
+proxy_host = "127.0.0.1"  # SOCKS5 proxy address
+proxy_port = 1080         # SOCKS5 proxy port
+test_message = "Hello, SOCKS5 Proxy!"
+
+# Run the test
+test_socks5_udp_associate(proxy_host, proxy_port, test_message)